@@ -8,6 +8,7 @@ import { catchError, retry } from 'rxjs/operators';
 
 import { Axis } from './axis';
 import { MoveAxisRelMsg } from './move-axis-rel';
+import { AxisEnd, StartJogMsg } from './start-jog';
 import { SurfaceGrinderCutParams } from './surface-grinder-cut-params';
 
 
@@ -17,11 +18,14 @@ import { SurfaceGrinderCutParams } from './surface-grinder-cut-params';
 })
 export class MotorControlService {
 
+	private jogKeepaliveUrl = "api/jogKeepalive";
 	private moveAxisRelUrl = "api/moveAxisRel";
 	private spindlePowerUrl = "api/spindlePower";
 	private startHomingUrl = "api/startHoming";
+	private startJogUrl = "api/startJog";
 	private startSurfaceGrinderCutUrl = "api/startSurfaceGrinderCut";
 	private stopUrl = "api/stop";
+	private stopJogUrl = "api/stopJog";
 
 	httpOptions = {
 		headers: new HttpHeaders({"Content-Type": "application/json"})
@@ -29,6 +33,14 @@ export class MotorControlService {
 
 	constructor(private http: HttpClient) { }
 
+	jogKeepalive() : Observable<any> {
+		let msg = null;
+		return this.http.post(this.jogKeepaliveUrl, msg, this.httpOptions)
+			.pipe(
+				catchError(this.handleError('jogKeepalive', msg))
+			);
+	}
+
 	moveAxisRel(axis: Axis, distance: number, speed: number) : Observable<any> {
 		let msg = new MoveAxisRelMsg(axis, distance, speed);
 		return this.http.post<MoveAxisRelMsg>(this.moveAxisRelUrl, msg, this.httpOptions)
@@ -52,6 +64,14 @@ export class MotorControlService {
 			);
 	}
 
+	startJog(axis: Axis, direction: AxisEnd, speed: number) : Observable<any> {
+		let msg = new StartJogMsg(axis, direction, speed);
+		return this.http.post<StartJogMsg>(this.startJogUrl, msg, this.httpOptions)
+			.pipe(
+				catchError(this.handleError('startJog', msg))
+			);
+	}
+
 	startSurfaceGrinderCut(msg: SurfaceGrinderCutParams) : Observable<any> {
 		return this.http.post<SurfaceGrinderCutParams>(this.startSurfaceGrinderCutUrl, msg, this.httpOptions)
 			.pipe(
@@ -67,6 +87,14 @@ export class MotorControlService {
 			);
 	}
 
+	stopJog() : Observable<any> {
+		let msg = null;
+		return this.http.post(this.stopJogUrl, msg, this.httpOptions)
+			.pipe(
+				catchError(this.handleError('stopJog', msg))
+			);
+	}
+
 	/**
 	 * Handle Http operation that failed.
 	 * Let the app continue.
@@ -1,27 +1,54 @@
-import { Component, Input, OnInit } from '@angular/core';
+import { Component, Input, OnDestroy, OnInit } from '@angular/core';
 
 import { Axis } from "../axis";
 import { MotorControlService } from '../motor-control.service';
+import { AxisEnd } from '../start-jog';
 
 
 
+// How often to tell the controller to keep jogging, well within its timeout, so a dropped connection stops the axis
+const JOG_KEEPALIVE_INTERVAL_MS = 100;
+
+
 @Component({
 	selector: 'app-move-axis-row',
 	templateUrl: './move-axis-row.component.html',
 	styleUrls: ['./move-axis-row.component.scss']
 })
-export class MoveAxisRowComponent implements OnInit {
+export class MoveAxisRowComponent implements OnInit, OnDestroy {
 	@Input() axis: Axis = Axis.X;
 	stepSize: number = 0.001;
 	speed: number = 0.1;
 	position = 0;
+	AxisEnd = AxisEnd;
+	private jogKeepaliveTimer: number | null = null;
 
 
 	constructor(private motorControlService: MotorControlService) {}
 
 	ngOnInit(): void {}
 
+	ngOnDestroy(): void {
+		this.stopJog();
+	}
+
 	moveAxis(distance: number): void {
 		this.motorControlService.moveAxisRel(this.axis, distance, this.speed).subscribe();
 	}
+
+	// Jog for as long as the button is held down
+	startJog(direction: AxisEnd): void {
+		this.stopJog();
+		this.motorControlService.startJog(this.axis, direction, this.speed).subscribe();
+		this.jogKeepaliveTimer = window.setInterval(() => this.motorControlService.jogKeepalive().subscribe(), JOG_KEEPALIVE_INTERVAL_MS);
+	}
+
+	stopJog(): void {
+		if (this.jogKeepaliveTimer === null) {
+			return;
+		}
+		window.clearInterval(this.jogKeepaliveTimer);
+		this.jogKeepaliveTimer = null;
+		this.motorControlService.stopJog().subscribe();
+	}
 }
@@ -0,0 +1,20 @@
+import { Axis } from './axis';
+
+
+
+export enum AxisEnd {
+	Min = "Min",
+	Max = "Max",
+}
+
+export class StartJogMsg {
+	axis: Axis;
+	direction: AxisEnd;
+	speed: number;
+
+	constructor(axis: Axis, direction: AxisEnd, speed: number) {
+		this.axis = axis;
+		this.direction = direction;
+		this.speed = speed;
+	}
+}
@@ -1,15 +1,196 @@
+use std::collections::HashMap;
+use std::fmt;
+
 use strum_macros::Display;
 
 
 
+/// Length of an inch, in the internal unit (nanometres)
+pub const NANOMETRES_PER_INCH: f64 = 25_400_000.0;
+/// Length of a millimetre, in the internal unit (nanometres)
+pub const NANOMETRES_PER_MILLIMETRE: f64 = 1_000_000.0;
+/// Size of a degree, in the internal unit for angles (microdegrees).
+/// That makes a degree about as big as a millimetre, so when a rotary axis moves along with a linear one, neither swamps the other's share of the speed along the path.
+pub const MICRODEGREES_PER_DEGREE: f64 = 1_000_000.0;
+
+/**
+ * Units that lengths (and speeds etc. made from them) can be given in, in the config and in API requests.
+ * Internally, everything is in nanometres, converted at the edges. Whole nanometres are exact in both inches and millimetres, so going back and forth doesn't make 25mm into 24.99999mm.
+ */
+#[derive(Copy, Clone)]
+#[derive(Display, Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum LengthUnit {
+	#[serde(alias = "in")]
+	#[strum(to_string = "in")]
+	Inch,
+	#[serde(alias = "mm")]
+	#[strum(to_string = "mm")]
+	Millimetre,
+}
+impl Default for LengthUnit {
+	fn default() -> Self {
+		LengthUnit::Inch
+	}
+}
+
+impl LengthUnit {
+	fn nanometres(self) -> f64 {
+		match self {
+			LengthUnit::Inch => NANOMETRES_PER_INCH,
+			LengthUnit::Millimetre => NANOMETRES_PER_MILLIMETRE,
+		}
+	}
+
+	/// Convert a length (or speed, acceleration, etc.) in these units to the internal units, to the nearest nanometre
+	pub fn to_internal(self, length: f64) -> f64 {
+		(length * self.nanometres()).round()
+	}
+
+	/// Convert a length (or speed, acceleration, etc.) in the internal units to these units
+	pub fn internal_to_units(self, length: f64) -> f64 {
+		length / self.nanometres()
+	}
+
+	/// Convert something per length (e.g. revolutions per inch) in these units to per internal unit
+	pub fn per_length_to_internal(self, value: f64) -> f64 {
+		value / self.nanometres()
+	}
+
+	/// Convert something per internal unit of length to per one of these units
+	pub fn per_length_from_internal(self, value: f64) -> f64 {
+		value * self.nanometres()
+	}
+}
+
+
+
+/**
+ * Units that positions along an axis (and speeds etc. made from them) are given in: the length unit for a linear axis, or degrees for a rotary one.
+ */
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum AxisUnit {
+	Length(LengthUnit),
+	Degree,
+}
+
+impl AxisUnit {
+	pub fn new(kind: AxisKind, length_unit: LengthUnit) -> Self {
+		match kind {
+			AxisKind::Linear => AxisUnit::Length(length_unit),
+			AxisKind::Rotary => AxisUnit::Degree,
+		}
+	}
+
+	/// Convert a position (or speed, acceleration, etc.) in these units to the internal units, to the nearest nanometre or microdegree
+	pub fn to_internal(self, value: f64) -> f64 {
+		match self {
+			AxisUnit::Length(units) => units.to_internal(value),
+			AxisUnit::Degree => (value * MICRODEGREES_PER_DEGREE).round(),
+		}
+	}
+
+	/// Convert a position (or speed, acceleration, etc.) in the internal units to these units
+	pub fn internal_to_units(self, value: f64) -> f64 {
+		match self {
+			AxisUnit::Length(units) => units.internal_to_units(value),
+			AxisUnit::Degree => value / MICRODEGREES_PER_DEGREE,
+		}
+	}
+
+	/// Convert something per unit of travel (e.g. revolutions of the motor per degree) in these units to per internal unit
+	pub fn per_unit_to_internal(self, value: f64) -> f64 {
+		match self {
+			AxisUnit::Length(units) => units.per_length_to_internal(value),
+			AxisUnit::Degree => value / MICRODEGREES_PER_DEGREE,
+		}
+	}
+
+	/// Convert something per internal unit of travel to per one of these units
+	pub fn per_unit_from_internal(self, value: f64) -> f64 {
+		match self {
+			AxisUnit::Length(units) => units.per_length_from_internal(value),
+			AxisUnit::Degree => value * MICRODEGREES_PER_DEGREE,
+		}
+	}
+}
+impl fmt::Display for AxisUnit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			AxisUnit::Length(units) => write!(f, "{}", units),
+			AxisUnit::Degree => write!(f, "deg"),
+		}
+	}
+}
+
+
+/**
+ * Units of everything given to the controller or reported by it: lengths in the length unit, except positions of rotary axes, which are in degrees.
+ */
+#[derive(Clone)]
+pub struct UnitSystem {
+	pub length: LengthUnit,
+	/// Kind of each axis the machine has
+	pub axis_kinds: HashMap<Axis, AxisKind>,
+}
+
+impl UnitSystem {
+	/// Units of positions along the given axis. An axis the machine doesn't have is taken to be linear.
+	pub fn for_axis(&self, axis: Axis) -> AxisUnit {
+		AxisUnit::new(self.axis_kinds.get(&axis).copied().unwrap_or_default(), self.length)
+	}
+
+	/// The same, but with lengths in the given units
+	pub fn with_length(&self, length: LengthUnit) -> Self {
+		UnitSystem {
+			length,
+			axis_kinds: self.axis_kinds.clone(),
+		}
+	}
+}
+
+
+
+/**
+ * Name of an axis, as in G-code: X, Y and Z are the main linear axes, A, B and C usually turn about them, and U, V and W are secondary linear axes parallel to them.
+ * Which of them the machine actually has, and whether each one is linear or rotary, is up to the config.
+ */
 #[derive(Copy, Clone)]
 #[derive(Display, Debug)]
 #[derive(PartialEq, Eq, Hash)]
+#[derive(PartialOrd, Ord)]
 #[derive(Serialize, Deserialize)]
 pub enum Axis {
 	X,
 	Y,
 	Z,
+	A,
+	B,
+	C,
+	U,
+	V,
+	W,
+}
+
+
+/// Whether an axis moves in a straight line, or turns
+#[derive(Copy, Clone)]
+#[derive(Display, Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum AxisKind {
+	/// Positions are in the length unit
+	Linear,
+	/// Positions are in degrees. They aren't wrapped round at 360, so turning the same way keeps counting up (or down).
+	Rotary,
+}
+impl Default for AxisKind {
+	fn default() -> Self {
+		AxisKind::Linear
+	}
 }
 
 
@@ -24,6 +205,23 @@ pub enum AxisEnd {
 
 
 
+/// Which of an axis's motors something belongs to. Most axes just have a main motor; a dual-motor axis has a slave too, stepping in lockstep with it.
+#[derive(Copy, Clone)]
+#[derive(Display, Debug)]
+#[derive(PartialEq, Eq, Hash)]
+#[derive(Serialize, Deserialize)]
+pub enum AxisMotor {
+	Main,
+	Slave,
+}
+impl Default for AxisMotor {
+	fn default() -> Self {
+		AxisMotor::Main
+	}
+}
+
+
+
 #[derive(Copy, Clone)]
 #[derive(Debug)]
 #[derive(PartialEq, Eq, Hash)]
@@ -31,13 +229,21 @@ pub enum AxisEnd {
 pub struct EndstopIdentifier {
 	pub axis: Axis,
 	pub position: AxisEnd,
+	/// Motor whose side of the axis the endstop is on. Only matters for a dual-motor axis, where each side has its own endstops to square it up against.
+	#[serde(default)]
+	pub motor: AxisMotor,
 }
 
 impl EndstopIdentifier {
 	pub fn new(axis: Axis, position: AxisEnd) -> Self {
+		EndstopIdentifier::for_motor(axis, position, AxisMotor::Main)
+	}
+
+	pub fn for_motor(axis: Axis, position: AxisEnd, motor: AxisMotor) -> Self {
 		EndstopIdentifier{
 			axis,
 			position,
+			motor,
 		}
 	}
 }
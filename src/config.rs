@@ -1,49 +1,1009 @@
 use crate::common::Axis;
 use crate::common::AxisEnd;
+use crate::common::AxisKind;
+use crate::common::AxisMotor;
+use crate::common::AxisUnit;
 use crate::common::EndstopIdentifier;
+use crate::common::LengthUnit;
+use crate::common::NANOMETRES_PER_INCH;
+use crate::common::UnitSystem;
 use crate::pins;
+use crate::tmc_uart;
 
 use std::collections::HashMap;
+use std::env;
 use std::error;
+use std::fmt;
 use std::fs::File;
 use std::io::BufReader;
-
-use serde_yaml;
+use std::time::Duration;
 
 
 
 const CONFIG_FILE_PATH : &str = "/home/pi/rust_grind.yaml";
+/// Environment variable that overrides CONFIG_FILE_PATH, e.g. to use a config for simulated hardware when not running on the Pi
+const CONFIG_FILE_PATH_ENV_VAR : &str = "RUST_GRIND_CONFIG";
+/// Largest step count we handle, i.e. 2^53. Every step count up to this can be represented exactly as an f64.
+const MAX_STEP_COUNT: f64 = 9007199254740992.0;
+/// Acceleration of an axis that doesn't have one configured: 500 inches/second^2, in internal units
+const DEFAULT_ACCELERATION: f64 = 500.0 * NANOMETRES_PER_INCH;
+/// Junction deviation if it isn't configured: 0.0005 inches, in internal units
+const DEFAULT_JUNCTION_DEVIATION: f64 = 0.0005 * NANOMETRES_PER_INCH;
+
+/// Shape of the acceleration ramps
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub enum MotionProfileConfig {
+	/// Constant acceleration
+	Trapezoidal,
+	/// Jerk-limited, i.e. the acceleration itself ramps up and down. Jerk is in length units/second^3.
+	SCurve {
+		#[serde(alias = "jerk_ips3")]
+		jerk: f64,
+	},
+}
+impl Default for MotionProfileConfig {
+	fn default() -> Self {
+		MotionProfileConfig::Trapezoidal
+	}
+}
+
+/// What motor control does with a move that would leave the soft limits
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub enum SoftLimitMode {
+	/// Refuse the whole move, without moving at all
+	Reject,
+	/// Cut the move short where it reaches the limit
+	Clamp,
+}
+impl Default for SoftLimitMode {
+	fn default() -> Self {
+		SoftLimitMode::Reject
+	}
+}
+
+fn default_microsteps() -> u32 {
+	1
+}
+
+fn default_motor_idle_timeout_secs() -> f64 {
+	5.0
+}
+
+fn default_jog_keepalive_timeout_ms() -> u64 {
+	500
+}
+
+fn default_vfd_poll_interval_ms() -> u64 {
+	100
+}
 
+fn default_tachometer_sample_interval_ms() -> u64 {
+	200
+}
+
+fn default_tmc_baud_rate() -> u32 {
+	115200
+}
+
+fn default_sense_resistor_ohms() -> f64 {
+	0.11
+}
+
+fn default_driver_poll_interval_ms() -> u64 {
+	10
+}
+
+/// Why a position couldn't be turned into a step count
 #[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+pub enum StepConversionError {
+	/// NaN or infinite
+	NotFinite,
+	/// Too far away to count in steps
+	OutOfRange,
+}
+impl fmt::Display for StepConversionError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			StepConversionError::NotFinite => write!(f, "position is not a finite number"),
+			StepConversionError::OutOfRange => write!(f, "position is too far away to count in steps"),
+		}
+	}
+}
+impl error::Error for StepConversionError {}
+
+/// One point of a leadscrew's pitch error table
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct PitchErrorPoint {
+	/// Commanded position
+	pub position: f64,
+	/// How much further the motor needs to turn to actually get to the position.
+	/// That's the opposite of the error measured (e.g. with a dial indicator) without any correction, i.e. actual minus commanded position.
+	pub correction: f64,
+}
+
+/// Make of stepper driver, which decides how the step pulses have to be timed and what the microstep mode pins do
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum DriverModel {
+	DRV8825,
+	A4988,
+	TMC2209,
+	/// Any other step/direction driver, e.g. an external industrial one. Its microstepping is set on the driver itself (e.g. with DIP switches), and its timing defaults to something slow enough for opto-isolated inputs.
+	Generic,
+}
+impl Default for DriverModel {
+	fn default() -> Self {
+		DriverModel::DRV8825
+	}
+}
+impl DriverModel {
+	/// Minimum time the step signal has to stay high, and then low, from the datasheet
+	fn step_pulse_length(&self) -> Duration {
+		match self {
+			DriverModel::DRV8825 => Duration::from_nanos(1900),
+			DriverModel::A4988 => Duration::from_nanos(1000),
+			DriverModel::TMC2209 => Duration::from_nanos(100),
+			DriverModel::Generic => Duration::from_micros(5),
+		}
+	}
+
+	/// Minimum time between changing the direction signal and the next step pulse, from the datasheet
+	fn direction_setup_time(&self) -> Duration {
+		match self {
+			DriverModel::DRV8825 => Duration::from_nanos(650),
+			DriverModel::A4988 => Duration::from_nanos(200),
+			DriverModel::TMC2209 => Duration::from_nanos(20),
+			DriverModel::Generic => Duration::from_micros(5),
+		}
+	}
+
+	/// Levels of the microstep mode pins (M0 first) that select the given microstepping, or None if the driver can't do it
+	fn microstep_pin_levels(&self, microsteps: u32) -> Option<Vec<bool>> {
+		let levels: &[bool] = match (self, microsteps) {
+			(DriverModel::DRV8825, 1) => &[false, false, false],
+			(DriverModel::DRV8825, 2) => &[true, false, false],
+			(DriverModel::DRV8825, 4) => &[false, true, false],
+			(DriverModel::DRV8825, 8) => &[true, true, false],
+			(DriverModel::DRV8825, 16) => &[false, false, true],
+			(DriverModel::DRV8825, 32) => &[true, false, true],
+			(DriverModel::A4988, 1) => &[false, false, false],
+			(DriverModel::A4988, 2) => &[true, false, false],
+			(DriverModel::A4988, 4) => &[false, true, false],
+			(DriverModel::A4988, 8) => &[true, true, false],
+			(DriverModel::A4988, 16) => &[true, true, true],
+			// MS1 and MS2, in standalone mode
+			(DriverModel::TMC2209, 8) => &[false, false],
+			(DriverModel::TMC2209, 16) => &[true, true],
+			(DriverModel::TMC2209, 32) => &[true, false],
+			(DriverModel::TMC2209, 64) => &[false, true],
+			_ => return None,
+		};
+		Some(levels.to_vec())
+	}
+}
+
+/// Stepper driver of an axis's motors, and how it's set up
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct DriverConfig {
+	#[serde(default)]
+	pub model: DriverModel,
+	/// Minimum step pulse length in nanoseconds, if the model's default isn't right (e.g. because of slow optocouplers in between)
+	#[serde(default)]
+	pub step_pulse_ns: Option<u64>,
+	/// Minimum direction setup time in nanoseconds, if the model's default isn't right
+	#[serde(default)]
+	pub direction_setup_ns: Option<u64>,
+	/// Microsteps per full step, selected over the UART or with the microstep mode pins if there are either, otherwise whatever the driver is set to
+	#[serde(default = "default_microsteps")]
+	pub microsteps: u32,
+	/// Set the driver up over its UART, and keep an eye on it, if it's a TMC2209 wired up for it
+	#[serde(default)]
+	pub uart: Option<TmcUartConfig>,
+}
+impl Default for DriverConfig {
+	fn default() -> Self {
+		DriverConfig {
+			model: DriverModel::default(),
+			step_pulse_ns: None,
+			direction_setup_ns: None,
+			microsteps: default_microsteps(),
+			uart: None,
+		}
+	}
+}
+impl DriverConfig {
+	pub fn step_pulse_length(&self) -> Duration {
+		self.step_pulse_ns.map(Duration::from_nanos).unwrap_or_else(|| self.model.step_pulse_length())
+	}
+
+	pub fn direction_setup_time(&self) -> Duration {
+		self.direction_setup_ns.map(Duration::from_nanos).unwrap_or_else(|| self.model.direction_setup_time())
+	}
+
+	/// Levels to set the given microstep mode pins to, M0 first. There have to be either none, if the microstepping is set some other way, or one for each of the driver's mode inputs.
+	pub fn microstep_pin_levels(&self, pin_count: usize) -> Result<Vec<bool>, String> {
+		if self.microsteps == 0 {
+			return Err("microsteps needs to be more than 0".to_string());
+		}
+		if pin_count == 0 {
+			return Ok(Vec::new());
+		}
+		let levels = match self.model.microstep_pin_levels(self.microsteps) {
+			Some(levels) => levels,
+			None => return Err(format!("{:?} can't be set to {} microsteps with its mode pins", self.model, self.microsteps)),
+		};
+		if levels.len() != pin_count {
+			return Err(format!("{:?} has {} microstep mode pins, not {}", self.model, levels.len(), pin_count));
+		}
+		Ok(levels)
+	}
+}
+
+/// StallGuard stall detection on a TMC2209, used as a virtual endstop at one end of the axis, e.g. where there's no room for a real one
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct StallGuardConfig {
+	/// End of the axis that running into counts as hitting an endstop. Only one motor driving that way can stall, so the axis can't have a real endstop there too.
+	pub end: AxisEnd,
+	/// SGTHRS: the motor counts as stalled when the StallGuard reading drops to twice this or less, so higher is more sensitive
+	pub threshold: u8,
+	/// Slowest speed at which the StallGuard reading can be trusted, in length units/second. Below it, e.g. while accelerating, the motor never counts as stalled.
+	pub min_speed: f64,
+}
+
+/// Trinamic TMC2209 driver set up over its single-wire UART, rather than with pins
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct TmcUartConfig {
+	/// Serial device the drivers' PDN_UART pins are wired to, e.g. /dev/ttyAMA0. Drivers of different axes can share it, at different addresses.
+	/// With simulated hardware, simulated drivers on a pseudo-terminal are used instead.
+	pub device: String,
+	#[serde(default = "default_tmc_baud_rate")]
+	pub baud_rate: u32,
+	/// Address of the main motor's driver, from 0 to 3, as set with its MS1 and MS2 pins
+	pub address: u8,
+	/// Motor current while moving, in amps RMS
+	pub run_current: f64,
+	/// Motor current while standing still, in amps RMS
+	pub hold_current: f64,
+	#[serde(default = "default_sense_resistor_ohms")]
+	pub sense_resistor_ohms: f64,
+	/// Quiet StealthChop, rather than SpreadCycle, which has more torque at speed
+	#[serde(default)]
+	pub stealth_chop: bool,
+	/// Stall detection, which only works with StealthChop
+	#[serde(default)]
+	pub stall_guard: Option<StallGuardConfig>,
+	/// How often to read the StallGuard reading and error flags
+	#[serde(default = "default_driver_poll_interval_ms")]
+	pub poll_interval_ms: u64,
+}
+impl TmcUartConfig {
+	/// Make sure the settings can be used: an address the driver can have, and currents it can be set to
+	pub fn check(&self) -> Result<(), String> {
+		if self.address > 3 {
+			return Err(format!("UART address {} is out of range", self.address));
+		}
+		if self.baud_rate == 0 || self.poll_interval_ms == 0 {
+			return Err("baud rate and poll interval need to be more than 0".to_string());
+		}
+		if !self.sense_resistor_ohms.is_finite() || self.sense_resistor_ohms <= 0.0 {
+			return Err("sense resistance needs to be a positive number".to_string());
+		}
+		let max_current = tmc_uart::max_current(self.sense_resistor_ohms);
+		for current in &[self.run_current, self.hold_current] {
+			if !(0.0..=max_current).contains(current) {
+				return Err(format!("{} A is not a current the driver can be set to (0 to {:.2} A with {} ohm sense resistors)", current, max_current, self.sense_resistor_ohms));
+			}
+		}
+		if let Some(stall_guard) = &self.stall_guard {
+			if !self.stealth_chop {
+				return Err("StallGuard only works with StealthChop".to_string());
+			}
+			if !stall_guard.min_speed.is_finite() || stall_guard.min_speed <= 0.0 {
+				return Err("StallGuard min speed needs to be a positive number".to_string());
+			}
+		}
+		Ok(())
+	}
+}
+
+/// Second motor driving an axis, in lockstep with its main motor, e.g. on the other screw of a gantry.
+/// It's geared the same as the main motor, so it takes the same steps, but it has its own pins, and its own endstops to square the axis against.
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct SlaveMotorConfig {
+	/// Set if the slave turns the other way to the main motor to move the axis the same way, e.g. because it's mounted facing it
+	#[serde(default)]
+	pub reversed: bool,
+	/// Furthest one motor may go without the other while squaring the axis, before it's taken to be a fault (e.g. an endstop that never trips) and the move is abandoned
+	pub max_squaring_distance: f64,
+
+	pub enable_pin_number: u64,
+	pub step_pin_number: u64,
+	pub direction_pin_number: u64,
+	#[serde(default)]
+	pub microstep_pin_numbers: Vec<u64>,
+	/// Address of the slave's driver on the main motor's driver UART, if it has one
+	#[serde(default)]
+	pub uart_address: Option<u8>,
+}
+
+#[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct MotorConfig {
+	/// Whether the axis is linear, or a rotary one with everything in degrees instead of the length unit
+	#[serde(default)]
+	pub kind: AxisKind,
+	/// Full steps per revolution of the motor. Multiplied by the driver's microstepping to get the steps it's actually sent.
 	pub steps_per_rev: i32,
-	pub revs_per_inch: f64,
+	/// Revolutions of the motor per unit of travel
+	#[serde(alias = "revs_per_inch")]
+	pub revs_per_unit: f64,
 	pub reversed: bool,
-	pub default_speed_ips: f64,
+	/// Speed for moves that don't ask for one, in length units/second
+	#[serde(alias = "default_speed_ips")]
+	pub default_speed: f64,
+	/// Maximum acceleration, in length units/second^2. Defaults to 500 inches/second^2, whatever the units.
+	#[serde(default, alias = "acceleration_ips2")]
+	pub acceleration: Option<f64>,
+	#[serde(default)]
+	pub motion_profile: MotionProfileConfig,
+	/// Keep the motor energized even when idle, e.g. so an axis doesn't drop under its own weight
+	#[serde(default)]
+	pub hold_torque: bool,
+	/// Slack in the leadscrew. Made up with extra steps whenever the axis reverses direction.
+	#[serde(default, alias = "backlash_inches")]
+	pub backlash: f64,
+	/// Corrections for cumulative pitch error along the leadscrew, in order of position.
+	/// Interpolated linearly between points; beyond the ends of the table, the correction at the nearest end is used.
+	#[serde(default)]
+	pub pitch_error_table: Vec<PitchErrorPoint>,
+	/// Second motor, for an axis driven from both sides
+	#[serde(default)]
+	pub slave: Option<SlaveMotorConfig>,
+	/// The slave motor, if any, has the same driver
+	#[serde(default)]
+	pub driver: DriverConfig,
 
 	pub enable_pin_number: u64,
 	pub step_pin_number: u64,
 	pub direction_pin_number: u64,
+	/// Microstep mode pins of the driver, M0 first. Leave them out if the microstepping is set on the driver itself.
+	#[serde(default)]
+	pub microstep_pin_numbers: Vec<u64>,
 }
 impl MotorConfig {
-	pub fn inches_to_steps(&self, inches: f64) -> i32 {
-		(inches * self.revs_per_inch * (self.steps_per_rev as f64)) as i32
+	/// Nearest step to the given position, corrected for pitch error
+	pub fn position_to_steps(&self, position: f64) -> Result<i64, StepConversionError> {
+		self.distance_to_steps(position + self.get_pitch_correction(position))
+	}
+
+	/// Nearest step to the given distance. Not for positions, since there's no pitch error correction.
+	pub fn distance_to_steps(&self, distance: f64) -> Result<i64, StepConversionError> {
+		let steps = (distance * self.steps_per_unit()).round();
+		if !steps.is_finite() {
+			return Err(StepConversionError::NotFinite);
+		}
+		if steps.abs() > MAX_STEP_COUNT {
+			return Err(StepConversionError::OutOfRange);
+		}
+		Ok(steps as i64)
+	}
+
+	pub fn steps_per_unit(&self) -> f64 {
+		self.revs_per_unit * (self.steps_per_rev as f64) * (self.driver.microsteps as f64)
+	}
+
+	/// Position of the given step, corrected for pitch error, to the nearest nanometre (or microdegree)
+	pub fn steps_to_position(&self, steps: i64) -> f64 {
+		self.remove_pitch_correction((steps as f64) / self.steps_per_unit()).round()
+	}
+
+	pub fn get_acceleration(&self) -> f64 {
+		self.acceleration.unwrap_or(DEFAULT_ACCELERATION)
+	}
+
+	/// Correction from the pitch error table for the given position
+	fn get_pitch_correction(&self, position: f64) -> f64 {
+		let table = &self.pitch_error_table;
+		if table.is_empty() || !position.is_finite() {
+			return 0.0;
+		}
+		let index = table.partition_point(|point| point.position <= position);
+		if index == 0 {
+			return table[0].correction;
+		}
+		if index == table.len() {
+			return table[index - 1].correction;
+		}
+		let (before, after) = (table[index - 1], table[index]);
+		let fraction = (position - before.position) / (after.position - before.position);
+		before.correction + (after.correction - before.correction) * fraction
+	}
+
+	/// Commanded position that needs the motor to turn to the given (uncorrected) position; the inverse of adding get_pitch_correction()
+	fn remove_pitch_correction(&self, motor_position: f64) -> f64 {
+		let table = &self.pitch_error_table;
+		if table.is_empty() || !motor_position.is_finite() {
+			return motor_position;
+		}
+		// The correction is linear between points, so so is the motor position
+		let corrected = |point: &PitchErrorPoint| point.position + point.correction;
+		let index = table.partition_point(|point| corrected(point) <= motor_position);
+		if index == 0 {
+			return motor_position - table[0].correction;
+		}
+		if index == table.len() {
+			return motor_position - table[index - 1].correction;
+		}
+		let (before, after) = (&table[index - 1], &table[index]);
+		let fraction = (motor_position - corrected(before)) / (corrected(after) - corrected(before));
+		before.position + (after.position - before.position) * fraction
+	}
+
+	/// Make sure the driver can be set up as configured, for the main motor and the slave
+	pub fn check_driver(&self) -> Result<(), String> {
+		self.driver.microstep_pin_levels(self.microstep_pin_numbers.len())?;
+		if let Some(slave) = &self.slave {
+			self.driver.microstep_pin_levels(slave.microstep_pin_numbers.len())?;
+		}
+		let uart = match &self.driver.uart {
+			Some(uart) => uart,
+			None => return Ok(()),
+		};
+		if self.driver.model != DriverModel::TMC2209 {
+			return Err(format!("{:?} doesn't have a UART", self.driver.model));
+		}
+		uart.check()?;
+		if tmc_uart::microstep_resolution(self.driver.microsteps).is_none() {
+			return Err(format!("{} microsteps can't be set over the UART", self.driver.microsteps));
+		}
+		// In UART mode, MS1 and MS2 set the address
+		if !self.microstep_pin_numbers.is_empty() || matches!(&self.slave, Some(slave) if !slave.microstep_pin_numbers.is_empty()) {
+			return Err("microstep mode pins can't be used with the UART".to_string());
+		}
+		if let Some(slave) = &self.slave {
+			match slave.uart_address {
+				Some(address) if address > 3 || address == uart.address => return Err(format!("slave UART address {} is out of range or the same as the main motor's", address)),
+				Some(_) => {},
+				None => return Err("slave motor needs a UART address too".to_string()),
+			}
+		}
+		Ok(())
+	}
+
+	/// Drivers of the axis's motors that are set up over a UART, and their addresses on it
+	pub fn uart_drivers(&self) -> Vec<(AxisMotor, u8)> {
+		let uart = match &self.driver.uart {
+			Some(uart) => uart,
+			None => return Vec::new(),
+		};
+		let mut drivers = vec![(AxisMotor::Main, uart.address)];
+		if let Some(address) = self.slave.as_ref().and_then(|slave| slave.uart_address) {
+			drivers.push((AxisMotor::Slave, address));
+		}
+		drivers
+	}
+
+	/// Virtual endstops given by the drivers' stall detection, one for each motor
+	pub fn stall_endstops(&self, axis: Axis) -> Vec<EndstopIdentifier> {
+		match self.driver.uart.as_ref().and_then(|uart| uart.stall_guard) {
+			Some(stall_guard) => self.uart_drivers().into_iter().map(|(motor, _)| EndstopIdentifier::for_motor(axis, stall_guard.end, motor)).collect(),
+			None => Vec::new(),
+		}
+	}
+
+	/// Make sure the pitch error table can be used: in order of position, and never correcting so steeply that the motor would have to turn backwards to go forwards.
+	pub fn check_pitch_error_table(&self) -> Result<(), String> {
+		for point in self.pitch_error_table.iter() {
+			if !point.position.is_finite() || !point.correction.is_finite() {
+				return Err(format!("{:?} is not a finite number", point));
+			}
+		}
+		for pair in self.pitch_error_table.windows(2) {
+			if pair[0].position >= pair[1].position {
+				return Err(format!("{:?} is not in order of position", pair));
+			}
+			if pair[0].position + pair[0].correction >= pair[1].position + pair[1].correction {
+				return Err(format!("correction between {:?} is too steep", pair));
+			}
+		}
+		Ok(())
+	}
+
+	/// Convert all lengths (and speeds etc.) from the given units to internal units, or back again
+	fn convert_units(&mut self, units: AxisUnit, to_internal: bool) {
+		let length = |value: f64| if to_internal { units.to_internal(value) } else { units.internal_to_units(value) };
+		self.revs_per_unit = if to_internal { units.per_unit_to_internal(self.revs_per_unit) } else { units.per_unit_from_internal(self.revs_per_unit) };
+		self.default_speed = length(self.default_speed);
+		self.acceleration = self.acceleration.map(length);
+		if let MotionProfileConfig::SCurve{jerk} = &mut self.motion_profile {
+			*jerk = length(*jerk);
+		}
+		self.backlash = length(self.backlash);
+		for point in self.pitch_error_table.iter_mut() {
+			point.position = length(point.position);
+			point.correction = length(point.correction);
+		}
+		if let Some(slave) = &mut self.slave {
+			slave.max_squaring_distance = length(slave.max_squaring_distance);
+		}
+		if let Some(stall_guard) = self.driver.uart.as_mut().and_then(|uart| uart.stall_guard.as_mut()) {
+			stall_guard.min_speed = length(stall_guard.min_speed);
+		}
+	}
+
+	/// Motors driving the axis: the main one, and the slave if there is one
+	pub fn motors(&self) -> Vec<AxisMotor> {
+		match self.slave {
+			Some(_) => vec![AxisMotor::Main, AxisMotor::Slave],
+			None => vec![AxisMotor::Main],
+		}
+	}
+
+	/// Convert between the given motor's own step count and logical steps (positive towards the Max end of the axis).
+	/// They only differ in sign, so this works in either direction.
+	pub fn apply_reversal(&self, motor: AxisMotor, steps: i64) -> i64 {
+		let reversed = match (motor, &self.slave) {
+			(AxisMotor::Slave, Some(slave)) => self.reversed != slave.reversed,
+			_ => self.reversed,
+		};
+		if reversed {
+			-steps
+		} else {
+			steps
+		}
+	}
+}
+
+/// Quadrature encoder (or glass scale) on an axis, read through the GPIO character device
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct EncoderConfig {
+	pub a_pin: u32,
+	pub b_pin: u32,
+	/// Counts per length unit of travel. Every edge of either channel is counted, so that's 4 per line of the scale.
+	pub counts_per_unit: f64,
+	/// Set if the count goes down as the axis moves towards its Max end
+	#[serde(default)]
+	pub reversed: bool,
+	/// How far the measured position may be from the commanded one before the following error alarm stops everything.
+	/// Needs to allow for any backlash that isn't compensated, and for how far the axis goes at full speed between encoder reports.
+	pub following_error_limit: f64,
+}
+impl EncoderConfig {
+	/// Logical position of the axis at the given count
+	pub fn counts_to_position(&self, counts: i64) -> f64 {
+		let counts = if self.reversed { -counts } else { counts };
+		((counts as f64) / self.counts_per_unit).round()
+	}
+
+	fn convert_units(&mut self, units: AxisUnit, to_internal: bool) {
+		let length = |value: f64| if to_internal { units.to_internal(value) } else { units.internal_to_units(value) };
+		self.counts_per_unit = if to_internal { units.per_unit_to_internal(self.counts_per_unit) } else { units.per_unit_from_internal(self.counts_per_unit) };
+		self.following_error_limit = length(self.following_error_limit);
+	}
+}
+
+/// One point of the spindle's speed to PWM duty cycle mapping
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct SpindleDutyPoint {
+	pub rpm: f64,
+	/// Fraction of the time the output is high, from 0 to 1
+	pub duty_cycle: f64,
+}
+
+/// Speed control of the spindle, through a PWM output of the Linux PWM sysfs class (e.g. into a VFD's analog input, via a filter)
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct SpindlePwmConfig {
+	/// Number of the PWM chip, as in /sys/class/pwm/pwmchipN
+	pub chip: u32,
+	/// Channel of the chip to use
+	pub channel: u32,
+	pub frequency_hz: f64,
+	/// Slowest the spindle can run. Ramping up starts from here, and ramping down stops here.
+	pub min_rpm: f64,
+	pub max_rpm: f64,
+	/// How quickly to change speed, in rpm/second
+	pub ramp_up_rpm_per_sec: f64,
+	pub ramp_down_rpm_per_sec: f64,
+	/// Duty cycle for each speed, in order of rpm and interpolated linearly between points.
+	/// If it's empty, the duty cycle is proportional to the speed, reaching 1 at max_rpm.
+	#[serde(default)]
+	pub duty_cycle_map: Vec<SpindleDutyPoint>,
+}
+impl SpindlePwmConfig {
+	/// Duty cycle to run the spindle at the given speed
+	pub fn rpm_to_duty_cycle(&self, rpm: f64) -> f64 {
+		let map = &self.duty_cycle_map;
+		if map.is_empty() {
+			return (rpm / self.max_rpm).clamp(0.0, 1.0);
+		}
+		let index = map.partition_point(|point| point.rpm <= rpm);
+		if index == 0 {
+			return map[0].duty_cycle;
+		}
+		if index == map.len() {
+			return map[index - 1].duty_cycle;
+		}
+		let (before, after) = (map[index - 1], map[index]);
+		let fraction = (rpm - before.rpm) / (after.rpm - before.rpm);
+		before.duty_cycle + (after.duty_cycle - before.duty_cycle) * fraction
+	}
+
+	/// Make sure the settings can be used: a sensible speed range and ramps, and a duty cycle map in order of rpm, with duty cycles between 0 and 1
+	pub fn check(&self) -> Result<(), String> {
+		let positive = |value: f64| value.is_finite() && value > 0.0;
+		if !positive(self.frequency_hz) {
+			return Err(format!("frequency {} is not a positive number", self.frequency_hz));
+		}
+		if !self.min_rpm.is_finite() || self.min_rpm < 0.0 || !positive(self.max_rpm) || self.min_rpm > self.max_rpm {
+			return Err(format!("{} to {} rpm is not a usable speed range", self.min_rpm, self.max_rpm));
+		}
+		if !positive(self.ramp_up_rpm_per_sec) || !positive(self.ramp_down_rpm_per_sec) {
+			return Err("ramp rates need to be positive numbers".to_string());
+		}
+		for point in self.duty_cycle_map.iter() {
+			if !point.rpm.is_finite() || !(0.0..=1.0).contains(&point.duty_cycle) {
+				return Err(format!("{:?} is not a usable point", point));
+			}
+		}
+		for pair in self.duty_cycle_map.windows(2) {
+			if pair[0].rpm >= pair[1].rpm {
+				return Err(format!("{:?} is not in order of rpm", pair));
+			}
+		}
+		Ok(())
 	}
+}
 
-	pub fn steps_to_inches(&self, steps: i32) -> f64 {
-		(steps as f64) / (self.revs_per_inch * (self.steps_per_rev as f64))
+/// Parity of a serial link
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub enum SerialParity {
+	None,
+	Even,
+	Odd,
+}
+impl Default for SerialParity {
+	/// Modbus RTU's default
+	fn default() -> Self {
+		SerialParity::Even
+	}
+}
+
+/// Holding registers a VFD is controlled through. They differ between makes, so they're all configured.
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct VfdRegisters {
+	/// Register that runs and stops the motor
+	pub control: u16,
+	/// Value written to the control register to run forwards
+	pub run_forward: u16,
+	/// Value written to the control register to stop
+	pub stop: u16,
+	/// Frequency to run the motor at
+	pub frequency_setpoint: u16,
+	/// Frequency the VFD is actually putting out
+	pub output_frequency: u16,
+	/// Code of the current fault, zero if there isn't one
+	pub fault_code: u16,
+}
+
+/// Spindle motor run by a VFD, controlled over Modbus RTU on an RS-485 serial link
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct ModbusVfdConfig {
+	/// Serial device of the RS-485 adapter, e.g. /dev/ttyUSB0. With simulated hardware, a simulated VFD on a pseudo-terminal is used instead.
+	pub device: String,
+	pub baud_rate: u32,
+	#[serde(default)]
+	pub parity: SerialParity,
+	/// Modbus address of the VFD
+	pub address: u8,
+	pub registers: VfdRegisters,
+	/// Counts of the frequency registers per Hz, e.g. 100 if they're in units of 0.01 Hz
+	pub frequency_scale: f64,
+	/// Spindle speed for each Hz the VFD puts out
+	pub rpm_per_hz: f64,
+	pub min_rpm: f64,
+	pub max_rpm: f64,
+	/// How often to read back the speed and fault status
+	#[serde(default = "default_vfd_poll_interval_ms")]
+	pub poll_interval_ms: u64,
+}
+impl ModbusVfdConfig {
+	/// Make sure the settings can be used: an address a VFD can have, and a sensible speed range that fits in the frequency register
+	pub fn check(&self) -> Result<(), String> {
+		let positive = |value: f64| value.is_finite() && value > 0.0;
+		if !(1..=247).contains(&self.address) {
+			return Err(format!("Modbus address {} is out of range", self.address));
+		}
+		if self.baud_rate == 0 || self.poll_interval_ms == 0 {
+			return Err("baud rate and poll interval need to be more than 0".to_string());
+		}
+		if !positive(self.frequency_scale) || !positive(self.rpm_per_hz) {
+			return Err("frequency scale and rpm per Hz need to be positive numbers".to_string());
+		}
+		if !self.min_rpm.is_finite() || self.min_rpm < 0.0 || !positive(self.max_rpm) || self.min_rpm > self.max_rpm {
+			return Err(format!("{} to {} rpm is not a usable speed range", self.min_rpm, self.max_rpm));
+		}
+		if self.rpm_to_frequency_counts(self.max_rpm) > u16::MAX as f64 {
+			return Err(format!("{} rpm doesn't fit in the frequency register", self.max_rpm));
+		}
+		Ok(())
+	}
+
+	/// Value of the frequency registers for the given spindle speed
+	pub fn rpm_to_frequency_counts(&self, rpm: f64) -> f64 {
+		(rpm / self.rpm_per_hz * self.frequency_scale).round()
+	}
+
+	/// Spindle speed for the given value of the frequency registers
+	pub fn frequency_counts_to_rpm(&self, counts: u16) -> f64 {
+		(counts as f64) / self.frequency_scale * self.rpm_per_hz
+	}
+}
+
+/// What drives the spindle
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub enum SpindleDriverConfig {
+	/// Switched on and off by spindle_enable_pin, with its speed set by spindle_pwm if that's configured
+	Pins,
+	/// A VFD, controlled over Modbus RTU
+	ModbusVfd(ModbusVfdConfig),
+}
+impl Default for SpindleDriverConfig {
+	fn default() -> Self {
+		SpindleDriverConfig::Pins
+	}
+}
+
+/// Tachometer on the spindle, e.g. a hall effect sensor, giving a pulse for each revolution (or several)
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct SpindleTachometerConfig {
+	pub pin: u32,
+	pub pulses_per_rev: f64,
+	/// How long pulses are counted for, for each measurement. Longer is more precise at low speeds, but slower to notice a change.
+	#[serde(default = "default_tachometer_sample_interval_ms")]
+	pub sample_interval_ms: u64,
+}
+impl SpindleTachometerConfig {
+	pub fn pulses_to_rpm(&self, pulses: u64, duration: Duration) -> f64 {
+		(pulses as f64) / self.pulses_per_rev * 60.0 / duration.as_secs_f64()
+	}
+}
+
+/// How to tell the spindle is up to speed
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SpindleAtSpeedConfig {
+	/// How far the measured speed may be from the requested speed, as a percentage of it
+	pub tolerance_percent: f64,
+	/// How long the spindle gets to come up to speed, before it's reported as a fault (which abandons a cut waiting for it)
+	pub timeout_secs: f64,
+	/// Without any speed feedback (a tachometer, or a VFD to read it back from), the spindle's taken to be up to speed this long after it's switched on
+	pub spin_up_secs: f64,
+}
+impl SpindleAtSpeedConfig {
+	pub fn new() -> Self {
+		SpindleAtSpeedConfig {
+			tolerance_percent: 5.0,
+			timeout_secs: 10.0,
+			spin_up_secs: 3.0,
+		}
+	}
+}
+impl Default for SpindleAtSpeedConfig {
+	fn default() -> Self {
+		SpindleAtSpeedConfig::new()
+	}
+}
+
+/// Settings for the simulated machine
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+	/// Position at which each simulated endstop trips
+	pub endstop_positions: HashMap<EndstopIdentifier, f64>,
+	/// Slack in each simulated leadscrew. The endstops go by where the table is, so they see it.
+	pub backlash: HashMap<Axis, f64>,
+	/// Make the motor of an axis miss one of every this many steps, as if it were stalling, e.g. to try out the following error alarm
+	pub lost_step_interval: HashMap<Axis, u64>,
+	/// How quickly the simulated VFD changes its output frequency
+	pub vfd_ramp_hz_per_sec: f64,
+	/// Make the simulated VFD trip with a fault once it's been running this long, e.g. to try out the spindle fault handling
+	pub vfd_fault_after_secs: Option<f64>,
+	/// Speed of the simulated spindle at full PWM duty cycle (or with just the enable pin), as seen by the simulated tachometer
+	pub spindle_full_speed_rpm: f64,
+	/// How quickly the simulated spindle changes speed
+	pub spindle_acceleration_rpm_per_sec: f64,
+	/// Position at which driving a motor into each stall detecting endstop stalls it, as seen by its simulated TMC2209
+	pub stall_positions: HashMap<EndstopIdentifier, f64>,
+	/// Make the simulated TMC2209 drivers shut down with overtemperature once they've been running this long, e.g. to try out the driver fault handling
+	pub driver_fault_after_secs: Option<f64>,
+}
+impl SimulationConfig {
+	pub fn new() -> Self {
+		let mut endstop_positions = HashMap::new();
+		endstop_positions.insert(EndstopIdentifier::new(Axis::X, AxisEnd::Min), -6.0);
+		endstop_positions.insert(EndstopIdentifier::new(Axis::X, AxisEnd::Max), 6.0);
+		endstop_positions.insert(EndstopIdentifier::new(Axis::Y, AxisEnd::Min), -3.0);
+		endstop_positions.insert(EndstopIdentifier::new(Axis::Y, AxisEnd::Max), 3.0);
+		endstop_positions.insert(EndstopIdentifier::new(Axis::Z, AxisEnd::Max), 4.0);
+		SimulationConfig {
+			endstop_positions,
+			backlash: HashMap::new(),
+			lost_step_interval: HashMap::new(),
+			vfd_ramp_hz_per_sec: 50.0,
+			vfd_fault_after_secs: None,
+			spindle_full_speed_rpm: 3000.0,
+			spindle_acceleration_rpm_per_sec: 1500.0,
+			stall_positions: HashMap::new(),
+			driver_fault_after_secs: None,
+		}
+	}
+
+	fn convert_units(&mut self, units: &UnitSystem, to_internal: bool) {
+		let length = |axis: Axis, value: f64| {
+			let units = units.for_axis(axis);
+			if to_internal { units.to_internal(value) } else { units.internal_to_units(value) }
+		};
+		for (endstop, position) in self.endstop_positions.iter_mut().chain(self.stall_positions.iter_mut()) {
+			*position = length(endstop.axis, *position);
+		}
+		for (axis, backlash) in self.backlash.iter_mut() {
+			*backlash = length(*axis, *backlash);
+		}
+	}
+}
+impl Default for SimulationConfig {
+	fn default() -> Self {
+		SimulationConfig::new()
+	}
+}
+
+/// Settings for the step generator thread, which needs to run with as little interruption as possible to keep the step timing accurate
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct StepGeneratorConfig {
+	/// SCHED_FIFO priority, from 1 to 99. Zero leaves the thread with normal scheduling.
+	pub realtime_priority: i32,
+	/// Lock all memory into RAM, so the step generator never has to wait for a page fault
+	pub lock_memory: bool,
+	/// CPU core to pin the step generator to, ideally one kept free of everything else with the isolcpus kernel parameter
+	pub cpu: Option<usize>,
+	/// How long before each step the step generator stops sleeping, and busy-waits for the exact time instead, to make up for how late the kernel wakes it.
+	/// Zero never busy-waits, which leaves the core free for everything else, but makes the step timing only as good as the wakeup latency.
+	pub spin_margin_us: u64,
+}
+impl StepGeneratorConfig {
+	pub fn new() -> Self {
+		StepGeneratorConfig {
+			realtime_priority: 80,
+			lock_memory: true,
+			cpu: None,
+			spin_margin_us: 50,
+		}
+	}
+
+	pub fn spin_margin(&self) -> Duration {
+		Duration::from_micros(self.spin_margin_us)
+	}
+}
+impl Default for StepGeneratorConfig {
+	fn default() -> Self {
+		StepGeneratorConfig::new()
+	}
+}
+
+/// Which hardware the controller talks to
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub enum HardwareConfig {
+	/// Real GPIO pins on the Raspberry Pi
+	Gpio,
+	/// Simulated motors and endstops, for running without the machine attached
+	Simulated(Box<SimulationConfig>),
+}
+impl Default for HardwareConfig {
+	fn default() -> Self {
+		HardwareConfig::Gpio
 	}
 }
 
 #[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 pub struct RustGrindConfig {
+	/// Units of all the lengths in the config file (and of API requests that don't say otherwise), apart from those of rotary axes, which are in degrees.
+	/// Once the config is loaded, everything in it is in internal units instead.
+	#[serde(default)]
+	pub units: LengthUnit,
+	/// The axes the machine has
 	pub motor_configs: HashMap<Axis, MotorConfig>,
 	// FIXME: needs to specify normally open or closed
 	pub endstop_config: HashMap<EndstopIdentifier, u32>,
+	/// Axes that have an encoder to check their position with
+	#[serde(default)]
+	pub encoder_configs: HashMap<Axis, EncoderConfig>,
 	pub gpio_chip_name: String,
 	pub spindle_enable_pin: u32,
+	/// Speed control for the spindle, if it has any. Without it, the spindle is just switched on and off with the enable pin.
+	#[serde(default)]
+	pub spindle_pwm: Option<SpindlePwmConfig>,
+	#[serde(default)]
+	pub spindle_driver: SpindleDriverConfig,
+	/// Tachometer to measure the spindle's speed with, if it has one. Otherwise it's read back from the VFD, if there is one.
+	#[serde(default)]
+	pub spindle_tachometer: Option<SpindleTachometerConfig>,
+	#[serde(default)]
+	pub spindle_at_speed: SpindleAtSpeedConfig,
+	#[serde(default)]
+	pub hardware: HardwareConfig,
+	/// How long a motor can sit idle before it's de-energized (unless it has hold_torque set, or has been locked)
+	#[serde(default = "default_motor_idle_timeout_secs")]
+	pub motor_idle_timeout_secs: f64,
+	/// How long a jog carries on without a keepalive from the client, before the axis is slowed to a stop
+	#[serde(default = "default_jog_keepalive_timeout_ms")]
+	pub jog_keepalive_timeout_ms: u64,
+	#[serde(default)]
+	pub step_generator: StepGeneratorConfig,
+	/// How far the path may cut inside the corner between two moves, which sets how fast we can go round it without stopping.
+	/// Defaults to 0.0005 inches, whatever the units.
+	#[serde(default)]
+	pub junction_deviation: Option<f64>,
+	/// What to do with moves that would leave the work envelope, once the machine has been homed
+	#[serde(default)]
+	pub soft_limit_mode: SoftLimitMode,
+}
+impl RustGrindConfig {
+	pub fn get_junction_deviation(&self) -> f64 {
+		self.junction_deviation.unwrap_or(DEFAULT_JUNCTION_DEVIATION)
+	}
+
+	/// Units of everything in the config file, and of API requests that don't say otherwise
+	pub fn unit_system(&self) -> UnitSystem {
+		UnitSystem {
+			length: self.units,
+			axis_kinds: self.motor_configs.iter().map(|(axis, motor_config)| (*axis, motor_config.kind)).collect(),
+		}
+	}
+
+	/// Convert all lengths (and speeds etc.) from the config's units to internal units, or back again
+	fn convert_units(&mut self, to_internal: bool) {
+		let unit_system = self.unit_system();
+		let units = self.units;
+		for (axis, motor_config) in self.motor_configs.iter_mut() {
+			motor_config.convert_units(unit_system.for_axis(*axis), to_internal);
+		}
+		for (axis, encoder_config) in self.encoder_configs.iter_mut() {
+			encoder_config.convert_units(unit_system.for_axis(*axis), to_internal);
+		}
+		if let HardwareConfig::Simulated(sim_config) = &mut self.hardware {
+			sim_config.convert_units(&unit_system, to_internal);
+		}
+		self.junction_deviation = self.junction_deviation.map(|value| if to_internal { units.to_internal(value) } else { units.internal_to_units(value) });
+	}
 }
 
 
@@ -65,69 +1025,325 @@ impl ConfigClient {
 
 pub struct ConfigManager {
 	config: RustGrindConfig,
+	config_file_path: String,
 }
 
 impl ConfigManager {
 	pub fn new() -> ConfigManager {
 		let mut ret = ConfigManager {
 			config: RustGrindConfig {
+				units: LengthUnit::Inch,
 				motor_configs: HashMap::new(),
 				endstop_config: HashMap::new(),
+				encoder_configs: HashMap::new(),
 				gpio_chip_name: "/dev/gpiochip0".to_string(),
 				spindle_enable_pin: pins::SPINDLE_PIN_NUMBER,
-			}
+				spindle_pwm: None,
+				spindle_driver: SpindleDriverConfig::Pins,
+				spindle_tachometer: None,
+				spindle_at_speed: SpindleAtSpeedConfig::new(),
+				hardware: HardwareConfig::Gpio,
+				motor_idle_timeout_secs: default_motor_idle_timeout_secs(),
+				jog_keepalive_timeout_ms: default_jog_keepalive_timeout_ms(),
+				step_generator: StepGeneratorConfig::new(),
+				junction_deviation: None,
+				soft_limit_mode: SoftLimitMode::Reject,
+			},
+			config_file_path: env::var(CONFIG_FILE_PATH_ENV_VAR).unwrap_or(CONFIG_FILE_PATH.to_string()),
 		};
 		ret.config.motor_configs.insert(Axis::X, MotorConfig {
+			kind: AxisKind::Linear,
 			steps_per_rev: 200,
-			revs_per_inch: 1.0,
+			revs_per_unit: 1.0,
 			reversed: false,
-			default_speed_ips: 1.0,
+			default_speed: 1.0,
+			acceleration: None,
+			motion_profile: MotionProfileConfig::Trapezoidal,
+			hold_torque: false,
+			backlash: 0.0,
+			pitch_error_table: Vec::new(),
+			slave: None,
+			driver: DriverConfig::default(),
 			enable_pin_number: pins::X_ENABLE_PIN_NUMBER,
 			step_pin_number: pins::X_STEP_PIN_NUMBER,
 			direction_pin_number: pins::X_DIRECTION_PIN_NUMBER,
+			microstep_pin_numbers: Vec::new(),
 		});
 		ret.config.motor_configs.insert(Axis::Y, MotorConfig {
+			kind: AxisKind::Linear,
 			steps_per_rev: 200,
-			revs_per_inch: 1.0,
+			revs_per_unit: 1.0,
 			reversed: false,
-			default_speed_ips: 1.0,
+			default_speed: 1.0,
+			acceleration: None,
+			motion_profile: MotionProfileConfig::Trapezoidal,
+			hold_torque: false,
+			backlash: 0.0,
+			pitch_error_table: Vec::new(),
+			slave: None,
+			driver: DriverConfig::default(),
 			enable_pin_number: pins::Y_ENABLE_PIN_NUMBER,
 			step_pin_number: pins::Y_STEP_PIN_NUMBER,
 			direction_pin_number: pins::Y_DIRECTION_PIN_NUMBER,
+			microstep_pin_numbers: Vec::new(),
 		});
 		ret.config.motor_configs.insert(Axis::Z, MotorConfig {
+			kind: AxisKind::Linear,
 			steps_per_rev: 200,
-			revs_per_inch: 1.0,
+			revs_per_unit: 1.0,
 			reversed: false,
-			default_speed_ips: 1.0,
+			default_speed: 1.0,
+			acceleration: None,
+			motion_profile: MotionProfileConfig::Trapezoidal,
+			hold_torque: true,
+			backlash: 0.0,
+			pitch_error_table: Vec::new(),
+			slave: None,
+			driver: DriverConfig::default(),
 			enable_pin_number: pins::Z_ENABLE_PIN_NUMBER,
 			step_pin_number: pins::Z_STEP_PIN_NUMBER,
 			direction_pin_number: pins::Z_DIRECTION_PIN_NUMBER,
+			microstep_pin_numbers: Vec::new(),
 		});
 
-		ret.config.endstop_config.insert(EndstopIdentifier{axis: Axis::X, position: AxisEnd::Min}, pins::X_MIN_ENDSTOP_PIN_NUMBER);
-		ret.config.endstop_config.insert(EndstopIdentifier{axis: Axis::X, position: AxisEnd::Max}, pins::X_MAX_ENDSTOP_PIN_NUMBER);
-		ret.config.endstop_config.insert(EndstopIdentifier{axis: Axis::Y, position: AxisEnd::Min}, pins::Y_MIN_ENDSTOP_PIN_NUMBER);
-		ret.config.endstop_config.insert(EndstopIdentifier{axis: Axis::Y, position: AxisEnd::Max}, pins::Y_MAX_ENDSTOP_PIN_NUMBER);
-		ret.config.endstop_config.insert(EndstopIdentifier{axis: Axis::Z, position: AxisEnd::Max}, pins::Z_MAX_ENDSTOP_PIN_NUMBER);
+		ret.config.endstop_config.insert(EndstopIdentifier::new(Axis::X, AxisEnd::Min), pins::X_MIN_ENDSTOP_PIN_NUMBER);
+		ret.config.endstop_config.insert(EndstopIdentifier::new(Axis::X, AxisEnd::Max), pins::X_MAX_ENDSTOP_PIN_NUMBER);
+		ret.config.endstop_config.insert(EndstopIdentifier::new(Axis::Y, AxisEnd::Min), pins::Y_MIN_ENDSTOP_PIN_NUMBER);
+		ret.config.endstop_config.insert(EndstopIdentifier::new(Axis::Y, AxisEnd::Max), pins::Y_MAX_ENDSTOP_PIN_NUMBER);
+		ret.config.endstop_config.insert(EndstopIdentifier::new(Axis::Z, AxisEnd::Max), pins::Z_MAX_ENDSTOP_PIN_NUMBER);
+		ret.config.convert_units(true);
 
 		ret
 	}
 
 	// FIXME: may not need to return result, just handle internally?
 	pub fn read_config_file(&mut self) -> Result<(), Box<dyn error::Error>> {
-		let file = File::open(CONFIG_FILE_PATH)?;
+		let file = File::open(&self.config_file_path)?;
 		let buf_reader = BufReader::new(file);
-		self.config = serde_yaml::from_reader(buf_reader)?;
+		let mut config: RustGrindConfig = serde_yaml::from_reader(buf_reader)?;
+		config.convert_units(true);
+		for (axis, motor_config) in config.motor_configs.iter() {
+			if let Err(error) = motor_config.check_pitch_error_table() {
+				return Err(format!("Invalid pitch error table for axis {}: {}", axis, error).into());
+			}
+			if let Err(error) = motor_config.check_driver() {
+				return Err(format!("Invalid driver config for axis {}: {}", axis, error).into());
+			}
+		}
+		let endstop_axes = config.endstop_config.keys().map(|endstop| endstop.axis);
+		if let Some(axis) = endstop_axes.chain(config.encoder_configs.keys().copied()).find(|axis| !config.motor_configs.contains_key(axis)) {
+			return Err(format!("Axis {} has an endstop or encoder, but no motor", axis).into());
+		}
+		for endstop in config.endstop_config.keys() {
+			if !config.motor_configs[&endstop.axis].motors().contains(&endstop.motor) {
+				return Err(format!("Endstop {:?} is for a motor the axis doesn't have", endstop).into());
+			}
+		}
+		for (axis, motor_config) in config.motor_configs.iter() {
+			if let Some(endstop) = motor_config.stall_endstops(*axis).into_iter().find(|endstop| config.endstop_config.contains_key(endstop)) {
+				return Err(format!("Endstop {:?} is both a real one and a stall detecting one", endstop).into());
+			}
+		}
+		let mut uart_addresses = HashMap::new();
+		for (axis, motor_config) in config.motor_configs.iter() {
+			let device = match &motor_config.driver.uart {
+				Some(uart) => &uart.device,
+				None => continue,
+			};
+			for (motor, address) in motor_config.uart_drivers() {
+				if let Some((other_axis, other_motor)) = uart_addresses.insert((device.clone(), address), (*axis, motor)) {
+					return Err(format!("Drivers of axis {} {} motor and axis {} {} motor both have address {} on {}", other_axis, other_motor, axis, motor, address, device).into());
+				}
+			}
+		}
+		for (axis, motor_config) in config.motor_configs.iter() {
+			if let Some(slave) = &motor_config.slave {
+				if !slave.max_squaring_distance.is_finite() || slave.max_squaring_distance <= 0.0 {
+					return Err(format!("Invalid slave motor config for axis {}: max squaring distance needs to be a positive number", axis).into());
+				}
+			}
+		}
+		if let Some(spindle_pwm) = &config.spindle_pwm {
+			if let Err(error) = spindle_pwm.check() {
+				return Err(format!("Invalid spindle PWM config: {}", error).into());
+			}
+		}
+		if let Some(tachometer) = &config.spindle_tachometer {
+			if !tachometer.pulses_per_rev.is_finite() || tachometer.pulses_per_rev <= 0.0 || tachometer.sample_interval_ms == 0 {
+				return Err("Invalid spindle tachometer config: pulses per rev and sample interval need to be more than 0".into());
+			}
+		}
+		if let SpindleDriverConfig::ModbusVfd(vfd_config) = &config.spindle_driver {
+			if let Err(error) = vfd_config.check() {
+				return Err(format!("Invalid VFD config: {}", error).into());
+			}
+		}
+		if config.jog_keepalive_timeout_ms == 0 {
+			return Err("Jog keepalive timeout needs to be more than zero".into());
+		}
+		self.config = config;
 		Ok(())
 	}
 
 	pub fn write_config_file(&self) {
-		let file = File::create(CONFIG_FILE_PATH).unwrap();
-		serde_yaml::to_writer(file, &self.config).unwrap();
+		let file = File::create(&self.config_file_path).unwrap();
+		let mut config = self.config.clone();
+		config.convert_units(false);
+		serde_yaml::to_writer(file, &config).unwrap();
 	}
 
 	pub fn get_config(&self) -> &RustGrindConfig {
 		&self.config
 	}
 }
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Leadscrew that needs correcting by up to a couple of thou, one way and then the other
+	fn corrected_motor_config() -> MotorConfig {
+		let mut config = ConfigManager::new().get_config().motor_configs[&Axis::X].clone();
+		let point = |position: f64, correction: f64| PitchErrorPoint{position: position * NANOMETRES_PER_INCH, correction: correction * NANOMETRES_PER_INCH};
+		config.pitch_error_table = vec![point(0.0, 0.0), point(1.0, 0.002), point(2.0, -0.001)];
+		config
+	}
+
+	#[test]
+	fn pitch_correction_can_be_removed_again() {
+		let config = corrected_motor_config();
+		assert!(config.check_pitch_error_table().is_ok());
+		// Either side of the table, on its points, and between them
+		for &position in [-1.0, 0.0, 0.25, 1.0, 1.5, 1.9999, 2.0, 3.0].iter() {
+			let position = position * NANOMETRES_PER_INCH;
+			let motor_position = position + config.get_pitch_correction(position);
+			let round_trip = config.remove_pitch_correction(motor_position);
+			assert!((round_trip - position).abs() < 1e-6, "{} came back as {}", position, round_trip);
+		}
+	}
+
+	#[test]
+	fn pitch_correction_is_interpolated_between_points() {
+		let config = corrected_motor_config();
+		assert!((config.get_pitch_correction(0.5 * NANOMETRES_PER_INCH) - 0.001 * NANOMETRES_PER_INCH).abs() < 1e-6);
+		assert!((config.get_pitch_correction(1.5 * NANOMETRES_PER_INCH) - 0.0005 * NANOMETRES_PER_INCH).abs() < 1e-6);
+		// Past the ends, the nearest end's correction carries on
+		assert_eq!(config.get_pitch_correction(-NANOMETRES_PER_INCH), 0.0);
+		assert_eq!(config.get_pitch_correction(5.0 * NANOMETRES_PER_INCH), -0.001 * NANOMETRES_PER_INCH);
+	}
+
+	/// Default X axis, with 200 steps to the inch and no pitch correction
+	fn plain_motor_config() -> MotorConfig {
+		ConfigManager::new().get_config().motor_configs[&Axis::X].clone()
+	}
+
+	#[test]
+	fn steps_are_rounded_to_the_nearest() {
+		let config = plain_motor_config();
+		let step = NANOMETRES_PER_INCH / 200.0;
+		assert_eq!(config.distance_to_steps(10.4 * step), Ok(10));
+		assert_eq!(config.distance_to_steps(10.6 * step), Ok(11));
+		assert_eq!(config.distance_to_steps(-10.6 * step), Ok(-11));
+		// Halfway rounds away from zero, the same either way
+		assert_eq!(config.distance_to_steps(10.5 * step), Ok(11));
+		assert_eq!(config.distance_to_steps(-10.5 * step), Ok(-11));
+		assert_eq!(config.position_to_steps(NANOMETRES_PER_INCH), Ok(200));
+		assert_eq!(config.steps_to_position(200), NANOMETRES_PER_INCH);
+	}
+
+	#[test]
+	fn positions_that_are_not_numbers_have_no_step() {
+		for config in [plain_motor_config(), corrected_motor_config()].iter() {
+			for &position in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY].iter() {
+				assert_eq!(config.position_to_steps(position), Err(StepConversionError::NotFinite));
+				assert_eq!(config.distance_to_steps(position), Err(StepConversionError::NotFinite));
+			}
+		}
+	}
+
+	#[test]
+	fn step_counts_past_2_to_the_53_are_out_of_range() {
+		// One step per unit, so the positions are the step counts
+		let mut config = plain_motor_config();
+		config.steps_per_rev = 1;
+		config.revs_per_unit = 1.0;
+		config.driver.microsteps = 1;
+		assert_eq!(config.position_to_steps(MAX_STEP_COUNT), Ok(1 << 53));
+		assert_eq!(config.position_to_steps(-MAX_STEP_COUNT), Ok(-(1 << 53)));
+		assert_eq!(config.position_to_steps(2.0 * MAX_STEP_COUNT), Err(StepConversionError::OutOfRange));
+		assert_eq!(config.position_to_steps(-2.0 * MAX_STEP_COUNT), Err(StepConversionError::OutOfRange));
+		// Finite, but far too big to fit in an i64
+		assert_eq!(config.distance_to_steps(f64::MAX), Err(StepConversionError::OutOfRange));
+		// Steps that overflow to infinity on the way aren't out of range, just not finite
+		config.revs_per_unit = 2.0;
+		assert_eq!(config.distance_to_steps(f64::MAX), Err(StepConversionError::NotFinite));
+	}
+
+	/// Spindle of up to 24000 rpm on PWM, with the given duty cycle map
+	fn spindle_pwm_config(duty_cycle_map: &[(f64, f64)]) -> SpindlePwmConfig {
+		SpindlePwmConfig {
+			chip: 0,
+			channel: 0,
+			frequency_hz: 1000.0,
+			min_rpm: 1000.0,
+			max_rpm: 24000.0,
+			ramp_up_rpm_per_sec: 1000.0,
+			ramp_down_rpm_per_sec: 1000.0,
+			duty_cycle_map: duty_cycle_map.iter().map(|&(rpm, duty_cycle)| SpindleDutyPoint{rpm, duty_cycle}).collect(),
+		}
+	}
+
+	#[test]
+	fn duty_cycle_without_a_map_is_proportional_to_speed() {
+		let config = spindle_pwm_config(&[]);
+		assert_eq!(config.rpm_to_duty_cycle(0.0), 0.0);
+		assert_eq!(config.rpm_to_duty_cycle(6000.0), 0.25);
+		assert_eq!(config.rpm_to_duty_cycle(24000.0), 1.0);
+		assert_eq!(config.rpm_to_duty_cycle(30000.0), 1.0);
+		assert_eq!(config.rpm_to_duty_cycle(-100.0), 0.0);
+	}
+
+	#[test]
+	fn duty_cycle_is_interpolated_along_the_map() {
+		let config = spindle_pwm_config(&[(1000.0, 0.1), (11000.0, 0.6), (21000.0, 0.9)]);
+		assert!(config.check().is_ok());
+		assert!((config.rpm_to_duty_cycle(6000.0) - 0.35).abs() < 1e-9);
+		assert!((config.rpm_to_duty_cycle(16000.0) - 0.75).abs() < 1e-9);
+		assert_eq!(config.rpm_to_duty_cycle(1000.0), 0.1);
+		assert_eq!(config.rpm_to_duty_cycle(11000.0), 0.6);
+		// Past the ends, the nearest end's duty cycle carries on
+		assert_eq!(config.rpm_to_duty_cycle(500.0), 0.1);
+		assert_eq!(config.rpm_to_duty_cycle(24000.0), 0.9);
+	}
+
+	fn driver_config(model: DriverModel, microsteps: u32) -> DriverConfig {
+		DriverConfig {
+			model,
+			microsteps,
+			..DriverConfig::default()
+		}
+	}
+
+	#[test]
+	fn microstep_pins_select_the_microstepping() {
+		assert_eq!(driver_config(DriverModel::DRV8825, 1).microstep_pin_levels(3), Ok(vec![false, false, false]));
+		assert_eq!(driver_config(DriverModel::DRV8825, 8).microstep_pin_levels(3), Ok(vec![true, true, false]));
+		assert_eq!(driver_config(DriverModel::DRV8825, 32).microstep_pin_levels(3), Ok(vec![true, false, true]));
+		assert_eq!(driver_config(DriverModel::A4988, 16).microstep_pin_levels(3), Ok(vec![true, true, true]));
+		assert_eq!(driver_config(DriverModel::TMC2209, 64).microstep_pin_levels(2), Ok(vec![false, true]));
+		// Without pins, the microstepping is set some other way, whatever the driver is
+		assert_eq!(driver_config(DriverModel::Generic, 400).microstep_pin_levels(0), Ok(Vec::new()));
+	}
+
+	#[test]
+	fn microstepping_the_pins_cannot_select_is_refused() {
+		assert!(driver_config(DriverModel::A4988, 32).microstep_pin_levels(3).is_err());
+		assert!(driver_config(DriverModel::TMC2209, 4).microstep_pin_levels(2).is_err());
+		assert!(driver_config(DriverModel::Generic, 1).microstep_pin_levels(3).is_err());
+		// One pin for each of the driver's mode inputs
+		assert!(driver_config(DriverModel::DRV8825, 8).microstep_pin_levels(2).is_err());
+		assert!(driver_config(DriverModel::DRV8825, 0).microstep_pin_levels(0).is_err());
+	}
+}
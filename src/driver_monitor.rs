@@ -0,0 +1,431 @@
+use crate::common::Axis;
+use crate::common::AxisMotor;
+use crate::common::EndstopIdentifier;
+use crate::config::RustGrindConfig;
+use crate::config::TmcUartConfig;
+use crate::hardware::HardwareBackend;
+use crate::hardware::HardwareError;
+use crate::messages::DriverFault;
+use crate::messages::DriverFaultMsg;
+use crate::messages::EndstopHitMsg;
+use crate::messages::Message;
+use crate::tmc_uart;
+use crate::tmc_uart::TmcUartClient;
+
+use std::collections::HashMap;
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+
+
+/// Number of times in a row a driver can fail to answer before it's reported as not responding
+const NO_RESPONSE_LIMIT: u32 = 3;
+
+
+
+/// A TMC2209 on one of the UARTs, and what's last been reported about it
+struct MonitoredDriver {
+	axis: Axis,
+	motor: AxisMotor,
+	address: u8,
+	config: TmcUartConfig,
+	microsteps: u32,
+	/// TSTEP at the StallGuard min speed. The motor can only count as stalled while it's going faster, i.e. with a TSTEP no more than this.
+	stall_tstep: u32,
+	stall_endstop: Option<EndstopIdentifier>,
+	stalled: bool,
+	faults: Vec<DriverFault>,
+	failed_transactions: u32,
+}
+
+/// What a driver says about itself each time it's polled
+struct DriverStatus {
+	drv_status: u32,
+	gstat: u32,
+	/// TSTEP and the StallGuard reading, if it has stall detection
+	stall_guard: Option<(u32, u32)>,
+}
+
+/// Drivers sharing one UART
+struct DriverLink {
+	client: TmcUartClient,
+	drivers: Vec<MonitoredDriver>,
+}
+
+/**
+ * Sets up the TMC2209 drivers that are wired to a UART, then keeps polling them.
+ * Stalls are reported as endstop hits (and unstalling as the endstops clearing), so anything that stops at an endstop stops at a stall too.
+ * Changes in the error flags are reported as driver faults.
+ */
+struct DriverMonitor {
+	links: Vec<DriverLink>,
+	poll_interval: Duration,
+	msg_senders: Vec<Sender<Message>>,
+}
+
+impl DriverMonitor {
+	/// Open the UARTs and set up all the drivers on them, so they're microstepping as configured before anything moves
+	pub fn new(initial_config: &RustGrindConfig, hardware: &dyn HardwareBackend, msg_senders: Vec<Sender<Message>>) -> Self {
+		let mut drivers_by_device: HashMap<String, (u32, Vec<MonitoredDriver>)> = HashMap::new();
+		let mut poll_interval_ms = u64::MAX;
+		for (axis, motor_config) in initial_config.motor_configs.iter() {
+			let uart = match &motor_config.driver.uart {
+				Some(uart) => uart,
+				None => continue,
+			};
+			poll_interval_ms = poll_interval_ms.min(uart.poll_interval_ms);
+			let microsteps = motor_config.driver.microsteps;
+			let stall_tstep = match uart.stall_guard {
+				Some(stall_guard) => tmc_uart::tstep(stall_guard.min_speed * motor_config.steps_per_unit(), microsteps),
+				None => 0,
+			};
+			let (_, drivers) = drivers_by_device.entry(uart.device.clone()).or_insert_with(|| (uart.baud_rate, Vec::new()));
+			for (motor, address) in motor_config.uart_drivers() {
+				drivers.push(MonitoredDriver {
+					axis: *axis,
+					motor,
+					address,
+					config: uart.clone(),
+					microsteps,
+					stall_tstep,
+					stall_endstop: uart.stall_guard.map(|stall_guard| EndstopIdentifier::for_motor(*axis, stall_guard.end, motor)),
+					stalled: false,
+					faults: Vec::new(),
+					failed_transactions: 0,
+				});
+			}
+		}
+
+		let mut monitor = DriverMonitor {
+			links: Vec::new(),
+			poll_interval: Duration::from_millis(poll_interval_ms),
+			msg_senders,
+		};
+		for (device, (baud_rate, mut drivers)) in drivers_by_device {
+			let addresses = drivers.iter().map(|driver| (driver.address, (driver.axis, driver.motor))).collect();
+			let client = hardware.make_driver_uart_port(&device, baud_rate, &addresses).and_then(TmcUartClient::new);
+			let mut client = match client {
+				Ok(client) => client,
+				Err(error) => {
+					println!("Couldn't open driver UART {}, error is {:?}", device, error);
+					for driver in drivers.iter_mut() {
+						monitor.report_faults(driver, vec![DriverFault::NoResponse]);
+					}
+					continue;
+				},
+			};
+			for driver in drivers.iter_mut() {
+				match configure_driver(&mut client, driver) {
+					Ok(()) => println!("Set up driver of axis {} {} motor", driver.axis, driver.motor),
+					Err(error) => {
+						println!("Couldn't set up driver of axis {} {} motor, error is {:?}", driver.axis, driver.motor, error);
+						monitor.report_faults(driver, vec![DriverFault::NoResponse]);
+						driver.failed_transactions = NO_RESPONSE_LIMIT;
+					},
+				}
+				// Start with the stall detecting endstops clear, as the motors can't be stalled before they've moved
+				if let Some(endstop) = driver.stall_endstop {
+					monitor.send_endstop_status(endstop, false);
+				}
+			}
+			monitor.links.push(DriverLink{client, drivers});
+		}
+		monitor
+	}
+
+	pub fn run(&mut self) {
+		loop {
+			thread::sleep(self.poll_interval);
+			// Taken out while they're polled, so the drivers can be updated while reporting on them
+			let mut links = mem::take(&mut self.links);
+			for link in links.iter_mut() {
+				for driver in link.drivers.iter_mut() {
+					self.poll(&mut link.client, driver);
+				}
+			}
+			self.links = links;
+		}
+	}
+
+	fn poll(&self, client: &mut TmcUartClient, driver: &mut MonitoredDriver) {
+		let status = match read_status(client, driver) {
+			Ok(status) => status,
+			Err(error) => return self.transaction_failed(driver, error),
+		};
+		if driver.failed_transactions >= NO_RESPONSE_LIMIT {
+			println!("Driver of axis {} {} motor is answering again", driver.axis, driver.motor);
+		}
+		driver.failed_transactions = 0;
+
+		if status.gstat & tmc_uart::GSTAT_RESET != 0 {
+			// e.g. its motor power was switched off and on again, so it's gone back to its defaults
+			println!("Driver of axis {} {} motor has been reset, setting it up again", driver.axis, driver.motor);
+			if let Err(error) = configure_driver(client, driver) {
+				return self.transaction_failed(driver, error);
+			}
+		}
+
+		self.report_faults(driver, faults_from_status(&status));
+
+		if let (Some(endstop), Some((tstep, sg_result))) = (driver.stall_endstop, status.stall_guard) {
+			let threshold = driver.config.stall_guard.map_or(0, |stall_guard| stall_guard.threshold as u32);
+			let moving = status.drv_status & tmc_uart::DRV_STATUS_STST == 0 && tstep <= driver.stall_tstep;
+			let stalled = moving && sg_result <= 2 * threshold;
+			if stalled != driver.stalled {
+				println!("Axis {} {} motor {}", driver.axis, driver.motor, if stalled { "stalled" } else { "no longer stalled" });
+				driver.stalled = stalled;
+				self.send_endstop_status(endstop, stalled);
+			}
+		}
+	}
+
+	fn transaction_failed(&self, driver: &mut MonitoredDriver, error: HardwareError) {
+		driver.failed_transactions += 1;
+		// Only the first failure is logged, so a disconnected driver doesn't flood the log
+		if driver.failed_transactions == 1 {
+			println!("UART transaction with driver of axis {} {} motor failed, error is {:?}", driver.axis, driver.motor, error);
+		}
+		if driver.failed_transactions == NO_RESPONSE_LIMIT {
+			self.report_faults(driver, vec![DriverFault::NoResponse]);
+		}
+	}
+
+	/// Send on the driver's faults, if they've changed since they were last reported
+	fn report_faults(&self, driver: &mut MonitoredDriver, faults: Vec<DriverFault>) {
+		if driver.faults == faults {
+			return;
+		}
+		println!("Driver of axis {} {} motor reports faults {:?}", driver.axis, driver.motor, faults);
+		for sender in &self.msg_senders {
+			if sender.send(Message::DriverFaultMsgType(DriverFaultMsg{axis: driver.axis, motor: driver.motor, faults: faults.clone()})).is_err() {
+				println!("Nothing is listening for driver faults");
+			}
+		}
+		driver.faults = faults;
+	}
+
+	fn send_endstop_status(&self, endstop: EndstopIdentifier, value: bool) {
+		for sender in &self.msg_senders {
+			if sender.send(Message::EndstopHitMsgType(EndstopHitMsg{endstop, value})).is_err() {
+				println!("Nothing is listening for endstop status");
+			}
+		}
+	}
+}
+
+
+/// Set the driver up as configured, and make sure it took all of it
+fn configure_driver(client: &mut TmcUartClient, driver: &MonitoredDriver) -> Result<(), HardwareError> {
+	let address = driver.address;
+	let config = &driver.config;
+	let count_before = client.read_register(address, tmc_uart::IFCNT)?;
+
+	let mut gconf = tmc_uart::GCONF_PDN_DISABLE | tmc_uart::GCONF_MSTEP_REG_SELECT | tmc_uart::GCONF_MULTISTEP_FILT;
+	if !config.stealth_chop {
+		gconf |= tmc_uart::GCONF_EN_SPREADCYCLE;
+	}
+	let (vsense, ihold_irun) = tmc_uart::current_settings(config.run_current, config.hold_current, config.sense_resistor_ohms);
+	// Only the microstepping and current range are changed, leaving the chopper timing as it is
+	let mres = tmc_uart::microstep_resolution(driver.microsteps).ok_or_else(|| format!("{} microsteps can't be set", driver.microsteps))?;
+	let mut chopconf = client.read_register(address, tmc_uart::CHOPCONF)?;
+	chopconf &= !(tmc_uart::CHOPCONF_MRES_MASK | tmc_uart::CHOPCONF_VSENSE);
+	chopconf |= (mres << tmc_uart::CHOPCONF_MRES_SHIFT) | tmc_uart::CHOPCONF_INTPOL;
+	if vsense {
+		chopconf |= tmc_uart::CHOPCONF_VSENSE;
+	}
+
+	let mut writes = vec![
+		(tmc_uart::GCONF, gconf),
+		(tmc_uart::CHOPCONF, chopconf),
+		(tmc_uart::IHOLD_IRUN, ihold_irun),
+	];
+	if let Some(stall_guard) = config.stall_guard {
+		writes.push((tmc_uart::SGTHRS, stall_guard.threshold as u32));
+		writes.push((tmc_uart::TCOOLTHRS, driver.stall_tstep));
+	}
+	// Clear the reset flag (and any others), so we can tell if it's reset again
+	writes.push((tmc_uart::GSTAT, tmc_uart::GSTAT_RESET | tmc_uart::GSTAT_DRV_ERR | tmc_uart::GSTAT_UV_CP));
+	for (register, value) in writes.iter() {
+		client.write_register(address, *register, *value)?;
+	}
+
+	// Writes aren't answered, so count them instead
+	let count_after = client.read_register(address, tmc_uart::IFCNT)?;
+	let accepted = count_after.wrapping_sub(count_before) & 0xFF;
+	if accepted != writes.len() as u32 {
+		return Err(format!("only {} of {} writes were accepted", accepted, writes.len()).into());
+	}
+	Ok(())
+}
+
+fn read_status(client: &mut TmcUartClient, driver: &MonitoredDriver) -> Result<DriverStatus, HardwareError> {
+	let drv_status = client.read_register(driver.address, tmc_uart::DRV_STATUS)?;
+	let gstat = client.read_register(driver.address, tmc_uart::GSTAT)?;
+	let stall_guard = match driver.stall_endstop {
+		Some(_) => Some((client.read_register(driver.address, tmc_uart::TSTEP)?, client.read_register(driver.address, tmc_uart::SG_RESULT)?)),
+		None => None,
+	};
+	Ok(DriverStatus {
+		drv_status,
+		gstat,
+		stall_guard,
+	})
+}
+
+fn faults_from_status(status: &DriverStatus) -> Vec<DriverFault> {
+	let flag = |bits: u32| status.drv_status & bits != 0;
+	let mut faults = Vec::new();
+	if flag(tmc_uart::DRV_STATUS_OT) {
+		faults.push(DriverFault::OverTemperature);
+	} else if flag(tmc_uart::DRV_STATUS_OTPW) {
+		faults.push(DriverFault::OverTemperatureWarning);
+	}
+	if flag(tmc_uart::DRV_STATUS_S2GA | tmc_uart::DRV_STATUS_S2GB | tmc_uart::DRV_STATUS_S2VSA | tmc_uart::DRV_STATUS_S2VSB) {
+		faults.push(DriverFault::Short);
+	}
+	if flag(tmc_uart::DRV_STATUS_OLA | tmc_uart::DRV_STATUS_OLB) {
+		faults.push(DriverFault::OpenLoad);
+	}
+	if status.gstat & tmc_uart::GSTAT_UV_CP != 0 {
+		faults.push(DriverFault::UnderVoltage);
+	}
+	faults
+}
+
+
+pub fn init(initial_config: RustGrindConfig, hardware: Arc<dyn HardwareBackend>, msg_senders: Vec<Sender<Message>>) {
+	if initial_config.motor_configs.values().all(|motor_config| motor_config.driver.uart.is_none()) {
+		return;
+	}
+	let mut monitor = DriverMonitor::new(&initial_config, hardware.as_ref(), msg_senders);
+	let builder = thread::Builder::new().name("DriverMonitor".to_string());
+	builder.spawn(move || {
+		monitor.run();
+	}).unwrap();
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::AxisEnd;
+	use crate::config::SerialParity;
+	use crate::config::StallGuardConfig;
+	use crate::hardware::open_serial_port;
+	use crate::hardware::simulated_tmc;
+	use crate::hardware::simulated_tmc::SimulatedDriver;
+
+	use std::fs::File;
+	use std::io::Read;
+	use std::io::Write;
+	use std::os::unix::io::FromRawFd;
+	use std::path::Path;
+
+	use nix::pty;
+	use nix::unistd;
+
+	fn monitored_driver(address: u8, stall_guard: Option<StallGuardConfig>) -> MonitoredDriver {
+		MonitoredDriver {
+			axis: Axis::X,
+			motor: AxisMotor::Main,
+			address,
+			config: TmcUartConfig {
+				device: String::new(),
+				baud_rate: 115200,
+				address,
+				run_current: 0.8,
+				hold_current: 0.4,
+				sense_resistor_ohms: 0.11,
+				stealth_chop: stall_guard.is_some(),
+				stall_guard,
+				poll_interval_ms: 10,
+			},
+			microsteps: 16,
+			stall_tstep: 400,
+			stall_endstop: None,
+			stalled: false,
+			faults: Vec::new(),
+			failed_transactions: 0,
+		}
+	}
+
+	fn connect(device: &Path) -> TmcUartClient {
+		TmcUartClient::new(open_serial_port(device, 115200, SerialParity::None).unwrap()).unwrap()
+	}
+
+	fn simulated_client(address: u8) -> TmcUartClient {
+		let driver = SimulatedDriver {
+			get_position: Box::new(|| 0.0),
+			steps_per_unit: 1.0,
+			stall_positions: Vec::new(),
+		};
+		connect(&simulated_tmc::start(vec![(address, driver)].into_iter().collect(), None).unwrap())
+	}
+
+	/// A driver that answers reads, but loses every write (as if they were corrupted on the wire), so its IFCNT never moves
+	fn deaf_client() -> TmcUartClient {
+		let pty = pty::openpty(None, None).unwrap();
+		let device = unistd::ttyname(pty.slave).unwrap();
+		let mut master = unsafe { File::from_raw_fd(pty.master) };
+		let slave = unsafe { File::from_raw_fd(pty.slave) };
+		thread::spawn(move || {
+			let _slave = slave;
+			let mut received = Vec::new();
+			let mut buffer = [0; 64];
+			while let Ok(count) = master.read(&mut buffer) {
+				master.write_all(&buffer[..count]).unwrap();
+				received.extend_from_slice(&buffer[..count]);
+				while received.len() >= 3 {
+					let length = if received[2] & tmc_uart::WRITE_FLAG != 0 { tmc_uart::DATAGRAM_LENGTH } else { tmc_uart::READ_REQUEST_LENGTH };
+					if received.len() < length {
+						break;
+					}
+					let datagram: Vec<u8> = received.drain(..length).collect();
+					if length == tmc_uart::READ_REQUEST_LENGTH {
+						let mut reply = vec![tmc_uart::SYNC, tmc_uart::MASTER_ADDRESS, datagram[2], 0, 0, 0, 0];
+						tmc_uart::append_crc(&mut reply);
+						master.write_all(&reply).unwrap();
+					}
+				}
+			}
+		});
+		connect(&device)
+	}
+
+	#[test]
+	fn driver_is_configured_and_every_write_is_counted() {
+		let stall_guard = StallGuardConfig{end: AxisEnd::Min, threshold: 60, min_speed: 0.5};
+		let driver = monitored_driver(2, Some(stall_guard));
+		let mut client = simulated_client(2);
+		configure_driver(&mut client, &driver).unwrap();
+		let chopconf = client.read_register(2, tmc_uart::CHOPCONF).unwrap();
+		assert_eq!((chopconf & tmc_uart::CHOPCONF_MRES_MASK) >> tmc_uart::CHOPCONF_MRES_SHIFT, 4);
+		assert_eq!(client.read_register(2, tmc_uart::SGTHRS).unwrap(), 60);
+		assert_eq!(client.read_register(2, tmc_uart::TCOOLTHRS).unwrap(), 400);
+		assert_eq!(client.read_register(2, tmc_uart::GSTAT).unwrap() & tmc_uart::GSTAT_RESET, 0);
+		assert_eq!(client.read_register(2, tmc_uart::IFCNT).unwrap(), 6);
+	}
+
+	#[test]
+	fn write_count_wraps_around() {
+		let driver = monitored_driver(0, None);
+		let mut client = simulated_client(0);
+		// IFCNT is only 8 bits, so leave it just short of wrapping
+		for _ in 0..254 {
+			client.write_register(0, tmc_uart::TCOOLTHRS, 0).unwrap();
+		}
+		configure_driver(&mut client, &driver).unwrap();
+		assert_eq!(client.read_register(0, tmc_uart::IFCNT).unwrap(), 2);
+	}
+
+	#[test]
+	fn lost_writes_are_an_error() {
+		let driver = monitored_driver(0, None);
+		let mut client = deaf_client();
+		assert!(configure_driver(&mut client, &driver).is_err());
+	}
+}
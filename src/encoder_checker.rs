@@ -0,0 +1,76 @@
+use crate::common::Axis;
+use crate::config::EncoderConfig;
+use crate::config::RustGrindConfig;
+use crate::hardware::EncoderInputs;
+use crate::hardware::HardwareBackend;
+use crate::machine_state::MachineStateClient;
+use crate::messages::EncoderPositionMsg;
+use crate::messages::Message;
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+
+
+/// How often the measured positions are sent to motor control, if they've changed.
+/// Motor control compares them with where the axes are by the time the message arrives, so the following error limits need to allow for how far an axis can move in this time.
+const REPORT_INTERVAL: Duration = Duration::from_millis(5);
+
+
+
+/**
+ * Reads the encoders (or glass scales) on the axes that have them, and keeps motor control up to date with the positions they measure.
+ */
+struct EncoderChecker {
+	inputs: Box<dyn EncoderInputs>,
+	encoder_configs: HashMap<Axis, EncoderConfig>,
+	/// Positions the encoders were at when the controller last stopped, as the counts start from zero again
+	start_positions: HashMap<Axis, f64>,
+	msg_sender: Sender<Message>,
+}
+
+impl EncoderChecker {
+	pub fn new(initial_config: &RustGrindConfig, hardware: &dyn HardwareBackend, machine_state: &MachineStateClient, msg_sender: Sender<Message>) -> Self {
+		let start_positions = machine_state.get().axes.iter()
+			.filter_map(|(axis, axis_state)| axis_state.encoder_position.map(|position| (*axis, position)))
+			.collect();
+		EncoderChecker{
+			inputs: hardware.make_encoder_inputs(&initial_config.encoder_configs).unwrap(),
+			encoder_configs: initial_config.encoder_configs.clone(),
+			start_positions,
+			msg_sender,
+		}
+	}
+
+	pub fn run(&mut self) {
+		let mut last_counts = HashMap::new();
+		loop {
+			let counts = self.inputs.read_counts(REPORT_INTERVAL).unwrap();
+			if counts != last_counts {
+				let positions = counts.iter()
+					.map(|(axis, count)| (*axis, self.start_positions.get(axis).copied().unwrap_or(0.0) + self.encoder_configs[axis].counts_to_position(*count)))
+					.collect();
+				if self.msg_sender.send(Message::EncoderPositionMsgType(EncoderPositionMsg{positions})).is_err() {
+					println!("Motor control has gone away, so nothing needs the encoder positions any more");
+					return;
+				}
+				last_counts = counts;
+			}
+		}
+	}
+}
+
+
+pub fn init(initial_config: RustGrindConfig, hardware: Arc<dyn HardwareBackend>, machine_state: MachineStateClient, msg_sender: Sender<Message>) {
+	if initial_config.encoder_configs.is_empty() {
+		return;
+	}
+	let builder = thread::Builder::new().name("EncoderChecker".to_string());
+	builder.spawn(move || {
+		let mut checker = EncoderChecker::new(&initial_config, hardware.as_ref(), &machine_state, msg_sender);
+		checker.run();
+	}).unwrap();
+}
@@ -1,16 +1,13 @@
 use crate::common::EndstopIdentifier;
 use crate::config::RustGrindConfig;
+use crate::hardware::EndstopInputs;
+use crate::hardware::HardwareBackend;
 use crate::messages::EndstopHitMsg;
 use crate::messages::Message;
 
-use gpio_cdev::*;
-use nix::poll::*;
-
-type PollEventFlags = nix::poll::PollFlags;
-
 use std::collections::HashMap;
-use std::os::unix::io::AsRawFd;
 use std::sync::mpsc::Sender;
+use std::sync::Arc;
 use std::thread;
 
 
@@ -39,90 +36,45 @@ impl EndstopStatusClient {
 
 
 struct EndstopChecker {
-	chip_name: String,
-	lines: HashMap<EndstopIdentifier, u32>,
-	line_to_endstop_id: HashMap<u32, EndstopIdentifier>,
+	inputs: Box<dyn EndstopInputs>,
 	msg_senders: Vec<Sender<Message>>,
 }
 
 impl EndstopChecker {
-	pub fn new(initial_config : &RustGrindConfig, msg_senders: Vec<Sender<Message>>) -> Self {
-		let mut line_to_endstop_id = HashMap::new();
-		for (endstop_id, line) in initial_config.endstop_config.iter() {
-			line_to_endstop_id.insert(line.clone(), endstop_id.clone());
-		}
+	pub fn new(initial_config : &RustGrindConfig, hardware: &dyn HardwareBackend, msg_senders: Vec<Sender<Message>>) -> Self {
 		EndstopChecker{
-			chip_name: initial_config.gpio_chip_name.clone(),
-			lines: initial_config.endstop_config.clone(),
-			line_to_endstop_id,
+			inputs: hardware.make_endstop_inputs(&initial_config.endstop_config).unwrap(),
 			msg_senders,
 		}
 	}
 
 	pub fn run(&mut self) {
-		// Based off of https://github.com/rust-embedded/gpio-cdev/blob/master/examples/monitor.rs
-
-		println!("Open chip name {}", self.chip_name);
-
-		let mut chip = Chip::new(self.chip_name.clone()).unwrap();
-		// Get event handles for each line to monitor.
-		let mut evt_handles: Vec<LineEventHandle> = self.lines
-			.values()
-			.into_iter()
-			.map(|pin_num| {
-				let line = chip.get_line(*pin_num).unwrap();
-				line.events(
-					LineRequestFlags::INPUT,
-					EventRequestFlags::BOTH_EDGES,
-					"monitor",
-				)
-				.unwrap()
-			})
-			.collect();
-
-		// Create a vector of file descriptors for polling
-		let mut pollfds: Vec<PollFd> = evt_handles
-			.iter()
-			.map(|h| {
-				PollFd::new(
-					h.as_raw_fd(),
-					PollEventFlags::POLLIN | PollEventFlags::POLLPRI,
-				)
-			})
-			.collect();
-
-		// TODO: check initial status of endstops and send out messages
+		// Start with the endstops that are already hit, so nothing tries to move into them before they change
+		for (endstop, value) in self.inputs.read_all().unwrap() {
+			self.send_status(endstop, value);
+		}
 
 		loop {
-			// Poll for an event on any of the lines
-			if poll(&mut pollfds, -1).unwrap() != 0 {
-				// received data
-				for i in 0..pollfds.len() {
-					if let Some(revts) = pollfds[i].revents() {
-						let h = &mut evt_handles[i];
-						if revts.contains(PollEventFlags::POLLIN) {
-							let value = h.get_value().unwrap();
-							// Retrieve and clear the event
-							// We don't need the event object itself, but it won't clear until we take it.
-							h.get_event().unwrap();
-							let endstop = self.line_to_endstop_id.get(&h.line().offset()).unwrap();
-							for sender in &self.msg_senders {
-								sender.send(Message::EndstopHitMsgType(EndstopHitMsg{endstop: *endstop, value: (value != 0)}));
-							}
-							println!("Got event for GPIO {}, new value {}", h.line().offset(), value);
-						}
-					}
-				}
+			for (endstop, value) in self.inputs.wait_for_changes().unwrap() {
+				self.send_status(endstop, value);
+			}
+		}
+	}
+
+	fn send_status(&self, endstop: EndstopIdentifier, value: bool) {
+		for sender in &self.msg_senders {
+			if sender.send(Message::EndstopHitMsgType(EndstopHitMsg{endstop, value})).is_err() {
+				println!("Nothing is listening for endstop status");
 			}
 		}
 	}
 }
 
 
-pub fn init(initial_config : RustGrindConfig, msg_senders: Vec<Sender<Message>>) {
+pub fn init(initial_config : RustGrindConfig, hardware: Arc<dyn HardwareBackend>, msg_senders: Vec<Sender<Message>>) {
 	let builder = thread::Builder::new().name("EndstopChecker".to_string());
 	builder.spawn(move || {
-		let mut checker = EndstopChecker::new(&initial_config, msg_senders);
+		let mut checker = EndstopChecker::new(&initial_config, hardware.as_ref(), msg_senders);
 		checker.run();
 	}).unwrap();
 }
@@ -0,0 +1,417 @@
+use super::DigitalOutput;
+use super::EncoderInputs;
+use super::EndstopInputs;
+use super::HardwareBackend;
+use super::HardwareError;
+use super::MotorPins;
+use super::PulseCounter;
+use super::PwmOutput;
+use super::open_serial_port;
+
+use crate::common::Axis;
+use crate::common::AxisMotor;
+use crate::common::EndstopIdentifier;
+use crate::config::EncoderConfig;
+use crate::config::ModbusVfdConfig;
+use crate::config::MotorConfig;
+use crate::config::SerialParity;
+use crate::config::SpindlePwmConfig;
+use crate::config::SpindleTachometerConfig;
+
+use gpio_cdev::*;
+use nix::poll::*;
+
+type PollEventFlags = nix::poll::PollFlags;
+
+use std::collections::HashMap;
+use std::fs;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use embedded_hal::digital::OutputPin;
+
+use linux_embedded_hal::CdevPin;
+use linux_embedded_hal::sysfs_gpio::Direction as PinDirection;
+use linux_embedded_hal::SysfsPin;
+
+use serial_unix::TTYPort;
+
+
+
+/// How long to wait for a PWM channel to appear after exporting it, while udev sets up its permissions
+const PWM_EXPORT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Change in count for each transition of a quadrature encoder, indexed by its old and new states (the A channel in bit 1, B in bit 0).
+/// Both channels changing at once means an edge was missed, so there's no telling which way it went.
+const QUADRATURE_TRANSITIONS: [[i64; 4]; 4] = [
+	[0, 1, -1, 0],
+	[-1, 0, 0, 1],
+	[1, 0, 0, -1],
+	[0, -1, 1, 0],
+];
+
+
+impl DigitalOutput for SysfsPin {
+	fn set_high(&mut self) -> Result<(), HardwareError> {
+		Ok(self.try_set_high()?)
+	}
+
+	fn set_low(&mut self) -> Result<(), HardwareError> {
+		Ok(self.try_set_low()?)
+	}
+}
+
+impl DigitalOutput for CdevPin {
+	fn set_high(&mut self) -> Result<(), HardwareError> {
+		Ok(self.try_set_high()?)
+	}
+
+	fn set_low(&mut self) -> Result<(), HardwareError> {
+		Ok(self.try_set_low()?)
+	}
+}
+
+
+
+/**
+ * Real hardware: motors on sysfs GPIO pins, spindle and endstops on the GPIO character device, spindle speed on the PWM sysfs class, and the VFD on a serial device.
+ */
+pub struct GpioBackend {
+	chip_name: String,
+}
+
+impl GpioBackend {
+	pub fn new(chip_name: String) -> Self {
+		GpioBackend {
+			chip_name,
+		}
+	}
+
+	fn make_sysfs_output(&self, pin_number: u64) -> Result<SysfsPin, HardwareError> {
+		// FIXME: switch to cdev pins, and remember to run `echo PIN_NUMBER > /sys/class/gpio/unexport` for each pin to switch it away from sysfs
+		let pin = SysfsPin::new(pin_number);
+		match pin.export() {
+			Ok(()) => println!("Gpio {} exported!", pin.get_pin()),
+			Err(err) => println!("Gpio {} could not be exported: {}", pin.get_pin(), err)
+		}
+		pin.set_direction(PinDirection::Out)?;
+		Ok(pin)
+	}
+}
+
+impl HardwareBackend for GpioBackend {
+	fn make_motor_pins(&self, axis: Axis, motor: AxisMotor, config: &MotorConfig) -> Result<MotorPins, HardwareError> {
+		let (enable_pin_number, step_pin_number, direction_pin_number, microstep_pin_numbers) = match (motor, &config.slave) {
+			(AxisMotor::Main, _) => (config.enable_pin_number, config.step_pin_number, config.direction_pin_number, &config.microstep_pin_numbers),
+			(AxisMotor::Slave, Some(slave)) => (slave.enable_pin_number, slave.step_pin_number, slave.direction_pin_number, &slave.microstep_pin_numbers),
+			(AxisMotor::Slave, None) => return Err(format!("Axis {} has no slave motor", axis).into()),
+		};
+		let mut microstep: Vec<Box<dyn DigitalOutput>> = Vec::new();
+		for pin_number in microstep_pin_numbers.iter() {
+			microstep.push(Box::new(self.make_sysfs_output(*pin_number)?));
+		}
+		Ok(MotorPins {
+			enable: Box::new(self.make_sysfs_output(enable_pin_number)?),
+			step: Box::new(self.make_sysfs_output(step_pin_number)?),
+			direction: Box::new(self.make_sysfs_output(direction_pin_number)?),
+			microstep,
+		})
+	}
+
+	fn make_spindle_pin(&self, line: u32) -> Result<Box<dyn DigitalOutput>, HardwareError> {
+		let mut chip = linux_embedded_hal::gpio_cdev::Chip::new(self.chip_name.clone())?;
+		let line_handle = chip.get_line(line)?.request(linux_embedded_hal::gpio_cdev::LineRequestFlags::OUTPUT, 0, "spindle control")?;
+		Ok(Box::new(CdevPin::new(line_handle)?))
+	}
+
+	fn make_spindle_pwm(&self, config: &SpindlePwmConfig) -> Result<Box<dyn PwmOutput>, HardwareError> {
+		Ok(Box::new(SysfsPwm::new(config.chip, config.channel, config.frequency_hz)?))
+	}
+
+	fn make_endstop_inputs(&self, endstops: &HashMap<EndstopIdentifier, u32>) -> Result<Box<dyn EndstopInputs>, HardwareError> {
+		Ok(Box::new(GpioEndstopInputs::new(&self.chip_name, endstops)?))
+	}
+
+	fn make_encoder_inputs(&self, encoders: &HashMap<Axis, EncoderConfig>) -> Result<Box<dyn EncoderInputs>, HardwareError> {
+		Ok(Box::new(GpioEncoderInputs::new(&self.chip_name, encoders)?))
+	}
+
+	fn make_vfd_port(&self, config: &ModbusVfdConfig) -> Result<TTYPort, HardwareError> {
+		open_serial_port(Path::new(&config.device), config.baud_rate, config.parity)
+	}
+
+	fn make_spindle_tachometer(&self, config: &SpindleTachometerConfig) -> Result<Box<dyn PulseCounter>, HardwareError> {
+		let mut chip = Chip::new(&self.chip_name)?;
+		let handle = chip.get_line(config.pin)?.events(LineRequestFlags::INPUT, EventRequestFlags::RISING_EDGE, "tachometer")?;
+		Ok(Box::new(GpioPulseCounter{handle}))
+	}
+
+	fn make_driver_uart_port(&self, device: &str, baud_rate: u32, _drivers: &HashMap<u8, (Axis, AxisMotor)>) -> Result<TTYPort, HardwareError> {
+		open_serial_port(Path::new(device), baud_rate, SerialParity::None)
+	}
+}
+
+
+
+struct GpioEndstopInputs {
+	evt_handles: Vec<LineEventHandle>,
+	pollfds: Vec<PollFd>,
+	line_to_endstop_id: HashMap<u32, EndstopIdentifier>,
+}
+
+impl GpioEndstopInputs {
+	pub fn new(chip_name: &str, endstops: &HashMap<EndstopIdentifier, u32>) -> Result<Self, HardwareError> {
+		// Based off of https://github.com/rust-embedded/gpio-cdev/blob/master/examples/monitor.rs
+
+		println!("Open chip name {}", chip_name);
+
+		let mut line_to_endstop_id = HashMap::new();
+		for (endstop_id, line) in endstops.iter() {
+			line_to_endstop_id.insert(*line, *endstop_id);
+		}
+
+		let mut chip = Chip::new(chip_name)?;
+		// Get event handles for each line to monitor.
+		let mut evt_handles: Vec<LineEventHandle> = Vec::new();
+		for pin_num in endstops.values() {
+			let line = chip.get_line(*pin_num)?;
+			evt_handles.push(line.events(
+				LineRequestFlags::INPUT,
+				EventRequestFlags::BOTH_EDGES,
+				"monitor",
+			)?);
+		}
+
+		// Create a vector of file descriptors for polling
+		let pollfds: Vec<PollFd> = evt_handles
+			.iter()
+			.map(|h| {
+				PollFd::new(
+					h.as_raw_fd(),
+					PollEventFlags::POLLIN | PollEventFlags::POLLPRI,
+				)
+			})
+			.collect();
+
+		Ok(GpioEndstopInputs {
+			evt_handles,
+			pollfds,
+			line_to_endstop_id,
+		})
+	}
+}
+
+impl EndstopInputs for GpioEndstopInputs {
+	fn read_all(&mut self) -> Result<Vec<(EndstopIdentifier, bool)>, HardwareError> {
+		let mut values = Vec::new();
+		for h in self.evt_handles.iter() {
+			let value = h.get_value()?;
+			let endstop = self.line_to_endstop_id.get(&h.line().offset()).unwrap();
+			values.push((*endstop, value != 0));
+		}
+		Ok(values)
+	}
+
+	fn wait_for_changes(&mut self) -> Result<Vec<(EndstopIdentifier, bool)>, HardwareError> {
+		let mut changes = Vec::new();
+		// Poll for an event on any of the lines
+		while changes.is_empty() {
+			if poll(&mut self.pollfds, -1)? != 0 {
+				// received data
+				for i in 0..self.pollfds.len() {
+					if let Some(revts) = self.pollfds[i].revents() {
+						let h = &mut self.evt_handles[i];
+						if revts.contains(PollEventFlags::POLLIN) {
+							let value = h.get_value()?;
+							// Retrieve and clear the event
+							// We don't need the event object itself, but it won't clear until we take it.
+							h.get_event()?;
+							let endstop = self.line_to_endstop_id.get(&h.line().offset()).unwrap();
+							changes.push((*endstop, value != 0));
+							println!("Got event for GPIO {}, new value {}", h.line().offset(), value);
+						}
+					}
+				}
+			}
+		}
+		Ok(changes)
+	}
+}
+
+
+
+/**
+ * PWM channel of the Linux PWM sysfs class, i.e. /sys/class/pwm/pwmchipN/pwmM
+ */
+struct SysfsPwm {
+	path: PathBuf,
+	period_ns: u64,
+}
+
+impl SysfsPwm {
+	pub fn new(chip: u32, channel: u32, frequency_hz: f64) -> Result<Self, HardwareError> {
+		let chip_path = PathBuf::from(format!("/sys/class/pwm/pwmchip{}", chip));
+		let path = chip_path.join(format!("pwm{}", channel));
+		if !path.exists() {
+			fs::write(chip_path.join("export"), channel.to_string())?;
+			let mut waited = Duration::from_secs(0);
+			while !path.join("enable").exists() {
+				if waited >= PWM_EXPORT_TIMEOUT {
+					return Err(format!("PWM channel {} of chip {} didn't appear after exporting it", channel, chip).into());
+				}
+				thread::sleep(Duration::from_millis(10));
+				waited += Duration::from_millis(10);
+			}
+		}
+		let ret = SysfsPwm {
+			path,
+			period_ns: (1e9 / frequency_hz).round() as u64,
+		};
+		// The duty cycle can't be longer than the period, so clear it before changing the period
+		ret.write("duty_cycle", 0)?;
+		ret.write("period", ret.period_ns)?;
+		ret.write("enable", 1)?;
+		Ok(ret)
+	}
+
+	fn write(&self, file: &str, value: u64) -> Result<(), HardwareError> {
+		Ok(fs::write(self.path.join(file), value.to_string())?)
+	}
+}
+
+impl PwmOutput for SysfsPwm {
+	fn set_duty_cycle(&mut self, duty_cycle: f64) -> Result<(), HardwareError> {
+		let duty_ns = (duty_cycle.clamp(0.0, 1.0) * (self.period_ns as f64)).round() as u64;
+		self.write("duty_cycle", duty_ns)
+	}
+}
+
+
+
+/// Input on the GPIO character device, counting its rising edges
+struct GpioPulseCounter {
+	handle: LineEventHandle,
+}
+
+impl PulseCounter for GpioPulseCounter {
+	fn count_pulses(&mut self, duration: Duration) -> Result<u64, HardwareError> {
+		let mut pollfds = [PollFd::new(self.handle.as_raw_fd(), PollEventFlags::POLLIN | PollEventFlags::POLLPRI)];
+		let deadline = Instant::now() + duration;
+		let mut pulses = 0;
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if remaining == Duration::from_secs(0) {
+				break;
+			}
+			if poll(&mut pollfds, remaining.as_millis() as i32)? == 0 {
+				continue;
+			}
+			if matches!(pollfds[0].revents(), Some(revts) if revts.contains(PollEventFlags::POLLIN)) {
+				self.handle.get_event()?;
+				pulses += 1;
+			}
+		}
+		Ok(pulses)
+	}
+}
+
+
+
+/// One quadrature encoder, counting every edge of its A and B channels
+struct GpioEncoder {
+	axis: Axis,
+	a_handle: LineEventHandle,
+	b_handle: LineEventHandle,
+	/// Last known levels of the channels, A in bit 1 and B in bit 0
+	state: usize,
+	count: i64,
+}
+
+impl GpioEncoder {
+	fn handle_edge(&mut self, channel_bit: usize, rising: bool) {
+		let new_state = if rising { self.state | channel_bit } else { self.state & !channel_bit };
+		if new_state == self.state ^ 0b11 {
+			println!("Encoder for axis {} missed an edge", self.axis);
+		}
+		self.count += QUADRATURE_TRANSITIONS[self.state][new_state];
+		self.state = new_state;
+	}
+}
+
+/**
+ * Quadrature encoders decoded from edge events on the GPIO character device, like the endstops.
+ * Edges can be missed if they come faster than we can handle the events, so this is for scales on slow-moving axes rather than motor encoders.
+ */
+struct GpioEncoderInputs {
+	encoders: Vec<GpioEncoder>,
+	pollfds: Vec<PollFd>,
+	/// Encoder and channel (as its bit in the state) of each entry in pollfds
+	pollfd_channels: Vec<(usize, usize)>,
+}
+
+impl GpioEncoderInputs {
+	pub fn new(chip_name: &str, encoder_configs: &HashMap<Axis, EncoderConfig>) -> Result<Self, HardwareError> {
+		let mut chip = Chip::new(chip_name)?;
+		let mut encoders = Vec::new();
+		let mut pollfds = Vec::new();
+		let mut pollfd_channels = Vec::new();
+		for (axis, config) in encoder_configs.iter() {
+			let a_handle = chip.get_line(config.a_pin)?.events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "encoder")?;
+			let b_handle = chip.get_line(config.b_pin)?.events(LineRequestFlags::INPUT, EventRequestFlags::BOTH_EDGES, "encoder")?;
+			let state = ((a_handle.get_value()? as usize) << 1) | (b_handle.get_value()? as usize);
+			for (handle, channel_bit) in [(&a_handle, 0b10), (&b_handle, 0b01)].iter() {
+				pollfds.push(PollFd::new(handle.as_raw_fd(), PollEventFlags::POLLIN | PollEventFlags::POLLPRI));
+				pollfd_channels.push((encoders.len(), *channel_bit));
+			}
+			encoders.push(GpioEncoder {
+				axis: *axis,
+				a_handle,
+				b_handle,
+				state,
+				count: 0,
+			});
+		}
+		Ok(GpioEncoderInputs {
+			encoders,
+			pollfds,
+			pollfd_channels,
+		})
+	}
+}
+
+impl EncoderInputs for GpioEncoderInputs {
+	fn read_counts(&mut self, duration: Duration) -> Result<HashMap<Axis, i64>, HardwareError> {
+		let deadline = Instant::now() + duration;
+		loop {
+			let remaining = deadline.saturating_duration_since(Instant::now());
+			if remaining == Duration::from_secs(0) {
+				break;
+			}
+			if poll(&mut self.pollfds, remaining.as_millis() as i32)? == 0 {
+				continue;
+			}
+			// Both channels of an encoder may have changed since the last poll, so handle the edges in the order they happened
+			let mut edges = Vec::new();
+			for i in 0..self.pollfds.len() {
+				if let Some(revts) = self.pollfds[i].revents() {
+					if revts.contains(PollEventFlags::POLLIN) {
+						let (encoder_index, channel_bit) = self.pollfd_channels[i];
+						let encoder = &self.encoders[encoder_index];
+						let handle = if channel_bit == 0b10 { &encoder.a_handle } else { &encoder.b_handle };
+						let event = handle.get_event()?;
+						edges.push((event.timestamp(), encoder_index, channel_bit, event.event_type() == EventType::RisingEdge));
+					}
+				}
+			}
+			edges.sort_by_key(|edge| edge.0);
+			for (_, encoder_index, channel_bit, rising) in edges {
+				self.encoders[encoder_index].handle_edge(channel_bit, rising);
+			}
+		}
+		Ok(self.encoders.iter().map(|encoder| (encoder.axis, encoder.count)).collect())
+	}
+}
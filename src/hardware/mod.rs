@@ -0,0 +1,126 @@
+mod gpio_backend;
+mod simulated_backend;
+pub(crate) mod simulated_tmc;
+mod simulated_vfd;
+
+pub use self::gpio_backend::GpioBackend;
+pub use self::simulated_backend::SimulatedBackend;
+
+use crate::common::Axis;
+use crate::common::AxisMotor;
+use crate::common::EndstopIdentifier;
+use crate::config::EncoderConfig;
+use crate::config::HardwareConfig;
+use crate::config::ModbusVfdConfig;
+use crate::config::MotorConfig;
+use crate::config::RustGrindConfig;
+use crate::config::SerialParity;
+use crate::config::SpindlePwmConfig;
+use crate::config::SpindleTachometerConfig;
+
+use std::collections::HashMap;
+use std::error;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use serial_core::BaudRate;
+use serial_core::CharSize;
+use serial_core::FlowControl;
+use serial_core::Parity;
+use serial_core::PortSettings;
+use serial_core::SerialPort;
+use serial_core::StopBits;
+use serial_unix::TTYPort;
+
+
+
+pub type HardwareError = Box<dyn error::Error + Send + Sync>;
+
+/// A single digital output line, such as a step, direction, or enable pin.
+pub trait DigitalOutput: Send {
+	fn set_high(&mut self) -> Result<(), HardwareError>;
+	fn set_low(&mut self) -> Result<(), HardwareError>;
+}
+
+/// A PWM output, running at a fixed frequency.
+pub trait PwmOutput: Send {
+	/// Set the fraction of the time the output is high, from 0 to 1
+	fn set_duty_cycle(&mut self, duty_cycle: f64) -> Result<(), HardwareError>;
+}
+
+/// An input whose pulses are counted, such as a tachometer.
+pub trait PulseCounter: Send {
+	/// Wait for the given time, and return how many pulses (rising edges) came in during it
+	fn count_pulses(&mut self, duration: Duration) -> Result<u64, HardwareError>;
+}
+
+/// The set of endstop inputs watched by the endstop checker.
+pub trait EndstopInputs: Send {
+	/// Current value of every endstop, e.g. to find out which ones were already hit at startup.
+	fn read_all(&mut self) -> Result<Vec<(EndstopIdentifier, bool)>, HardwareError>;
+	/// Block until at least one endstop changes state, and return the new values of the ones that changed.
+	fn wait_for_changes(&mut self) -> Result<Vec<(EndstopIdentifier, bool)>, HardwareError>;
+}
+
+/// The set of encoders watched by the encoder checker.
+pub trait EncoderInputs: Send {
+	/// Keep count of the encoders' edges for the given time, then return the count of every encoder.
+	fn read_counts(&mut self, duration: Duration) -> Result<HashMap<Axis, i64>, HardwareError>;
+}
+
+pub struct MotorPins {
+	pub enable: Box<dyn DigitalOutput>,
+	pub step: Box<dyn DigitalOutput>,
+	pub direction: Box<dyn DigitalOutput>,
+	/// Microstep mode pins of the driver, M0 first. Empty if the microstepping is set some other way.
+	pub microstep: Vec<Box<dyn DigitalOutput>>,
+}
+
+
+/**
+ * Source of all the I/O used to control the machine.
+ * Which implementation is used is selected by `RustGrindConfig::hardware`, so everything above this layer runs the same whether it's talking to a real Raspberry Pi or a simulated machine.
+ */
+pub trait HardwareBackend: Send + Sync {
+	/// Pins of one of an axis's motors
+	fn make_motor_pins(&self, axis: Axis, motor: AxisMotor, config: &MotorConfig) -> Result<MotorPins, HardwareError>;
+	fn make_spindle_pin(&self, line: u32) -> Result<Box<dyn DigitalOutput>, HardwareError>;
+	fn make_spindle_pwm(&self, config: &SpindlePwmConfig) -> Result<Box<dyn PwmOutput>, HardwareError>;
+	fn make_endstop_inputs(&self, endstops: &HashMap<EndstopIdentifier, u32>) -> Result<Box<dyn EndstopInputs>, HardwareError>;
+	fn make_encoder_inputs(&self, encoders: &HashMap<Axis, EncoderConfig>) -> Result<Box<dyn EncoderInputs>, HardwareError>;
+	/// Serial link to the VFD that runs the spindle
+	fn make_vfd_port(&self, config: &ModbusVfdConfig) -> Result<TTYPort, HardwareError>;
+	fn make_spindle_tachometer(&self, config: &SpindleTachometerConfig) -> Result<Box<dyn PulseCounter>, HardwareError>;
+	/// Single-wire UART shared by the stepper drivers of the given motors, by their addresses on it
+	fn make_driver_uart_port(&self, device: &str, baud_rate: u32, drivers: &HashMap<u8, (Axis, AxisMotor)>) -> Result<TTYPort, HardwareError>;
+}
+
+
+/// Open a serial device in raw mode, 8 data bits and 1 stop bit with no flow control, as Modbus RTU and the TMC2209 UART use
+pub(crate) fn open_serial_port(device: &Path, baud_rate: u32, parity: SerialParity) -> Result<TTYPort, HardwareError> {
+	let mut port = TTYPort::open(device)?;
+	port.configure(&PortSettings {
+		baud_rate: BaudRate::from_speed(baud_rate as usize),
+		char_size: CharSize::Bits8,
+		parity: match parity {
+			SerialParity::None => Parity::ParityNone,
+			SerialParity::Even => Parity::ParityEven,
+			SerialParity::Odd => Parity::ParityOdd,
+		},
+		stop_bits: StopBits::Stop1,
+		flow_control: FlowControl::FlowNone,
+	})?;
+	Ok(port)
+}
+
+
+pub fn create_backend(config: &RustGrindConfig) -> Arc<dyn HardwareBackend> {
+	match &config.hardware {
+		HardwareConfig::Gpio => Arc::new(GpioBackend::new(config.gpio_chip_name.clone())),
+		HardwareConfig::Simulated(sim_config) => {
+			println!("Using simulated hardware");
+			Arc::new(SimulatedBackend::new(config, sim_config))
+		},
+	}
+}
@@ -0,0 +1,550 @@
+use super::DigitalOutput;
+use super::EncoderInputs;
+use super::EndstopInputs;
+use super::HardwareBackend;
+use super::HardwareError;
+use super::MotorPins;
+use super::PulseCounter;
+use super::PwmOutput;
+use super::open_serial_port;
+use super::simulated_tmc;
+use super::simulated_tmc::SimulatedDriver;
+use super::simulated_vfd;
+
+use crate::common::Axis;
+use crate::common::AxisEnd;
+use crate::common::AxisMotor;
+use crate::common::EndstopIdentifier;
+use crate::config::EncoderConfig;
+use crate::config::ModbusVfdConfig;
+use crate::config::MotorConfig;
+use crate::config::RustGrindConfig;
+use crate::config::SerialParity;
+use crate::config::SimulationConfig;
+use crate::config::SpindlePwmConfig;
+use crate::config::SpindleTachometerConfig;
+
+use std::collections::HashMap;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use serial_unix::TTYPort;
+
+
+
+/// How often the simulated endstops look at the motor positions
+const ENDSTOP_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+
+/// State of a virtual stepper, shared between its step and direction pins and the simulated endstops.
+struct SimulatedMotor {
+	forward: AtomicBool,
+	step_count: AtomicI64,
+	/// Where the table driven by the motor is, in the motor's steps. It lags behind the motor by up to the backlash.
+	table_position: AtomicI64,
+	backlash_steps: i64,
+	/// Every this many steps, one is missed. Zero to never miss any.
+	lost_step_interval: u64,
+	/// Steps the motor has been given, including missed ones
+	steps_given: AtomicU64,
+}
+
+impl SimulatedMotor {
+	pub fn new(backlash_steps: i64, lost_step_interval: u64) -> Self {
+		SimulatedMotor {
+			forward: AtomicBool::new(true),
+			step_count: AtomicI64::new(0),
+			table_position: AtomicI64::new(0),
+			backlash_steps,
+			lost_step_interval,
+			steps_given: AtomicU64::new(0),
+		}
+	}
+
+	fn step(&self, step: i64) {
+		let steps_given = self.steps_given.fetch_add(1, Ordering::SeqCst) + 1;
+		if self.lost_step_interval != 0 && steps_given % self.lost_step_interval == 0 {
+			return;
+		}
+		let step_count = self.step_count.fetch_add(step, Ordering::SeqCst) + step;
+		// The table only moves once the slack in the leadscrew has been taken up
+		let table_position = self.table_position.load(Ordering::SeqCst)
+			.max(step_count - self.backlash_steps)
+			.min(step_count);
+		self.table_position.store(table_position, Ordering::SeqCst);
+	}
+
+	pub fn get_table_position(&self) -> i64 {
+		self.table_position.load(Ordering::SeqCst)
+	}
+}
+
+/// Step pin of a virtual stepper. Each rising edge moves the motor one step in the direction set by its direction pin.
+struct SimulatedStepPin {
+	motor: Arc<SimulatedMotor>,
+	high: bool,
+}
+
+impl DigitalOutput for SimulatedStepPin {
+	fn set_high(&mut self) -> Result<(), HardwareError> {
+		if !self.high {
+			let step = if self.motor.forward.load(Ordering::SeqCst) { 1 } else { -1 };
+			self.motor.step(step);
+		}
+		self.high = true;
+		Ok(())
+	}
+
+	fn set_low(&mut self) -> Result<(), HardwareError> {
+		self.high = false;
+		Ok(())
+	}
+}
+
+struct SimulatedDirectionPin {
+	motor: Arc<SimulatedMotor>,
+}
+
+impl DigitalOutput for SimulatedDirectionPin {
+	fn set_high(&mut self) -> Result<(), HardwareError> {
+		self.motor.forward.store(true, Ordering::SeqCst);
+		Ok(())
+	}
+
+	fn set_low(&mut self) -> Result<(), HardwareError> {
+		self.motor.forward.store(false, Ordering::SeqCst);
+		Ok(())
+	}
+}
+
+/// Output that doesn't drive anything, just logs when it changes.
+struct SimulatedOutput {
+	name: String,
+	high: bool,
+}
+
+impl SimulatedOutput {
+	pub fn new(name: String) -> Self {
+		SimulatedOutput {
+			name,
+			high: false,
+		}
+	}
+}
+
+impl DigitalOutput for SimulatedOutput {
+	fn set_high(&mut self) -> Result<(), HardwareError> {
+		if !self.high {
+			println!("Simulated {} set high", self.name);
+		}
+		self.high = true;
+		Ok(())
+	}
+
+	fn set_low(&mut self) -> Result<(), HardwareError> {
+		if self.high {
+			println!("Simulated {} set low", self.name);
+		}
+		self.high = false;
+		Ok(())
+	}
+}
+
+
+/// State of the simulated spindle motor, shared between its enable pin and PWM output, and the simulated tachometer
+struct SimulatedSpindle {
+	enabled: AtomicBool,
+	/// Duty cycle of the PWM output, as the bits of an f64. Stays at 1 if the spindle has no PWM output, so it runs at full speed.
+	duty_cycle: AtomicU64,
+}
+
+impl SimulatedSpindle {
+	pub fn new() -> Self {
+		SimulatedSpindle {
+			enabled: AtomicBool::new(false),
+			duty_cycle: AtomicU64::new(1.0f64.to_bits()),
+		}
+	}
+
+	/// Fraction of full speed the spindle is being driven at
+	fn get_drive(&self) -> f64 {
+		if self.enabled.load(Ordering::SeqCst) {
+			f64::from_bits(self.duty_cycle.load(Ordering::SeqCst))
+		} else {
+			0.0
+		}
+	}
+}
+
+/// Enable pin of the simulated spindle
+struct SimulatedSpindlePin {
+	output: SimulatedOutput,
+	spindle: Arc<SimulatedSpindle>,
+}
+
+impl DigitalOutput for SimulatedSpindlePin {
+	fn set_high(&mut self) -> Result<(), HardwareError> {
+		self.spindle.enabled.store(true, Ordering::SeqCst);
+		self.output.set_high()
+	}
+
+	fn set_low(&mut self) -> Result<(), HardwareError> {
+		self.spindle.enabled.store(false, Ordering::SeqCst);
+		self.output.set_low()
+	}
+}
+
+/// PWM output of the simulated spindle. It logs its duty cycle to the nearest percent, so ramps don't flood the log.
+struct SimulatedPwm {
+	name: String,
+	percent: Option<i64>,
+	spindle: Arc<SimulatedSpindle>,
+}
+
+impl PwmOutput for SimulatedPwm {
+	fn set_duty_cycle(&mut self, duty_cycle: f64) -> Result<(), HardwareError> {
+		self.spindle.duty_cycle.store(duty_cycle.to_bits(), Ordering::SeqCst);
+		let percent = (duty_cycle * 100.0).round() as i64;
+		if self.percent != Some(percent) {
+			println!("Simulated {} duty cycle set to {}%", self.name, percent);
+			self.percent = Some(percent);
+		}
+		Ok(())
+	}
+}
+
+/// Tachometer on the simulated spindle, whose speed follows how hard it's driven at a limited acceleration
+struct SimulatedTachometer {
+	spindle: Arc<SimulatedSpindle>,
+	pulses_per_rev: f64,
+	full_speed_rpm: f64,
+	acceleration_rpm_per_sec: f64,
+	rpm: f64,
+	/// Part of a pulse left over from the last count
+	partial_pulse: f64,
+	last_update: Instant,
+}
+
+impl PulseCounter for SimulatedTachometer {
+	fn count_pulses(&mut self, duration: Duration) -> Result<u64, HardwareError> {
+		thread::sleep(duration);
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_update).as_secs_f64();
+		self.last_update = now;
+		let target = self.spindle.get_drive() * self.full_speed_rpm;
+		let change = (target - self.rpm).max(-self.acceleration_rpm_per_sec * elapsed).min(self.acceleration_rpm_per_sec * elapsed);
+		let average_rpm = self.rpm + change / 2.0;
+		self.rpm += change;
+		self.partial_pulse += average_rpm / 60.0 * elapsed * self.pulses_per_rev;
+		let pulses = self.partial_pulse.floor();
+		self.partial_pulse -= pulses;
+		Ok(pulses as u64)
+	}
+}
+
+
+
+/**
+ * Simulated machine, so the controller can run without a Raspberry Pi.
+ * Motors are virtual steppers that count the steps they're given, and endstops trip when those step counts reach configured positions.
+ * Encoders read back where the table actually is, and the VFD is a simulated one on a pseudo-terminal.
+ * The spindle has a tachometer that follows its enable pin and PWM output (but not the VFD, which reports its own speed).
+ * TMC2209 drivers are simulated on a pseudo-terminal too, with their motors stalling at configured positions.
+ */
+pub struct SimulatedBackend {
+	motor_configs: HashMap<Axis, MotorConfig>,
+	motors: HashMap<(Axis, AxisMotor), Arc<SimulatedMotor>>,
+	endstop_positions: HashMap<EndstopIdentifier, f64>,
+	vfd_ramp_hz_per_sec: f64,
+	vfd_fault_after_secs: Option<f64>,
+	spindle: Arc<SimulatedSpindle>,
+	spindle_full_speed_rpm: f64,
+	spindle_acceleration_rpm_per_sec: f64,
+	stall_positions: HashMap<EndstopIdentifier, f64>,
+	driver_fault_after_secs: Option<f64>,
+}
+
+impl SimulatedBackend {
+	pub fn new(config: &RustGrindConfig, sim_config: &SimulationConfig) -> Self {
+		let mut motors = HashMap::new();
+		for (axis, motor_config) in config.motor_configs.iter() {
+			let backlash = sim_config.backlash.get(axis).copied().unwrap_or(0.0);
+			let backlash_steps = motor_config.distance_to_steps(backlash).unwrap_or(0).max(0);
+			let lost_step_interval = sim_config.lost_step_interval.get(axis).copied().unwrap_or(0);
+			for motor in motor_config.motors() {
+				motors.insert((*axis, motor), Arc::new(SimulatedMotor::new(backlash_steps, lost_step_interval)));
+			}
+		}
+		SimulatedBackend {
+			motor_configs: config.motor_configs.clone(),
+			motors,
+			endstop_positions: sim_config.endstop_positions.clone(),
+			vfd_ramp_hz_per_sec: sim_config.vfd_ramp_hz_per_sec,
+			vfd_fault_after_secs: sim_config.vfd_fault_after_secs,
+			spindle: Arc::new(SimulatedSpindle::new()),
+			spindle_full_speed_rpm: sim_config.spindle_full_speed_rpm,
+			spindle_acceleration_rpm_per_sec: sim_config.spindle_acceleration_rpm_per_sec,
+			stall_positions: sim_config.stall_positions.clone(),
+			driver_fault_after_secs: sim_config.driver_fault_after_secs,
+		}
+	}
+
+	fn get_motor(&self, axis: Axis, motor: AxisMotor) -> Result<Arc<SimulatedMotor>, HardwareError> {
+		match self.motors.get(&(axis, motor)) {
+			Some(simulated_motor) => Ok(simulated_motor.clone()),
+			None => Err(format!("No simulated {} motor for axis {}", motor, axis).into()),
+		}
+	}
+}
+
+impl HardwareBackend for SimulatedBackend {
+	fn make_motor_pins(&self, axis: Axis, motor: AxisMotor, config: &MotorConfig) -> Result<MotorPins, HardwareError> {
+		let (name, microstep_pin_count) = match (motor, &config.slave) {
+			(AxisMotor::Slave, Some(slave)) => (format!("{} slave", axis), slave.microstep_pin_numbers.len()),
+			_ => (format!("{}", axis), config.microstep_pin_numbers.len()),
+		};
+		let microstep = (0..microstep_pin_count)
+			.map(|index| Box::new(SimulatedOutput::new(format!("{} M{} pin", name, index))) as Box<dyn DigitalOutput>)
+			.collect();
+		let motor = self.get_motor(axis, motor)?;
+		Ok(MotorPins {
+			enable: Box::new(SimulatedOutput::new(format!("{} enable pin", name))),
+			step: Box::new(SimulatedStepPin{motor: motor.clone(), high: false}),
+			direction: Box::new(SimulatedDirectionPin{motor}),
+			microstep,
+		})
+	}
+
+	fn make_spindle_pin(&self, _line: u32) -> Result<Box<dyn DigitalOutput>, HardwareError> {
+		Ok(Box::new(SimulatedSpindlePin{output: SimulatedOutput::new("spindle".to_string()), spindle: self.spindle.clone()}))
+	}
+
+	fn make_spindle_pwm(&self, _config: &SpindlePwmConfig) -> Result<Box<dyn PwmOutput>, HardwareError> {
+		Ok(Box::new(SimulatedPwm{name: "spindle PWM".to_string(), percent: None, spindle: self.spindle.clone()}))
+	}
+
+	fn make_endstop_inputs(&self, endstops: &HashMap<EndstopIdentifier, u32>) -> Result<Box<dyn EndstopInputs>, HardwareError> {
+		let mut watched = Vec::new();
+		for endstop in endstops.keys() {
+			// Endstops without a configured position never trip
+			if let Some(trip_position) = self.endstop_positions.get(endstop) {
+				watched.push(SimulatedEndstop {
+					endstop: *endstop,
+					motor: self.get_motor(endstop.axis, endstop.motor)?,
+					motor_config: self.motor_configs.get(&endstop.axis).cloned().unwrap(),
+					trip_position: *trip_position,
+					value: false,
+				});
+			}
+		}
+		Ok(Box::new(SimulatedEndstopInputs{watched}))
+	}
+
+	fn make_encoder_inputs(&self, encoders: &HashMap<Axis, EncoderConfig>) -> Result<Box<dyn EncoderInputs>, HardwareError> {
+		let mut watched = Vec::new();
+		for (axis, encoder_config) in encoders.iter() {
+			watched.push(SimulatedEncoder {
+				axis: *axis,
+				motor: self.get_motor(*axis, AxisMotor::Main)?,
+				motor_config: self.motor_configs.get(axis).cloned().unwrap(),
+				encoder_config: *encoder_config,
+			});
+		}
+		Ok(Box::new(SimulatedEncoderInputs{watched}))
+	}
+
+	fn make_vfd_port(&self, config: &ModbusVfdConfig) -> Result<TTYPort, HardwareError> {
+		let device = simulated_vfd::start(config, self.vfd_ramp_hz_per_sec, self.vfd_fault_after_secs)?;
+		open_serial_port(&device, config.baud_rate, config.parity)
+	}
+
+	fn make_spindle_tachometer(&self, config: &SpindleTachometerConfig) -> Result<Box<dyn PulseCounter>, HardwareError> {
+		Ok(Box::new(SimulatedTachometer {
+			spindle: self.spindle.clone(),
+			pulses_per_rev: config.pulses_per_rev,
+			full_speed_rpm: self.spindle_full_speed_rpm,
+			acceleration_rpm_per_sec: self.spindle_acceleration_rpm_per_sec,
+			rpm: 0.0,
+			partial_pulse: 0.0,
+			last_update: Instant::now(),
+		}))
+	}
+
+	fn make_driver_uart_port(&self, _device: &str, baud_rate: u32, drivers: &HashMap<u8, (Axis, AxisMotor)>) -> Result<TTYPort, HardwareError> {
+		let mut simulated_drivers = HashMap::new();
+		for (address, (axis, motor)) in drivers.iter() {
+			let simulated_motor = self.get_motor(*axis, *motor)?;
+			let motor_config = self.motor_configs.get(axis).cloned().unwrap();
+			let steps_per_unit = motor_config.steps_per_unit();
+			let motor = *motor;
+			let stall_positions = self.stall_positions.iter()
+				.filter(|(endstop, _)| endstop.axis == *axis && endstop.motor == motor)
+				.map(|(endstop, position)| (endstop.position, *position))
+				.collect();
+			simulated_drivers.insert(*address, SimulatedDriver {
+				get_position: Box::new(move || (motor_config.apply_reversal(motor, simulated_motor.get_table_position()) as f64) / motor_config.steps_per_unit()),
+				steps_per_unit,
+				stall_positions,
+			});
+		}
+		let device = simulated_tmc::start(simulated_drivers, self.driver_fault_after_secs)?;
+		open_serial_port(&device, baud_rate, SerialParity::None)
+	}
+}
+
+
+
+struct SimulatedEndstop {
+	endstop: EndstopIdentifier,
+	motor: Arc<SimulatedMotor>,
+	motor_config: MotorConfig,
+	trip_position: f64,
+	value: bool,
+}
+
+impl SimulatedEndstop {
+	fn is_tripped(&self) -> bool {
+		// Endstops are fixed to the machine, so they trip based on the logical position of the table rather than which way the motor happens to turn.
+		// The simulated leadscrew is perfect, so there's no pitch error to correct for.
+		let position = (self.motor_config.apply_reversal(self.endstop.motor, self.motor.get_table_position()) as f64) / self.motor_config.steps_per_unit();
+		match self.endstop.position {
+			AxisEnd::Min => position <= self.trip_position,
+			AxisEnd::Max => position >= self.trip_position,
+		}
+	}
+}
+
+struct SimulatedEndstopInputs {
+	watched: Vec<SimulatedEndstop>,
+}
+
+impl EndstopInputs for SimulatedEndstopInputs {
+	fn read_all(&mut self) -> Result<Vec<(EndstopIdentifier, bool)>, HardwareError> {
+		let mut values = Vec::new();
+		for simulated_endstop in self.watched.iter_mut() {
+			simulated_endstop.value = simulated_endstop.is_tripped();
+			values.push((simulated_endstop.endstop, simulated_endstop.value));
+		}
+		Ok(values)
+	}
+
+	fn wait_for_changes(&mut self) -> Result<Vec<(EndstopIdentifier, bool)>, HardwareError> {
+		loop {
+			let mut changes = Vec::new();
+			for simulated_endstop in self.watched.iter_mut() {
+				let value = simulated_endstop.is_tripped();
+				if value != simulated_endstop.value {
+					simulated_endstop.value = value;
+					changes.push((simulated_endstop.endstop, value));
+				}
+			}
+			if !changes.is_empty() {
+				return Ok(changes);
+			}
+			thread::sleep(ENDSTOP_POLL_INTERVAL);
+		}
+	}
+}
+
+
+
+/// Encoder on the simulated table, so it sees the backlash and any lost steps
+struct SimulatedEncoder {
+	axis: Axis,
+	motor: Arc<SimulatedMotor>,
+	motor_config: MotorConfig,
+	encoder_config: EncoderConfig,
+}
+
+impl SimulatedEncoder {
+	fn get_count(&self) -> i64 {
+		let position = (self.motor_config.apply_reversal(AxisMotor::Main, self.motor.get_table_position()) as f64) / self.motor_config.steps_per_unit();
+		let count = (position * self.encoder_config.counts_per_unit).round() as i64;
+		if self.encoder_config.reversed {
+			-count
+		} else {
+			count
+		}
+	}
+}
+
+struct SimulatedEncoderInputs {
+	watched: Vec<SimulatedEncoder>,
+}
+
+impl EncoderInputs for SimulatedEncoderInputs {
+	fn read_counts(&mut self, duration: Duration) -> Result<HashMap<Axis, i64>, HardwareError> {
+		thread::sleep(duration);
+		Ok(self.watched.iter().map(|encoder| (encoder.axis, encoder.get_count())).collect())
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::common::NANOMETRES_PER_INCH;
+	use crate::config::ConfigManager;
+
+	fn step(pins: &mut MotorPins, count: u32) {
+		for _ in 0..count {
+			pins.step.set_high().unwrap();
+			pins.step.set_low().unwrap();
+		}
+	}
+
+	fn is_tripped(endstops: &mut Box<dyn EndstopInputs>) -> bool {
+		endstops.read_all().unwrap()[0].1
+	}
+
+	#[test]
+	fn motor_counts_its_steps_and_the_table_lags_by_the_backlash() {
+		let config = ConfigManager::new().get_config().clone();
+		let max = EndstopIdentifier::new(Axis::X, AxisEnd::Max);
+		// The default X axis has 200 steps to the inch. SimulationConfig::new is in config units, so set the positions up in internal ones.
+		let mut sim_config = SimulationConfig::new();
+		sim_config.endstop_positions = vec![(max, 0.5 * NANOMETRES_PER_INCH)].into_iter().collect();
+		sim_config.backlash = vec![(Axis::X, 0.01 * NANOMETRES_PER_INCH)].into_iter().collect();
+		let backend = SimulatedBackend::new(&config, &sim_config);
+		let mut pins = backend.make_motor_pins(Axis::X, AxisMotor::Main, &config.motor_configs[&Axis::X]).unwrap();
+		let mut endstops = backend.make_endstop_inputs(&vec![(max, 0)].into_iter().collect()).unwrap();
+		let motor = backend.get_motor(Axis::X, AxisMotor::Main).unwrap();
+
+		// Setting off, the 2 steps of slack have to be taken up first, so the table is 2 steps short of the endstop
+		pins.direction.set_high().unwrap();
+		step(&mut pins, 99);
+		assert_eq!(motor.step_count.load(Ordering::SeqCst), 99);
+		assert_eq!(motor.get_table_position(), 97);
+		assert!(!is_tripped(&mut endstops));
+		step(&mut pins, 3);
+		assert_eq!(motor.get_table_position(), 100);
+		assert!(is_tripped(&mut endstops));
+
+		// Turning back, the table stays put until the slack has been taken up the other way
+		pins.direction.set_low().unwrap();
+		step(&mut pins, 2);
+		assert_eq!(motor.step_count.load(Ordering::SeqCst), 100);
+		assert_eq!(motor.get_table_position(), 100);
+		assert!(is_tripped(&mut endstops));
+		step(&mut pins, 1);
+		assert_eq!(motor.get_table_position(), 99);
+		assert!(!is_tripped(&mut endstops));
+	}
+
+	#[test]
+	fn motor_misses_one_of_every_lost_step_interval() {
+		let motor = SimulatedMotor::new(0, 10);
+		for _ in 0..25 {
+			motor.step(1);
+		}
+		assert_eq!(motor.step_count.load(Ordering::SeqCst), 23);
+		assert_eq!(motor.get_table_position(), 23);
+	}
+}
@@ -0,0 +1,261 @@
+use super::HardwareError;
+
+use crate::common::AxisEnd;
+use crate::tmc_uart;
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+use nix::pty;
+use nix::unistd;
+
+
+
+/// Value of IOIN's version field (the top byte) for a TMC2209
+const VERSION: u32 = 0x21 << 24;
+/// Reset values of the registers that have something other than zero in them
+const GCONF_RESET_VALUE: u32 = tmc_uart::GCONF_I_SCALE_ANALOG | tmc_uart::GCONF_PDN_DISABLE;
+const CHOPCONF_RESET_VALUE: u32 = 0x10000053;
+/// StallGuard reading of a motor that's moving freely
+const FREE_RUNNING_SG_RESULT: u32 = 200;
+
+
+
+/// Motor driven by a simulated TMC2209, and what it runs into
+pub struct SimulatedDriver {
+	/// Logical position of the table the motor drives, in internal units
+	pub get_position: Box<dyn Fn() -> f64 + Send>,
+	/// Steps per unit of the motor, at the microstepping it's configured for
+	pub steps_per_unit: f64,
+	/// Ends of the axis where the table runs into something that stalls the motor, and where
+	pub stall_positions: Vec<(AxisEnd, f64)>,
+}
+
+/// State of one of the simulated drivers on the bus
+struct DriverState {
+	driver: SimulatedDriver,
+	registers: HashMap<u8, u32>,
+	interface_count: u8,
+	last_position: f64,
+	last_update: Instant,
+	/// Speed in internal units/second, as of the last time DRV_STATUS was read
+	velocity: f64,
+}
+
+impl DriverState {
+	fn new(driver: SimulatedDriver) -> Self {
+		let mut registers = HashMap::new();
+		registers.insert(tmc_uart::GCONF, GCONF_RESET_VALUE);
+		registers.insert(tmc_uart::GSTAT, tmc_uart::GSTAT_RESET);
+		registers.insert(tmc_uart::CHOPCONF, CHOPCONF_RESET_VALUE);
+		DriverState {
+			last_position: (driver.get_position)(),
+			driver,
+			registers,
+			interface_count: 0,
+			last_update: Instant::now(),
+			velocity: 0.0,
+		}
+	}
+
+	fn register(&self, register: u8) -> u32 {
+		self.registers.get(&register).copied().unwrap_or(0)
+	}
+
+	/// Work out how fast the motor has been going since the last update
+	fn update_motion(&mut self) {
+		let now = Instant::now();
+		let position = (self.driver.get_position)();
+		let elapsed = now.duration_since(self.last_update).as_secs_f64();
+		self.velocity = if elapsed > 0.0 { (position - self.last_position) / elapsed } else { 0.0 };
+		self.last_position = position;
+		self.last_update = now;
+	}
+
+	/// Whether the motor is being driven into something it can't move
+	fn is_stalled(&self) -> bool {
+		self.driver.stall_positions.iter().any(|(end, stall_position)| match end {
+			AxisEnd::Min => self.velocity < 0.0 && self.last_position <= *stall_position,
+			AxisEnd::Max => self.velocity > 0.0 && self.last_position >= *stall_position,
+		})
+	}
+
+	fn microsteps(&self) -> u32 {
+		256 >> ((self.register(tmc_uart::CHOPCONF) & tmc_uart::CHOPCONF_MRES_MASK) >> tmc_uart::CHOPCONF_MRES_SHIFT)
+	}
+
+	fn read_register(&mut self, register: u8, faulted: bool) -> u32 {
+		match register {
+			tmc_uart::GSTAT => self.register(register) | if faulted { tmc_uart::GSTAT_DRV_ERR } else { 0 },
+			tmc_uart::IFCNT => self.interface_count as u32,
+			tmc_uart::IOIN => VERSION,
+			tmc_uart::TSTEP => tmc_uart::tstep(self.velocity.abs() * self.driver.steps_per_unit, self.microsteps()),
+			tmc_uart::SG_RESULT => if self.is_stalled() || self.velocity == 0.0 { 0 } else { FREE_RUNNING_SG_RESULT },
+			tmc_uart::DRV_STATUS => {
+				self.update_motion();
+				let mut status = 0;
+				if self.velocity == 0.0 {
+					status |= tmc_uart::DRV_STATUS_STST;
+				}
+				if self.register(tmc_uart::GCONF) & tmc_uart::GCONF_EN_SPREADCYCLE == 0 {
+					status |= tmc_uart::DRV_STATUS_STEALTH;
+				}
+				if faulted {
+					status |= tmc_uart::DRV_STATUS_OT | tmc_uart::DRV_STATUS_OTPW;
+				}
+				status
+			},
+			_ => self.register(register),
+		}
+	}
+
+	fn write_register(&mut self, register: u8, value: u32) {
+		self.interface_count = self.interface_count.wrapping_add(1);
+		if register == tmc_uart::GSTAT {
+			// Flags are cleared by writing ones to them
+			let flags = self.register(register) & !value;
+			self.registers.insert(register, flags);
+		} else {
+			self.registers.insert(register, value);
+		}
+	}
+}
+
+/**
+ * TMC2209 drivers sharing a single-wire UART, on the master side of a pseudo-terminal, so the driver monitor can talk to them through the slave side just like a real serial device.
+ * Everything sent is echoed back, as it would be by the shared wire, before any reply.
+ */
+struct SimulatedTmcBus {
+	master: File,
+	/// Kept open so the master doesn't see the link as hung up before the driver monitor opens it
+	_slave: File,
+	drivers: HashMap<u8, DriverState>,
+	fault_after_secs: Option<f64>,
+	started: Instant,
+}
+
+impl SimulatedTmcBus {
+	fn is_faulted(&self) -> bool {
+		matches!(self.fault_after_secs, Some(fault_after) if self.started.elapsed().as_secs_f64() >= fault_after)
+	}
+
+	/// Reply to a whole datagram, if it's a read request for one of the drivers
+	fn handle_datagram(&mut self, datagram: &[u8]) -> Option<Vec<u8>> {
+		let faulted = self.is_faulted();
+		let driver = self.drivers.get_mut(&datagram[1])?;
+		let register = datagram[2] & !tmc_uart::WRITE_FLAG;
+		if datagram[2] & tmc_uart::WRITE_FLAG != 0 {
+			driver.write_register(register, u32::from_be_bytes([datagram[3], datagram[4], datagram[5], datagram[6]]));
+			return None;
+		}
+		let mut reply = vec![tmc_uart::SYNC, tmc_uart::MASTER_ADDRESS, register];
+		reply.extend_from_slice(&driver.read_register(register, faulted).to_be_bytes());
+		tmc_uart::append_crc(&mut reply);
+		Some(reply)
+	}
+
+	fn run(&mut self) -> Result<(), HardwareError> {
+		let mut received = Vec::new();
+		let mut buffer = [0; 64];
+		loop {
+			let count = self.master.read(&mut buffer)?;
+			self.master.write_all(&buffer[..count])?;
+			received.extend_from_slice(&buffer[..count]);
+			loop {
+				// Skip anything that can't be the start of a datagram
+				let start = received.iter().position(|byte| byte & 0x0F == tmc_uart::SYNC).unwrap_or(received.len());
+				received.drain(..start);
+				if received.len() < 3 {
+					break;
+				}
+				let length = if received[2] & tmc_uart::WRITE_FLAG != 0 { tmc_uart::DATAGRAM_LENGTH } else { tmc_uart::READ_REQUEST_LENGTH };
+				if received.len() < length {
+					break;
+				}
+				if !tmc_uart::crc_matches(&received[..length]) {
+					// Not really the start of a datagram, so look for the next one
+					received.remove(0);
+					continue;
+				}
+				let datagram: Vec<u8> = received.drain(..length).collect();
+				if let Some(reply) = self.handle_datagram(&datagram) {
+					self.master.write_all(&reply)?;
+				}
+			}
+		}
+	}
+}
+
+
+/// Start simulated drivers at the given addresses, and return the path of the device to talk to them on
+pub fn start(drivers: HashMap<u8, SimulatedDriver>, fault_after_secs: Option<f64>) -> Result<PathBuf, HardwareError> {
+	let pty = pty::openpty(None, None)?;
+	let device = unistd::ttyname(pty.slave)?;
+	let mut bus = SimulatedTmcBus {
+		master: unsafe { File::from_raw_fd(pty.master) },
+		_slave: unsafe { File::from_raw_fd(pty.slave) },
+		drivers: drivers.into_iter().map(|(address, driver)| (address, DriverState::new(driver))).collect(),
+		fault_after_secs,
+		started: Instant::now(),
+	};
+	println!("Simulated TMC2209 drivers at addresses {:?} on {}", bus.drivers.keys().collect::<Vec<_>>(), device.display());
+	let builder = thread::Builder::new().name("SimulatedTmc".to_string());
+	builder.spawn(move || {
+		if let Err(error) = bus.run() {
+			println!("Simulated TMC2209 drivers stopped, error is {:?}", error);
+		}
+	})?;
+	Ok(device)
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::SerialParity;
+	use crate::hardware::open_serial_port;
+	use crate::tmc_uart::TmcUartClient;
+
+	fn start_client(addresses: &[u8]) -> TmcUartClient {
+		let drivers = addresses.iter().map(|address| (*address, SimulatedDriver {
+			get_position: Box::new(|| 0.0),
+			steps_per_unit: 1.0,
+			stall_positions: Vec::new(),
+		})).collect();
+		let device = start(drivers, None).unwrap();
+		TmcUartClient::new(open_serial_port(&device, 115200, SerialParity::None).unwrap()).unwrap()
+	}
+
+	#[test]
+	fn reads_come_back_from_the_addressed_driver() {
+		let mut client = start_client(&[0, 2]);
+		for address in [0, 2].iter() {
+			assert_eq!(client.read_register(*address, tmc_uart::IOIN).unwrap() & 0xFF00_0000, VERSION);
+			assert_eq!(client.read_register(*address, tmc_uart::CHOPCONF).unwrap(), CHOPCONF_RESET_VALUE);
+		}
+		// Nothing answers at an address without a driver
+		assert!(client.read_register(1, tmc_uart::IOIN).is_err());
+		// Which doesn't stop the next read working
+		assert_eq!(client.read_register(0, tmc_uart::GCONF).unwrap(), GCONF_RESET_VALUE);
+	}
+
+	#[test]
+	fn writes_are_counted_and_read_back() {
+		let mut client = start_client(&[0, 1]);
+		client.write_register(1, tmc_uart::IHOLD_IRUN, 0x0008_1004).unwrap();
+		client.write_register(1, tmc_uart::GSTAT, tmc_uart::GSTAT_RESET).unwrap();
+		assert_eq!(client.read_register(1, tmc_uart::IHOLD_IRUN).unwrap(), 0x0008_1004);
+		assert_eq!(client.read_register(1, tmc_uart::GSTAT).unwrap(), 0);
+		assert_eq!(client.read_register(1, tmc_uart::IFCNT).unwrap(), 2);
+		// Only the addressed driver took them
+		assert_eq!(client.read_register(0, tmc_uart::IHOLD_IRUN).unwrap(), 0);
+		assert_eq!(client.read_register(0, tmc_uart::IFCNT).unwrap(), 0);
+	}
+}
@@ -0,0 +1,177 @@
+use super::HardwareError;
+
+use crate::config::ModbusVfdConfig;
+use crate::modbus;
+
+use std::fs::File;
+use std::io::Read;
+use std::io::Write;
+use std::os::unix::io::FromRawFd;
+use std::path::PathBuf;
+use std::thread;
+use std::time::Instant;
+
+use nix::pty;
+use nix::unistd;
+
+
+
+/// Fault code the simulated VFD trips with
+const SIMULATED_FAULT_CODE: u16 = 1;
+/// Length of the requests the simulated VFD understands (reading holding registers and writing a single register)
+const REQUEST_LENGTH: usize = 8;
+
+
+
+/**
+ * VFD answering Modbus RTU requests on the master side of a pseudo-terminal, so the spindle driver can talk to it through the slave side just like a real serial device.
+ * Its output frequency ramps towards the setpoint while it's running, and back to zero when it's stopped or has faulted.
+ */
+struct SimulatedVfd {
+	master: File,
+	/// Kept open so the master doesn't see the link as hung up before the driver opens it, or between its reconnections
+	_slave: File,
+	config: ModbusVfdConfig,
+	ramp_hz_per_sec: f64,
+	fault_after_secs: Option<f64>,
+	running: bool,
+	setpoint_counts: u16,
+	output_hz: f64,
+	fault_code: u16,
+	running_secs: f64,
+	last_update: Instant,
+}
+
+impl SimulatedVfd {
+	/// Move the output frequency along, and trip the fault if it's time to
+	fn update(&mut self) {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_update).as_secs_f64();
+		self.last_update = now;
+		if self.running {
+			self.running_secs += elapsed;
+			if matches!(self.fault_after_secs, Some(fault_after) if self.running_secs >= fault_after) {
+				println!("Simulated VFD tripped with fault {}", SIMULATED_FAULT_CODE);
+				self.fault_code = SIMULATED_FAULT_CODE;
+				self.running = false;
+			}
+		}
+		let target = if self.running { (self.setpoint_counts as f64) / self.config.frequency_scale } else { 0.0 };
+		let step = self.ramp_hz_per_sec * elapsed;
+		self.output_hz = if target > self.output_hz { (self.output_hz + step).min(target) } else { (self.output_hz - step).max(target) };
+	}
+
+	fn read_register(&self, register: u16) -> Option<u16> {
+		let registers = self.config.registers;
+		if register == registers.frequency_setpoint {
+			Some(self.setpoint_counts)
+		} else if register == registers.output_frequency {
+			Some((self.output_hz * self.config.frequency_scale).round() as u16)
+		} else if register == registers.fault_code {
+			Some(self.fault_code)
+		} else {
+			None
+		}
+	}
+
+	fn write_register(&mut self, register: u16, value: u16) -> bool {
+		let registers = self.config.registers;
+		if register == registers.control && value == registers.run_forward {
+			if self.fault_code == 0 {
+				self.running = true;
+			}
+		} else if register == registers.control && value == registers.stop {
+			// Stopping resets any fault, too
+			self.running = false;
+			self.fault_code = 0;
+			self.running_secs = 0.0;
+		} else if register == registers.frequency_setpoint {
+			self.setpoint_counts = value;
+		} else {
+			return false;
+		}
+		true
+	}
+
+	/// Response to a request, both without their address and CRC
+	fn handle_request(&mut self, request: &[u8]) -> Vec<u8> {
+		let function = request[0];
+		let first = u16::from_be_bytes([request[1], request[2]]);
+		let value = u16::from_be_bytes([request[3], request[4]]);
+		let exception = |code| vec![function | modbus::EXCEPTION_FLAG, code];
+		match function {
+			modbus::READ_HOLDING_REGISTERS => {
+				let mut response = vec![function, (2 * value) as u8];
+				for register in first..first.saturating_add(value) {
+					match self.read_register(register) {
+						Some(contents) => response.extend_from_slice(&contents.to_be_bytes()),
+						None => return exception(modbus::ILLEGAL_DATA_ADDRESS),
+					}
+				}
+				response
+			},
+			modbus::WRITE_SINGLE_REGISTER => {
+				if self.write_register(first, value) {
+					request.to_vec()
+				} else {
+					exception(modbus::ILLEGAL_DATA_ADDRESS)
+				}
+			},
+			_ => exception(modbus::ILLEGAL_FUNCTION),
+		}
+	}
+
+	fn run(&mut self) -> Result<(), HardwareError> {
+		let mut received = Vec::new();
+		let mut buffer = [0; 64];
+		loop {
+			let count = self.master.read(&mut buffer)?;
+			received.extend_from_slice(&buffer[..count]);
+			while received.len() >= REQUEST_LENGTH {
+				let frame: Vec<u8> = received.drain(..REQUEST_LENGTH).collect();
+				if !modbus::crc_matches(&frame) {
+					// Lost track of where the frames start, so throw away everything and wait for the next one
+					received.clear();
+					break;
+				}
+				if frame[0] != self.config.address {
+					continue;
+				}
+				self.update();
+				let mut response = vec![self.config.address];
+				response.extend(self.handle_request(&frame[1..REQUEST_LENGTH - 2]));
+				modbus::append_crc(&mut response);
+				thread::sleep(modbus::frame_gap(self.config.baud_rate));
+				self.master.write_all(&response)?;
+			}
+		}
+	}
+}
+
+
+/// Start a simulated VFD, and return the path of the device to talk to it on
+pub fn start(config: &ModbusVfdConfig, ramp_hz_per_sec: f64, fault_after_secs: Option<f64>) -> Result<PathBuf, HardwareError> {
+	let pty = pty::openpty(None, None)?;
+	let device = unistd::ttyname(pty.slave)?;
+	let mut vfd = SimulatedVfd {
+		master: unsafe { File::from_raw_fd(pty.master) },
+		_slave: unsafe { File::from_raw_fd(pty.slave) },
+		config: config.clone(),
+		ramp_hz_per_sec,
+		fault_after_secs,
+		running: false,
+		setpoint_counts: 0,
+		output_hz: 0.0,
+		fault_code: 0,
+		running_secs: 0.0,
+		last_update: Instant::now(),
+	};
+	println!("Simulated VFD on {}", device.display());
+	let builder = thread::Builder::new().name("SimulatedVfd".to_string());
+	builder.spawn(move || {
+		if let Err(error) = vfd.run() {
+			println!("Simulated VFD stopped, error is {:?}", error);
+		}
+	})?;
+	Ok(device)
+}
@@ -0,0 +1,169 @@
+use crate::common::Axis;
+use crate::config::RustGrindConfig;
+use crate::messages::AxisLimits;
+
+use std::collections::HashMap;
+use std::env;
+use std::error;
+use std::fs;
+use std::fs::File;
+use std::io::BufReader;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+
+
+const STATE_FILE_PATH: &str = "/home/pi/rust_grind_state.yaml";
+/// Environment variable that overrides STATE_FILE_PATH, e.g. to keep the simulated machine's state apart from the real one's
+const STATE_FILE_PATH_ENV_VAR: &str = "RUST_GRIND_STATE";
+
+
+
+/// Where an axis was left
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct AxisState {
+	/// Position in logical steps, as far as the step generator had got
+	pub steps: i64,
+	/// Steps the slave motor of a dual-motor axis was ahead of the main one
+	#[serde(default)]
+	pub slave_offset: i64,
+	/// Steps per unit the step count is in, so it isn't used if the gearing or microstepping has been changed since
+	pub steps_per_unit: f64,
+	/// Position measured by the axis's encoder, if it has one
+	#[serde(default)]
+	pub encoder_position: Option<f64>,
+}
+
+/**
+ * What's known about where the machine is, kept in the state file across restarts.
+ * Everything in it is in internal units, as it's only meant to be read back by the controller.
+ */
+#[derive(Clone)]
+#[derive(Default)]
+#[derive(Serialize, Deserialize)]
+pub struct MachineState {
+	#[serde(default)]
+	pub axes: HashMap<Axis, AxisState>,
+	/// Extents of the axes found by homing
+	#[serde(default)]
+	pub envelope: HashMap<Axis, AxisLimits>,
+	#[serde(default)]
+	pub homed: bool,
+	/// Whether the positions can be relied on: carried on from a clean shutdown, or found by homing since
+	#[serde(default)]
+	pub position_trusted: bool,
+	/// Set in the file while the controller is running, and cleared on a clean shutdown, so an unclean exit shows up the next time it starts
+	#[serde(default)]
+	pub running: bool,
+}
+
+impl MachineState {
+	/// Throw away everything that doesn't fit the given config, e.g. axes that have been removed or regeared.
+	/// Then nothing else can be relied on either, so it needs homing again.
+	fn check_against_config(&mut self, config: &RustGrindConfig) {
+		let axes = &self.axes;
+		let matches = config.motor_configs.len() == axes.len() && config.motor_configs.iter()
+			.all(|(axis, motor_config)| matches!(axes.get(axis), Some(state) if state.steps_per_unit == motor_config.steps_per_unit()));
+		if !matches {
+			println!("Axes have changed since the machine state was saved, so the machine needs homing again");
+			*self = MachineState::default();
+		}
+	}
+}
+
+
+
+/// Handle on the machine state, shared between everything that has a part of it to keep up to date, and written out on a clean shutdown
+#[derive(Clone)]
+pub struct MachineStateClient {
+	state: Arc<Mutex<MachineState>>,
+	state_file_path: String,
+}
+
+impl MachineStateClient {
+	/// Restore the state saved by the last run, if it shut down cleanly. Otherwise start from scratch, needing homing.
+	/// Either way, the file is marked as running straight away, so it won't be trusted if this run doesn't shut down cleanly either.
+	pub fn load(config: &RustGrindConfig) -> Self {
+		let state_file_path = env::var(STATE_FILE_PATH_ENV_VAR).unwrap_or(STATE_FILE_PATH.to_string());
+		let mut state = match read_state_file(&state_file_path) {
+			Ok(state) if state.running => {
+				println!("The last run didn't shut down cleanly, so the positions can't be trusted and the machine needs homing again");
+				MachineState::default()
+			},
+			Ok(mut state) => {
+				state.check_against_config(config);
+				state
+			},
+			Err(error) => {
+				println!("Couldn't read the machine state from {}, so starting from scratch. Error is {:?}", state_file_path, error);
+				MachineState::default()
+			},
+		};
+		println!("Restored machine state: homed {}, positions {}trusted", state.homed, if state.position_trusted { "" } else { "not " });
+		state.running = true;
+		let ret = MachineStateClient {
+			state: Arc::new(Mutex::new(state)),
+			state_file_path,
+		};
+		ret.write_state_file();
+		ret
+	}
+
+	/// State that starts from scratch and is never written out, for tests
+	#[cfg(test)]
+	pub fn unsaved() -> Self {
+		MachineStateClient {
+			state: Arc::new(Mutex::new(MachineState::default())),
+			state_file_path: String::new(),
+		}
+	}
+
+	pub fn get(&self) -> MachineState {
+		self.state.lock().unwrap().clone()
+	}
+
+	/// Record where the axes are
+	pub fn set_axes(&self, axes: HashMap<Axis, AxisState>) {
+		self.state.lock().unwrap().axes = axes;
+	}
+
+	/// Record the work envelope, whenever it changes
+	pub fn set_envelope(&self, envelope: HashMap<Axis, AxisLimits>, homed: bool) {
+		let mut state = self.state.lock().unwrap();
+		state.envelope = envelope;
+		state.homed = homed;
+	}
+
+	pub fn set_position_trusted(&self, trusted: bool) {
+		self.state.lock().unwrap().position_trusted = trusted;
+	}
+
+	/// Write out the state on a clean shutdown, once everything has stopped
+	pub fn save(&self) {
+		self.state.lock().unwrap().running = false;
+		self.write_state_file();
+	}
+
+	fn write_state_file(&self) {
+		if let Err(error) = write_state_file(&self.state_file_path, &self.get()) {
+			println!("Couldn't write the machine state to {}, error is {:?}", self.state_file_path, error);
+		}
+	}
+}
+
+
+fn read_state_file(path: &str) -> Result<MachineState, Box<dyn error::Error>> {
+	let file = File::open(path)?;
+	Ok(serde_yaml::from_reader(BufReader::new(file))?)
+}
+
+fn write_state_file(path: &str, state: &MachineState) -> Result<(), Box<dyn error::Error>> {
+	// Write it all to a new file first, so a crash part way through can't leave half a file behind
+	let new_path = format!("{}.new", path);
+	serde_yaml::to_writer(File::create(&new_path)?, state)?;
+	fs::rename(&new_path, path)?;
+	Ok(())
+}
@@ -7,36 +7,107 @@
 
 mod common;
 mod config;
+mod driver_monitor;
+mod encoder_checker;
 mod endstop_checker;
+mod hardware;
+mod machine_state;
 mod messages;
+mod modbus;
+mod motion_profile;
 mod motor_control;
 mod operation_controllers;
 mod pins;
+mod spindle;
+mod step_generator;
+mod tachometer;
+mod tmc_uart;
 mod ui;
 
+use crate::machine_state::MachineStateClient;
+use crate::messages::Message;
+
+use std::process;
 use std::sync::mpsc;
+use std::sync::mpsc::Sender;
 use std::thread;
 use std::time::Duration;
+use std::time::Instant;
+
+use nix::sys::signal::SigSet;
+use nix::sys::signal::Signal;
+
+
+
+/// How long the axes have to have stayed put after a feed hold before they're taken to have stopped
+const SHUTDOWN_SETTLE_TIME: Duration = Duration::from_millis(100);
+/// How long to wait for the axes to stop on shutdown, before giving up on them and stopping dead
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
 
 
 
 fn main() -> ! {
+	// Block the shutdown signals before starting any threads, so they all inherit the mask and the signals are left for us to wait for
+	let mut shutdown_signals = SigSet::empty();
+	shutdown_signals.add(Signal::SIGINT);
+	shutdown_signals.add(Signal::SIGTERM);
+	shutdown_signals.thread_block().unwrap();
+
 	let (main_thread_sender, main_thread_receiver) = mpsc::channel();
 	let (motor_control_sender, motor_control_receiver) = mpsc::channel();
 
 	let mut config_manager = config::ConfigManager::new();
-	// FIXME: log error if it fails to read the file
-	config_manager.read_config_file();
+	if let Err(error) = config_manager.read_config_file() {
+		println!("Couldn't read the config file, so using the defaults. Error is {:?}", error);
+	}
 	// REMOVEME: just writing this temporarily, so we have an up-to-date config file during initial development (format will change often at the moment)
 	config_manager.write_config_file();
 	let initial_config = config_manager.get_config();
 
-	ui::init(main_thread_sender.clone());
-	motor_control::init(initial_config.clone(), motor_control_receiver, main_thread_sender.clone());
-	endstop_checker::init(initial_config.clone(), vec![motor_control_sender.clone(), main_thread_sender.clone()]);
-	operation_controllers::init(initial_config.clone(), main_thread_receiver, motor_control_sender.clone());
+	let hardware = hardware::create_backend(initial_config);
+	let machine_state = MachineStateClient::load(initial_config);
 
-	loop {
-		thread::sleep(Duration::from_millis(1000));
+	ui::init(main_thread_sender.clone(), initial_config.unit_system());
+	// Set up the drivers first, so they're microstepping as configured before anything moves
+	driver_monitor::init(initial_config.clone(), hardware.clone(), vec![motor_control_sender.clone(), main_thread_sender.clone()]);
+	motor_control::init(initial_config.clone(), hardware.clone(), machine_state.clone(), motor_control_receiver, main_thread_sender.clone());
+	endstop_checker::init(initial_config.clone(), hardware.clone(), vec![motor_control_sender.clone(), main_thread_sender.clone()]);
+	encoder_checker::init(initial_config.clone(), hardware.clone(), machine_state.clone(), motor_control_sender.clone());
+	tachometer::init(initial_config.clone(), hardware.clone(), motor_control_sender.clone());
+	operation_controllers::init(initial_config.clone(), machine_state.clone(), main_thread_receiver, motor_control_sender.clone());
+
+	let signal = shutdown_signals.wait().unwrap();
+	println!("Got {}, shutting down", signal);
+	shut_down(&machine_state, &motor_control_sender);
+}
+
+
+/// Bring the axes to a controlled stop, and save where they ended up
+fn shut_down(machine_state: &MachineStateClient, motor_control_sender: &Sender<Message>) -> ! {
+	if motor_control_sender.send(Message::FeedHoldMsgType()).is_err() {
+		println!("Motor control has already stopped");
+	}
+	let start = Instant::now();
+	let mut last_axes = machine_state.get().axes;
+	let mut settled_since = Instant::now();
+	while settled_since.elapsed() < SHUTDOWN_SETTLE_TIME {
+		if start.elapsed() > SHUTDOWN_TIMEOUT {
+			println!("Axes didn't stop in time, so their positions can't be trusted");
+			machine_state.set_position_trusted(false);
+			break;
+		}
+		thread::sleep(Duration::from_millis(10));
+		let axes = machine_state.get().axes;
+		if axes != last_axes {
+			last_axes = axes;
+			settled_since = Instant::now();
+		}
+	}
+	if motor_control_sender.send(Message::StopMsgType()).is_err() {
+		println!("Motor control has already stopped");
 	}
+	// Give motor control a moment to disable the motors before the state is written and everything goes away
+	thread::sleep(Duration::from_millis(50));
+	machine_state.save();
+	process::exit(0);
 }
@@ -1,46 +1,159 @@
 use crate::common::Axis;
+use crate::common::AxisEnd;
+use crate::common::AxisMotor;
 use crate::common::EndstopIdentifier;
+use crate::common::AxisUnit;
+use crate::common::UnitSystem;
+use crate::operation_controllers::BacklashMeasurementParams;
 use crate::operation_controllers::SurfaceGrinderCutParams;
 
+use std::collections::HashMap;
+
 
 
 #[derive(Serialize, Deserialize)]
+#[allow(clippy::enum_variant_names)]
 pub enum Message {
+	AxisLockMsgType(AxisLockMsg),
 	CurrentPositionMsgType(CurrentPositionMsg),
+	DriverFaultMsgType(DriverFaultMsg),
+	EncoderPositionMsgType(EncoderPositionMsg),
 	EndstopHitMsgType(EndstopHitMsg),
+	FeedHoldMsgType(),
+	FeedOverrideMsgType(FeedOverrideMsg),
+	FollowingErrorMsgType(FollowingErrorMsg),
 	GoToPositionMsgType(GoToPositionMsg),
+	JogKeepaliveMsgType(),
+	LinearMoveMsgType(LinearMoveMsg),
+	LinearMoveCompleteMsgType(LinearMoveCompleteMsg),
 	MoveAxisRelMsgType(MoveAxisRelMsg),
+	MoveRejectedMsgType(MoveRejectedMsg),
 	MovementCompleteMsgType(MovementCompleteMsg),
+	ResetFollowingErrorMsgType(ResetFollowingErrorMsg),
+	ResumeMsgType(),
+	SoftLimitsMsgType(SoftLimitsMsg),
 	SpindleControlMsgType(SpindleControlMsg),
+	SpindleFaultMsgType(SpindleFaultMsg),
+	SpindleSpeedMsgType(SpindleSpeedMsg),
+	SpindleStatusMsgType(SpindleStatusMsg),
+	StartBacklashMeasurementMsgType(BacklashMeasurementParams),
 	StartHomingMsgType(),
+	StartJogMsgType(StartJogMsg),
 	StartSurfaceGrinderCutMsgType(SurfaceGrinderCutParams),
+	StepTimingMsgType(StepTimingMsg),
+	StopJogMsgType(),
 	StopMsgType(),
 }
 
-#[derive(Copy, Clone)]
+/// Requests with lengths (or angles) in them, which can come from the API in whatever units the operator is using
+pub trait ConvertUnits {
+	/// Convert all lengths and angles (and speeds) from the given units to internal units
+	fn to_internal_units(self, units: &UnitSystem) -> Self;
+}
+
+/**
+ * Message sent to lock an axis (keep its motor energized) or free it (de-energize it, e.g. to move it by hand with the handwheel)
+ */
+#[derive(Serialize, Deserialize)]
+pub struct AxisLockMsg {
+	pub axis: Axis,
+	pub locked: bool,
+}
+
+/**
+ * Where the axes are. The plain positions are the commanded ones, as far as the motors have been stepped, for every axis the machine has.
+ * Axes with an encoder also have the position it measured (like a DRO), which only differs if the motor has lost steps, or by the backlash.
+ */
+#[derive(Clone)]
 #[derive(PartialEq)]
 #[derive(Serialize, Deserialize)]
 pub struct CurrentPositionMsg {
-	pub x: f64,
-	pub y: f64,
-	pub z: f64,
+	pub positions: HashMap<Axis, f64>,
+	pub measured_positions: HashMap<Axis, f64>,
 }
 impl CurrentPositionMsg {
 	pub fn new() -> Self {
 		CurrentPositionMsg{
-			x: 0.0,
-			y: 0.0,
-			z: 0.0,
+			positions: HashMap::new(),
+			measured_positions: HashMap::new(),
 		}
 	}
 }
 
+/// What a stepper driver reports is wrong, over its UART
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum DriverFault {
+	/// It's shut down to cool off
+	OverTemperature,
+	/// It's getting hot, and will shut down if it gets much hotter
+	OverTemperatureWarning,
+	/// A motor coil is shorted to ground or to the supply, so it's shut down
+	Short,
+	/// A motor coil seems to be disconnected
+	OpenLoad,
+	/// The supply voltage is too low for it to drive the motor
+	UnderVoltage,
+	/// It's stopped answering, so nothing it's meant to be watching for (such as a stall) would be seen
+	NoResponse,
+}
+impl DriverFault {
+	/// Whether the motor can't be relied on to follow its steps, so nothing should carry on moving
+	pub fn stops_motion(&self) -> bool {
+		!matches!(self, DriverFault::OverTemperatureWarning | DriverFault::OpenLoad)
+	}
+}
+
+/**
+ * Sent by the driver monitor whenever the faults a stepper driver reports change, with all the ones it has now (none once they've cleared).
+ * Motor control stops everything when one of them stops motion.
+ */
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct DriverFaultMsg {
+	pub axis: Axis,
+	pub motor: AxisMotor,
+	pub faults: Vec<DriverFault>,
+}
+
+/**
+ * Positions measured by the encoders, sent to motor control whenever they change
+ */
+#[derive(Serialize, Deserialize)]
+pub struct EncoderPositionMsg {
+	pub positions: HashMap<Axis, f64>,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct EndstopHitMsg {
 	pub endstop: EndstopIdentifier,
 	pub value: bool,
 }
 
+/**
+ * Message sent to speed up or slow down all moves, including ones already in progress.
+ * The percentage is of the commanded speed, so 100 runs moves as commanded.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct FeedOverrideMsg {
+	pub percent: f64,
+}
+
+/**
+ * Sent when an axis's encoder shows it's further from the commanded position than the following error limit, e.g. because the motor lost steps.
+ * Motor control has already stopped everything, and refuses to move the axis again until the alarm is reset with ResetFollowingErrorMsg.
+ */
+#[derive(Copy, Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct FollowingErrorMsg {
+	pub axis: Axis,
+	pub commanded: f64,
+	pub measured: f64,
+}
+
 /**
  * Message sent to move an axis to a given position
  */
@@ -51,6 +164,38 @@ pub struct GoToPositionMsg {
 	pub speed: f64,
 }
 
+/**
+ * Message sent to move several axes at once, in a straight line, to the given positions.
+ * Axes that aren't listed stay where they are. Speed is along the line, in length units per second, or degrees per second if only rotary axes are moving.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct LinearMoveMsg {
+	pub positions: HashMap<Axis, f64>,
+	pub speed: f64,
+}
+impl ConvertUnits for LinearMoveMsg {
+	fn to_internal_units(mut self, units: &UnitSystem) -> Self {
+		for (axis, position) in self.positions.iter_mut() {
+			*position = units.for_axis(*axis).to_internal(*position);
+		}
+		// The speed is only in degrees/second if there's no linear axis to go by
+		let speed_axis = self.positions.keys().find(|axis| units.for_axis(**axis) != AxisUnit::Degree).or(self.positions.keys().next());
+		let speed_units = speed_axis.map_or(AxisUnit::Length(units.length), |axis| units.for_axis(*axis));
+		self.speed = speed_units.to_internal(self.speed);
+		self
+	}
+}
+
+/**
+ * Sent once all axes of a linear move have arrived, or when it was cut short by an endstop
+ */
+#[derive(Copy, Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct LinearMoveCompleteMsg {
+	pub endstop_hit: bool,
+}
+
 /**
  * Message sent to move an axis by a relative distance
  */
@@ -59,6 +204,56 @@ pub struct MoveAxisRelMsg {
 	pub axis: Axis,
 	pub distance: f64,
 	pub speed: f64,
+	/// For an axis with two motors, keep each motor going until it reaches its own endstop, so the axis squares itself against them. Used for homing.
+	#[serde(default)]
+	pub square: bool,
+}
+impl ConvertUnits for MoveAxisRelMsg {
+	fn to_internal_units(mut self, units: &UnitSystem) -> Self {
+		let units = units.for_axis(self.axis);
+		self.distance = units.to_internal(self.distance);
+		self.speed = units.to_internal(self.speed);
+		self
+	}
+}
+
+/// Why motor control refused a move
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum MoveRejectionReason {
+	/// The move would have taken the axis outside its soft limits
+	SoftLimit,
+	/// The axis would have moved towards an endstop that's already hit
+	EndstopHit,
+	/// The target position or speed isn't usable, e.g. NaN, infinite, or too far away to count in steps
+	InvalidMove,
+	/// The axis has a following error alarm that hasn't been reset
+	FollowingError,
+	/// While squaring the axis, one motor went further on its own than the config allows, so the other side's endstop is probably broken
+	SquaringLimit,
+	/// A jog can only start once everything else has stopped, and nothing else can start while an axis is jogging
+	Busy,
+	/// The move was cancelled because one of its axes was freed, so it could no longer be moved
+	AxisFreed,
+	/// The step generator has died or got stuck, so nothing can move until we're restarted
+	StepGeneratorFailed,
+}
+
+/**
+ * Sent instead of MovementCompleteMsg or LinearMoveCompleteMsg when motor control refuses a move, without moving at all.
+ * The exceptions are a squaring move that goes wrong partway through, which is stopped where it is, and a move cancelled by freeing one of its axes.
+ */
+#[derive(Copy, Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct MoveRejectedMsg {
+	/// Axis of the rejected single-axis move, or None for a linear move
+	pub move_axis: Option<Axis>,
+	/// Axis that the move was refused for
+	pub axis: Axis,
+	pub reason: MoveRejectionReason,
 }
 
 #[derive(Copy, Clone)]
@@ -69,7 +264,118 @@ pub struct MovementCompleteMsg {
 	pub endstop_hit: bool,
 }
 
+/**
+ * Message sent to clear an axis's following error alarm, once whatever made it lose steps has been dealt with.
+ * The commanded position is set to the one the encoder measured, so the axis carries on from where it really is.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct ResetFollowingErrorMsg {
+	pub axis: Axis,
+}
+
+/// Range an axis is allowed to move within
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct AxisLimits {
+	pub min: f64,
+	pub max: f64,
+}
+
+/**
+ * Message sent to motor control to set the soft limits, which moves aren't allowed to go outside of.
+ * Axes that aren't listed aren't limited at all, e.g. because they haven't been homed yet.
+ */
+#[derive(Serialize, Deserialize)]
+pub struct SoftLimitsMsg {
+	pub limits: HashMap<Axis, AxisLimits>,
+}
+
+/**
+ * Message to switch the spindle on or off, and optionally set its speed.
+ * The speed only does anything if the spindle has speed control; it's clamped to the configured range.
+ */
 #[derive(Serialize, Deserialize)]
 pub struct SpindleControlMsg {
 	pub on: bool,
+	/// Speed in rpm. If it isn't given, the spindle runs at the last speed asked for (or full speed).
+	#[serde(default)]
+	pub rpm: Option<f64>,
+}
+
+/// What's gone wrong with the spindle
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum SpindleFault {
+	/// The VFD has tripped, with the given fault code (which means whatever its manual says it means)
+	VfdFault(u16),
+	/// The VFD has stopped answering
+	NoResponse,
+	/// The spindle didn't come up to speed within the configured time after it was started
+	NotUpToSpeed,
+	/// The spindle driver couldn't be told to switch the spindle or change its speed, e.g. its PWM output couldn't be written, or the VFD link has gone
+	DriverError,
+}
+
+/**
+ * Sent by the spindle driver, or motor control's spindle controller, when the spindle has faulted, so it can't be relied on to be turning.
+ */
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct SpindleFaultMsg {
+	pub fault: SpindleFault,
+}
+
+/**
+ * Sent to motor control whenever the spindle's measured speed changes, by the tachometer or as read back from the VFD
+ */
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct SpindleSpeedMsg {
+	pub rpm: f64,
+}
+
+/**
+ * Sent by motor control when the spindle comes up to speed or drops out of it, and in answer to every SpindleControlMsg
+ */
+#[derive(Copy, Clone)]
+#[derive(PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct SpindleStatusMsg {
+	pub on: bool,
+	/// Whether it's running at the requested speed, within the tolerance
+	pub at_speed: bool,
+	/// Measured speed, if there's anything to measure it with
+	pub rpm: Option<f64>,
+}
+
+/**
+ * Message sent to start moving an axis at a steady speed, until a StopJogMsg arrives, or the axis gets to the end of its travel.
+ * The client has to keep sending JogKeepaliveMsg while it wants the jog to carry on, or the axis slows to a stop by itself (e.g. if the connection drops).
+ */
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct StartJogMsg {
+	pub axis: Axis,
+	pub direction: AxisEnd,
+	pub speed: f64,
+}
+impl ConvertUnits for StartJogMsg {
+	fn to_internal_units(mut self, units: &UnitSystem) -> Self {
+		self.speed = units.for_axis(self.axis).to_internal(self.speed);
+		self
+	}
+}
+
+/**
+ * Sent by motor control every few seconds while the motors are stepping, with how late the steps were compared with the schedule
+ */
+#[derive(Copy, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct StepTimingMsg {
+	pub steps: u64,
+	pub mean_lateness_us: f64,
+	pub max_lateness_us: f64,
 }
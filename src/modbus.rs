@@ -0,0 +1,182 @@
+use crate::hardware::HardwareError;
+
+use std::io::Read;
+use std::io::Write;
+use std::thread;
+use std::time::Duration;
+
+use serial_core::SerialPort;
+use serial_unix::TTYPort;
+
+
+
+pub const READ_HOLDING_REGISTERS: u8 = 0x03;
+pub const WRITE_SINGLE_REGISTER: u8 = 0x06;
+/// Set in the function code of a response to say the request failed, with an exception code in place of the data
+pub const EXCEPTION_FLAG: u8 = 0x80;
+pub const ILLEGAL_FUNCTION: u8 = 0x01;
+pub const ILLEGAL_DATA_ADDRESS: u8 = 0x02;
+
+/// How long to wait for the start of a response, and for each part of it after that
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long to wait for stray bytes when clearing out the input after an error
+const DISCARD_TIMEOUT: Duration = Duration::from_millis(20);
+/// Length of an exception response: address, function, exception code and CRC
+const EXCEPTION_LENGTH: usize = 5;
+
+
+
+/// CRC of a Modbus RTU frame, sent low byte first
+pub fn crc16(data: &[u8]) -> u16 {
+	let mut crc: u16 = 0xFFFF;
+	for byte in data {
+		crc ^= *byte as u16;
+		for _ in 0..8 {
+			if crc & 1 != 0 {
+				crc = (crc >> 1) ^ 0xA001;
+			} else {
+				crc >>= 1;
+			}
+		}
+	}
+	crc
+}
+
+pub fn append_crc(frame: &mut Vec<u8>) {
+	let crc = crc16(frame);
+	frame.push((crc & 0xFF) as u8);
+	frame.push((crc >> 8) as u8);
+}
+
+/// Whether a whole frame (CRC included) arrived intact
+pub fn crc_matches(frame: &[u8]) -> bool {
+	frame.len() >= 4 && crc16(frame) == 0
+}
+
+/// Silence that marks the end of a frame: 3.5 characters of 11 bits, but never less than 1.75 ms
+pub fn frame_gap(baud_rate: u32) -> Duration {
+	Duration::from_secs_f64((3.5 * 11.0 / (baud_rate as f64)).max(0.00175))
+}
+
+
+
+/**
+ * Modbus RTU master, talking to a single slave device on a serial link
+ */
+pub struct ModbusRtuClient {
+	port: TTYPort,
+	address: u8,
+	frame_gap: Duration,
+}
+
+impl ModbusRtuClient {
+	pub fn new(mut port: TTYPort, address: u8, baud_rate: u32) -> Result<Self, HardwareError> {
+		port.set_timeout(RESPONSE_TIMEOUT)?;
+		Ok(ModbusRtuClient {
+			port,
+			address,
+			frame_gap: frame_gap(baud_rate),
+		})
+	}
+
+	pub fn read_holding_registers(&mut self, start: u16, count: u16) -> Result<Vec<u16>, HardwareError> {
+		let mut request = vec![READ_HOLDING_REGISTERS];
+		request.extend_from_slice(&start.to_be_bytes());
+		request.extend_from_slice(&count.to_be_bytes());
+		// Function code, byte count, then the registers
+		let response = self.transact(&request, 2 + 2 * count as usize)?;
+		if response[1] as usize != 2 * count as usize {
+			return Err(format!("Modbus response has {} bytes of registers, expected {}", response[1], 2 * count).into());
+		}
+		Ok(response[2..].chunks(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]])).collect())
+	}
+
+	pub fn write_single_register(&mut self, register: u16, value: u16) -> Result<(), HardwareError> {
+		let mut request = vec![WRITE_SINGLE_REGISTER];
+		request.extend_from_slice(&register.to_be_bytes());
+		request.extend_from_slice(&value.to_be_bytes());
+		// The response echoes the request
+		let response = self.transact(&request, request.len())?;
+		if response != request {
+			return Err(format!("Modbus write of register {} wasn't echoed back, got {:?}", register, response).into());
+		}
+		Ok(())
+	}
+
+	/// Send a request (function code and data) and return the response's, which should be the given length unless it's an exception
+	fn transact(&mut self, request: &[u8], response_length: usize) -> Result<Vec<u8>, HardwareError> {
+		let result = self.try_transact(request, response_length);
+		if result.is_err() {
+			// Don't let the rest of a bad or late response get mixed up with the next one
+			self.discard_input()?;
+		}
+		result
+	}
+
+	fn try_transact(&mut self, request: &[u8], response_length: usize) -> Result<Vec<u8>, HardwareError> {
+		let mut frame = vec![self.address];
+		frame.extend_from_slice(request);
+		append_crc(&mut frame);
+		thread::sleep(self.frame_gap);
+		self.port.write_all(&frame)?;
+
+		let mut response = vec![0; EXCEPTION_LENGTH];
+		self.port.read_exact(&mut response)?;
+		let function = request[0];
+		if response[1] != function | EXCEPTION_FLAG {
+			response.resize(response_length + 3, 0);
+			self.port.read_exact(&mut response[EXCEPTION_LENGTH..])?;
+		}
+		if !crc_matches(&response) {
+			return Err(format!("Modbus response {:?} failed its CRC check", response).into());
+		}
+		if response[0] != self.address {
+			return Err(format!("Modbus response came from address {}, expected {}", response[0], self.address).into());
+		}
+		if response[1] == function | EXCEPTION_FLAG {
+			return Err(format!("Modbus device {} refused function {} with exception {}", self.address, function, response[2]).into());
+		}
+		if response[1] != function {
+			return Err(format!("Modbus response is for function {}, expected {}", response[1], function).into());
+		}
+		response.truncate(response.len() - 2);
+		Ok(response.split_off(1))
+	}
+
+	fn discard_input(&mut self) -> Result<(), HardwareError> {
+		self.port.set_timeout(DISCARD_TIMEOUT)?;
+		let mut buffer = [0; 64];
+		while let Ok(count) = self.port.read(&mut buffer) {
+			if count == 0 {
+				break;
+			}
+		}
+		self.port.set_timeout(RESPONSE_TIMEOUT)?;
+		Ok(())
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Read 10 holding registers from address 0 of device 1, a frame whose CRC (C5 CD on the wire) is widely quoted
+	const READ_REQUEST: [u8; 6] = [0x01, 0x03, 0x00, 0x00, 0x00, 0x0A];
+
+	#[test]
+	fn crc16_of_a_known_frame() {
+		assert_eq!(crc16(&READ_REQUEST), 0xCDC5);
+	}
+
+	#[test]
+	fn crc_is_sent_low_byte_first_and_checked() {
+		let mut frame = READ_REQUEST.to_vec();
+		append_crc(&mut frame);
+		assert_eq!(&frame[6..], &[0xC5, 0xCD]);
+		assert!(crc_matches(&frame));
+		frame[3] ^= 1;
+		assert!(!crc_matches(&frame));
+	}
+}
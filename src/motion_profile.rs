@@ -0,0 +1,253 @@
+use crate::common::NANOMETRES_PER_INCH;
+
+
+
+/// Slowest we ever go while moving (0.01 inches/second, in nanometres/second), so the end of a ramp doesn't creep up on its target forever
+const MIN_VELOCITY: f64 = 0.01 * NANOMETRES_PER_INCH;
+
+
+
+/// Current speed along the path of a move, and how fast that's changing
+#[derive(Copy, Clone)]
+#[derive(Default)]
+pub struct PathState {
+	/// Nanometres/second
+	pub velocity: f64,
+	/// Nanometres/second^2
+	pub acceleration: f64,
+}
+
+/**
+ * Limits on the motion along the path of a move, worked out from the limits of the axes involved.
+ * Without a jerk limit, the velocity follows a trapezoidal profile (constant acceleration). With one, the acceleration itself ramps up and down, giving an S-curve.
+ */
+#[derive(Copy, Clone)]
+pub struct PathLimits {
+	/// Nanometres/second^2
+	pub acceleration: f64,
+	/// Nanometres/second^3
+	pub jerk: Option<f64>,
+}
+
+impl PathLimits {
+	/// Distance needed to get from one velocity to another (in either direction), starting with zero acceleration
+	pub fn distance_to_change_velocity(&self, from: f64, to: f64) -> f64 {
+		let change = (from - to).abs();
+		let time = match self.jerk {
+			None => change / self.acceleration,
+			Some(jerk) => {
+				// Symmetric S-curve: ramp the acceleration up, hold it (if there's time to reach the limit), then ramp it back down
+				if change >= self.acceleration * self.acceleration / jerk {
+					change / self.acceleration + self.acceleration / jerk
+				} else {
+					2.0 * (change / jerk).sqrt()
+				}
+			},
+		};
+		// Either way, the average velocity is halfway between the two
+		(from + to) / 2.0 * time
+	}
+
+	/// Fastest we can go and still be able to slow down to the given velocity within the given distance
+	pub fn max_velocity_before(&self, velocity: f64, distance: f64) -> f64 {
+		match self.jerk {
+			None => (velocity * velocity + 2.0 * self.acceleration * distance).sqrt(),
+			Some(_) => {
+				// No neat closed form, so search for it. The trapezoidal answer is always fast enough to be an upper bound.
+				let mut low = velocity;
+				let mut high = (velocity * velocity + 2.0 * self.acceleration * distance).sqrt();
+				for _ in 0..30 {
+					let middle = (low + high) / 2.0;
+					if self.distance_to_change_velocity(middle, velocity) <= distance {
+						low = middle;
+					} else {
+						high = middle;
+					}
+				}
+				low
+			},
+		}
+	}
+
+	/// Distance needed to slow to the given velocity, from the given state (which may still be accelerating)
+	pub fn distance_to_slow_to(&self, state: PathState, velocity: f64) -> f64 {
+		match self.jerk {
+			None => self.distance_to_change_velocity(state.velocity, velocity),
+			Some(jerk) => {
+				// If we're still accelerating, the velocity keeps going up while we bring the acceleration back to zero
+				let acceleration = state.acceleration.max(0.0);
+				let time_to_zero_accel = acceleration / jerk;
+				let distance_to_zero_accel = state.velocity * time_to_zero_accel + acceleration.powi(3) / (3.0 * jerk * jerk);
+				let peak_velocity = state.velocity + acceleration * acceleration / (2.0 * jerk);
+				distance_to_zero_accel + self.distance_to_change_velocity(peak_velocity, velocity)
+			},
+		}
+	}
+
+	/// Advance the state by a short time step, returning the distance travelled along the path.
+	/// We aim for the target velocity (zero to stop, e.g. for a feed hold), but slow down in time to be going no faster than the exit velocity after the remaining distance.
+	pub fn advance(&self, state: &mut PathState, target_velocity: f64, exit_velocity: f64, remaining: f64, time_step: f64) -> f64 {
+		let exit_velocity = exit_velocity.min(target_velocity);
+		let new_state = match self.jerk {
+			None => {
+				// Limited by where we'll be at the end of the time step, not where we are now, otherwise we start slowing down a step late every step
+				let remaining_after_step = (remaining - state.velocity * time_step).max(0.0);
+				let max_velocity = target_velocity.min(self.max_velocity_before(exit_velocity, remaining_after_step));
+				let max_change = self.acceleration * time_step;
+				let velocity = if max_velocity > state.velocity {
+					(state.velocity + max_change).min(max_velocity)
+				} else {
+					(state.velocity - max_change).max(max_velocity)
+				};
+				PathState{velocity, acceleration: 0.0}
+			},
+			Some(jerk) => self.advance_jerk_limited(*state, jerk, target_velocity, exit_velocity, remaining, time_step),
+		};
+
+		let mut velocity = new_state.velocity.max(0.0);
+		let mut acceleration = new_state.acceleration;
+		if target_velocity == 0.0 && velocity < MIN_VELOCITY {
+			// Stopped
+			velocity = 0.0;
+			acceleration = 0.0;
+		} else if remaining > 0.0 {
+			velocity = velocity.max(MIN_VELOCITY.min(target_velocity));
+		}
+		let distance = (state.velocity + velocity) / 2.0 * time_step;
+		*state = PathState{velocity, acceleration};
+		distance
+	}
+
+	fn advance_jerk_limited(&self, state: PathState, jerk: f64, target_velocity: f64, exit_velocity: f64, remaining: f64, time_step: f64) -> PathState {
+		// Look a step ahead: if we'd be unable to slow down in time after carrying on for one more step, we need to start slowing down now
+		let next_state = PathState {
+			velocity: state.velocity + state.acceleration.max(0.0) * time_step,
+			acceleration: (state.acceleration + jerk * time_step).min(self.acceleration),
+		};
+		let slowing_for_exit = remaining <= self.distance_to_slow_to(next_state, exit_velocity) + state.velocity * time_step;
+
+		// Velocity we'd reach if we eased the acceleration off to zero starting now
+		let eased_velocity_change = state.acceleration * state.acceleration.abs() / (2.0 * jerk);
+		let target_acceleration = if slowing_for_exit || state.velocity > target_velocity {
+			let floor = if slowing_for_exit { exit_velocity } else { target_velocity };
+			if state.velocity + eased_velocity_change <= floor {
+				// About to get down to speed, so ease off the deceleration
+				0.0
+			} else {
+				-self.acceleration
+			}
+		} else if state.velocity + eased_velocity_change >= target_velocity {
+			// Close enough to full speed that we need to start easing off the acceleration, to avoid overshooting it
+			0.0
+		} else {
+			self.acceleration
+		};
+
+		let max_change = jerk * time_step;
+		let acceleration = state.acceleration + (target_acceleration - state.acceleration).max(-max_change).min(max_change);
+		let mut velocity = state.velocity + (state.acceleration + acceleration) / 2.0 * time_step;
+		if target_acceleration >= 0.0 && !slowing_for_exit {
+			velocity = velocity.min(target_velocity.max(state.velocity));
+		}
+		PathState{velocity, acceleration}
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Same as the planner's
+	const TIME_STEP: f64 = 0.00005;
+
+	fn trapezoidal() -> PathLimits {
+		PathLimits{acceleration: 10.0 * NANOMETRES_PER_INCH, jerk: None}
+	}
+
+	fn s_curve() -> PathLimits {
+		PathLimits{acceleration: 10.0 * NANOMETRES_PER_INCH, jerk: Some(200.0 * NANOMETRES_PER_INCH)}
+	}
+
+	/// Run a move the way the planner does, checking every time step of it. Returns the fastest it went.
+	fn run_move(limits: &PathLimits, length: f64, target_velocity: f64, exit_velocity: f64) -> f64 {
+		let mut state = PathState::default();
+		let mut travelled = 0.0;
+		let mut top_velocity: f64 = 0.0;
+		for _ in 0..1_000_000 {
+			let remaining = length - travelled;
+			let previous_velocity = state.velocity;
+			let distance = limits.advance(&mut state, target_velocity, exit_velocity, remaining, TIME_STEP);
+			assert!(state.velocity <= target_velocity * (1.0 + 1e-9), "went {} with a target of {}", state.velocity, target_velocity);
+			if previous_velocity > MIN_VELOCITY && state.velocity > MIN_VELOCITY {
+				// Starting and stopping jump straight to and from the slowest speed, but otherwise the speed has to ramp
+				assert!((state.velocity - previous_velocity).abs() <= limits.acceleration * TIME_STEP * (1.0 + 1e-9), "changed velocity too fast at {}", travelled);
+			}
+			top_velocity = top_velocity.max(state.velocity);
+			if distance >= remaining {
+				// Only ever past the end by creeping up on it at the slowest speed, which the planner cuts short
+				assert!(distance - remaining <= MIN_VELOCITY.max(exit_velocity) * TIME_STEP, "overshot by {}", distance - remaining);
+				assert!(state.velocity <= exit_velocity.max(MIN_VELOCITY) * (1.0 + 1e-9), "got to the end at {} with an exit velocity of {}", state.velocity, exit_velocity);
+				return top_velocity;
+			}
+			travelled += distance;
+		}
+		panic!("never got to the end of the move");
+	}
+
+	#[test]
+	fn trapezoidal_move_reaches_speed_and_stops_at_the_end() {
+		let top_velocity = run_move(&trapezoidal(), 4.0 * NANOMETRES_PER_INCH, 2.0 * NANOMETRES_PER_INCH, 0.0);
+		assert!((top_velocity - 2.0 * NANOMETRES_PER_INCH).abs() < 1.0);
+	}
+
+	#[test]
+	fn s_curve_move_reaches_speed_and_stops_at_the_end() {
+		let top_velocity = run_move(&s_curve(), 4.0 * NANOMETRES_PER_INCH, 2.0 * NANOMETRES_PER_INCH, 0.0);
+		assert!((top_velocity - 2.0 * NANOMETRES_PER_INCH).abs() < 1.0);
+	}
+
+	#[test]
+	fn short_moves_stop_at_the_end_without_reaching_speed() {
+		for limits in [trapezoidal(), s_curve()].iter() {
+			let top_velocity = run_move(limits, 0.01 * NANOMETRES_PER_INCH, 2.0 * NANOMETRES_PER_INCH, 0.0);
+			assert!(top_velocity < 2.0 * NANOMETRES_PER_INCH);
+		}
+	}
+
+	#[test]
+	fn moves_slow_to_their_exit_velocity() {
+		for limits in [trapezoidal(), s_curve()].iter() {
+			run_move(limits, 2.0 * NANOMETRES_PER_INCH, 2.0 * NANOMETRES_PER_INCH, 0.5 * NANOMETRES_PER_INCH);
+		}
+	}
+
+	#[test]
+	fn stops_for_a_zero_target_velocity() {
+		for limits in [trapezoidal(), s_curve()].iter() {
+			let mut state = PathState{velocity: 2.0 * NANOMETRES_PER_INCH, acceleration: 0.0};
+			let mut travelled = 0.0;
+			while state.velocity > 0.0 {
+				travelled += limits.advance(&mut state, 0.0, 0.0, 100.0 * NANOMETRES_PER_INCH, TIME_STEP);
+			}
+			// Should take as long to stop as it says it does, give or take a time step
+			let expected = limits.distance_to_slow_to(PathState{velocity: 2.0 * NANOMETRES_PER_INCH, acceleration: 0.0}, 0.0);
+			assert!((travelled - expected).abs() < 2.0 * NANOMETRES_PER_INCH * TIME_STEP, "stopped in {} rather than {}", travelled, expected);
+		}
+	}
+
+	#[test]
+	fn max_velocity_before_is_the_inverse_of_distance_to_change_velocity() {
+		for limits in [trapezoidal(), s_curve()].iter() {
+			for &velocity in [0.0, 0.1 * NANOMETRES_PER_INCH, 1.5 * NANOMETRES_PER_INCH].iter() {
+				for &distance in [0.001 * NANOMETRES_PER_INCH, 0.1 * NANOMETRES_PER_INCH, 10.0 * NANOMETRES_PER_INCH].iter() {
+					let max_velocity = limits.max_velocity_before(velocity, distance);
+					assert!(max_velocity >= velocity);
+					let needed = limits.distance_to_change_velocity(max_velocity, velocity);
+					assert!((needed - distance).abs() <= distance * 1e-6, "{} from {} needs {} rather than {}", max_velocity, velocity, needed, distance);
+				}
+			}
+		}
+	}
+}
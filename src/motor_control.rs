@@ -1,36 +1,50 @@
 use crate::common::Axis;
 use crate::common::AxisEnd;
+use crate::common::AxisMotor;
 use crate::common::EndstopIdentifier;
+use crate::common::NANOMETRES_PER_INCH;
+use crate::config::EncoderConfig;
+use crate::config::MotionProfileConfig;
 use crate::config::MotorConfig;
 use crate::config::RustGrindConfig;
+use crate::config::SoftLimitMode;
 use crate::endstop_checker::EndstopStatusClient;
+use crate::hardware::DigitalOutput;
+use crate::hardware::HardwareBackend;
+use crate::hardware::HardwareError;
+use crate::machine_state::AxisState;
+use crate::machine_state::MachineStateClient;
+use crate::messages::AxisLimits;
 use crate::messages::CurrentPositionMsg;
+use crate::messages::DriverFaultMsg;
+use crate::messages::FollowingErrorMsg;
+use crate::messages::LinearMoveCompleteMsg;
 use crate::messages::Message;
+use crate::messages::MoveRejectedMsg;
+use crate::messages::MoveRejectionReason;
 use crate::messages::MovementCompleteMsg;
+use crate::messages::SpindleControlMsg;
+use crate::messages::SpindleFault;
+use crate::messages::SpindleFaultMsg;
+use crate::messages::StepTimingMsg;
+use crate::motion_profile::PathLimits;
+use crate::motion_profile::PathState;
+use crate::spindle::SpindleController;
+use crate::step_generator;
+use crate::step_generator::AxisStepper;
+use crate::step_generator::StepEvent;
+use crate::step_generator::StepGeneratorClient;
 
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::collections::VecDeque;
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::Sender;
-use std::sync::mpsc::TryRecvError;
+use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
-
-use stepper::{
-	drivers::drv8825::DRV8825,
-	motion_control, ramp_maker,
-	motion_control::SoftwareMotionControl,
-	traits::MotionControl,
-};
-
-use embedded_hal::digital::OutputPin;
-use embedded_hal::timer::CountDown;
-
-use linux_embedded_hal;
-use linux_embedded_hal::CdevPin;
-use linux_embedded_hal::gpio_cdev::Chip;
-use linux_embedded_hal::gpio_cdev::LineRequestFlags;
-use linux_embedded_hal::sysfs_gpio::Direction as PinDirection;
-use linux_embedded_hal::SysfsPin;
-use linux_embedded_hal::SysTimer;
+use std::time::Instant;
 
 
 
@@ -45,16 +59,14 @@ impl CurrentPositionClient {
 		}
 	}
 
+	#[allow(dead_code)]
 	pub fn get_position(&self) -> CurrentPositionMsg {
-		self.last_msg
+		self.last_msg.clone()
 	}
 
+	/// Commanded position of an axis. Zero for an axis the machine doesn't have, or before motor control has reported anything.
 	pub fn get_axis_position(&self, axis: Axis) -> f64 {
-		match axis {
-			Axis::X => self.last_msg.x,
-			Axis::Y => self.last_msg.y,
-			Axis::Z => self.last_msg.z,
-		}
+		self.last_msg.positions.get(&axis).copied().unwrap_or(0.0)
 	}
 
 	pub fn handle_message(&mut self, msg: CurrentPositionMsg) {
@@ -64,174 +76,260 @@ impl CurrentPositionClient {
 
 
 
-type Driver = SoftwareMotionControl<
-	DRV8825<
-		(),
-		(),
-		(),
-		(),
-		(),
-		(),
-		(),
-		SysfsPin,
-		SysfsPin,
-	>,
-	SysTimer,
-	ramp_maker::Trapezoidal<Num>,
-	DelayToTicks,
->;
+/// Range of the feed override, in percent of the commanded speed
+const MIN_FEED_OVERRIDE_PERCENT: f64 = 10.0;
+const MAX_FEED_OVERRIDE_PERCENT: f64 = 200.0;
+
+/// Length of each segment of the step schedule
+const SEGMENT_DURATION: f64 = 0.001;
+/// Time step for working out the motion along the path. Steps are spread evenly within each of these.
+const PLANNER_TIME_STEP: f64 = 0.00005;
+/// Longest the planner waits for messages before topping up the step buffer again. Needs to be well short of the time covered by the buffer.
+const PLANNER_INTERVAL: Duration = Duration::from_millis(1);
+/// Longest we wait for messages while nothing's moving, before checking on the idle motors and the spindle again
+const IDLE_INTERVAL: Duration = Duration::from_millis(20);
+/// How often to report the step timing, while the motors are stepping
+const STEP_TIMING_REPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How far a jog goes if nothing stops it sooner, on an axis without soft limits (i.e. before homing)
+const JOG_DISTANCE: f64 = 256.0 * NANOMETRES_PER_INCH;
+
 
+/// Per-motor state that motor control keeps for itself: the stepping is done by the step generator, but energizing the motor is up to us.
 pub struct StepperMotorController  {
-	// Not using "Stepper" because that interface wraps move_to_position() in a custom Future-like object with references and lifetimes and such
-	// We want to just set the target position, and poll it until it reaches it or we have to stop for some reason, and the whole MoveToFuture thing requires us to hold onto a future and release it when finished, and it just makes things very complicated.
-	driver: Driver,
+	enable_pin: Box<dyn DigitalOutput>,
 	config: MotorConfig,
-	movement_in_progress: bool,
-	direction: AxisEnd,
+	enabled: bool,
+	/// Locked axes stay enabled even when idle, until they're freed again
+	locked: bool,
+	/// Last time the motor was moving or told to move, for de-energizing it once it's been idle for a while
+	last_active_time: Instant,
 }
 
 impl StepperMotorController {
-	pub fn new(config: MotorConfig) -> Result<StepperMotorController, linux_embedded_hal::sysfs_gpio::Error> {
-		// FIXME: switch to cdev pins, and remember to run `echo PIN_NUMBER > /sys/class/gpio/unexport` for each pin to switch it away from sysfs
-
-		let enable_pin = SysfsPin::new(config.enable_pin_number);
-		match enable_pin.export() {
-			Ok(()) => println!("Gpio {} exported!", enable_pin.get_pin()),
-			Err(err) => println!("Gpio {} could not be exported: {}", enable_pin.get_pin(), err)
-		}
-		enable_pin.set_direction(PinDirection::Out)?;
-
-		let step_pin = SysfsPin::new(config.step_pin_number);
-		match step_pin.export() {
-			Ok(()) => println!("Gpio {} exported!", step_pin.get_pin()),
-			Err(err) => println!("Gpio {} could not be exported: {}", step_pin.get_pin(), err)
-		}
-		step_pin.set_direction(PinDirection::Out)?;
-
-		let direction_pin = SysfsPin::new(config.direction_pin_number);
-		match direction_pin.export() {
-			Ok(()) => println!("Gpio {} exported!", direction_pin.get_pin()),
-			Err(err) => println!("Gpio {} could not be exported: {}", direction_pin.get_pin(), err)
-		}
-		direction_pin.set_direction(PinDirection::Out)?;
-
-		let mut timer = SysTimer::new();
-		timer.try_start(Duration::from_millis(1));
-
-		// These values assume a 1 MHz timer, but that depends on the timer you're
-		// using, of course.
-		let target_accel: f64 = 0.1; // steps / millisecond^2
-
-		// We want to use the high-level motion control API (see below), but let's
-		// assume the driver we use for this example doesn't provide hardware
-		// support for that. Let's instantiate a motion profile from the RampMaker
-		// library to provide a software fallback.
-		let profile = ramp_maker::Trapezoidal::new(target_accel);
-
-		use stepper::traits::EnableDirectionControl;
-		use stepper::traits::EnableStepControl;
-
-		Ok(
-			StepperMotorController {
-				driver: SoftwareMotionControl::new(
-					DRV8825::new()
-						.enable_direction_control(direction_pin)
-						.enable_step_control(step_pin),
-					timer,
-					profile,
-					DelayToTicks
-				),
-				config,
-				movement_in_progress: false,
-				direction: AxisEnd::Min,
-			}
-		)
-	}
-
-	pub fn start_move_rel(&mut self, distance: f64, speed: f64) -> Result<(), <Driver as MotionControl>::Error> {
-		// FIXME: handle reversed motor
-		// FIXME: handle too-small values: goes wrong direction if you put in min-i32
-		let target_step = self.driver.current_step() + self.config.inches_to_steps(distance);
-
-		// FIXME: handle reversed motor, i.e. opposite of what the driver reports
-		if target_step < self.driver.current_step() {
-			self.direction = AxisEnd::Min;
-		} else {
-			self.direction = AxisEnd::Max;
+	pub fn new(config: MotorConfig, enable_pin: Box<dyn DigitalOutput>, mut microstep_pins: Vec<Box<dyn DigitalOutput>>) -> Result<StepperMotorController, HardwareError> {
+		let mut ret = StepperMotorController {
+			enable_pin,
+			config,
+			// Until the enable pin's driven, it's low, which enables the driver
+			enabled: true,
+			locked: false,
+			last_active_time: Instant::now(),
+		};
+		ret.set_enabled(false)?;
+		// Set the microstepping while the driver's disabled, so it doesn't take a step in the wrong mode.
+		// The pins hold their level from then on, so there's no need to keep them.
+		let levels = ret.config.driver.microstep_pin_levels(microstep_pins.len())?;
+		for (pin, high) in microstep_pins.iter_mut().zip(levels) {
+			if high {
+				pin.set_high()?;
+			} else {
+				pin.set_low()?;
+			}
 		}
-		self.movement_in_progress = true;
-		self.driver.move_to_position(self.ips_to_steps_per_millisecond(speed), target_step)
+		// Axes that need holding torque are energized from the start, everything else waits until it's moved
+		ret.set_enabled(ret.config.hold_torque)?;
+		Ok(ret)
 	}
 
-	pub fn start_move_to(&mut self, position: f64, speed: f64) -> Result<(), <Driver as MotionControl>::Error> {
-		// FIXME: handle reversed motor
-		// FIXME: handle too-small values: goes wrong direction if you put in min-i32
-		let target_step = self.config.inches_to_steps(position);
-		println!("target step is {}", target_step);
+	/// Energize the motor for a move, and keep it energized while the move is queued or running
+	pub fn set_active(&mut self) -> Result<(), HardwareError> {
+		self.last_active_time = Instant::now();
+		self.set_enabled(true)
+	}
 
-		// TODO: make common method that the various move methods will use
-		// FIXME: handle reversed motor, i.e. opposite of what the driver reports
-		if target_step < self.driver.current_step() {
-			self.direction = AxisEnd::Min;
-		} else {
-			self.direction = AxisEnd::Max;
+	/// Energize or de-energize the motor
+	fn set_enabled(&mut self, enabled: bool) -> Result<(), HardwareError> {
+		if enabled != self.enabled {
+			// Stepper drivers' enable inputs are active low
+			if enabled {
+				self.enable_pin.set_low()?;
+			} else {
+				self.enable_pin.set_high()?;
+			}
+			self.enabled = enabled;
 		}
-		self.movement_in_progress = true;
-		self.driver.move_to_position(self.ips_to_steps_per_millisecond(speed), target_step)
+		Ok(())
+	}
+
+	/// Lock the axis (keep it energized, even when idle), or free it (de-energize it now, so it can be moved by hand).
+	/// A freed axis is re-enabled automatically the next time it's told to move.
+	pub fn set_locked(&mut self, locked: bool) -> Result<(), HardwareError> {
+		self.locked = locked;
+		self.set_enabled(locked)
 	}
 
-	pub fn stop_move(&mut self) -> Result<(), <Driver as MotionControl>::Error> {
-		if self.movement_in_progress {
-			self.start_move_rel(0.0, 0.0)?;
-			self.movement_in_progress = false;
+	/// De-energize the motor if it's been idle for longer than the given timeout, unless it needs to hold its position
+	pub fn update_idle(&mut self, idle_timeout: Duration) -> Result<(), HardwareError> {
+		if
+			self.enabled
+			&& !self.locked
+			&& !self.config.hold_torque
+			&& self.last_active_time.elapsed() >= idle_timeout
+		{
+			self.set_enabled(false)?;
 		}
 		Ok(())
 	}
+}
+
 
-	pub fn update(&mut self) -> Result<bool, <Driver as MotionControl>::Error> {
-		// Stepper driver library will try to switch direction to Backward when told to go to its current position, which means if it was going Forward and we stopped it, it will actually tell us it's still moving when we call update().
-		// So if we believe we're done moving, let's not do the update.
-		if self.movement_in_progress {
-			self.movement_in_progress = self.driver.update()?;
+
+/// What kind of move a block came from, which decides how its completion is reported
+#[derive(Copy, Clone)]
+enum BlockKind {
+	/// Single-axis move, reported with MovementCompleteMsg
+	SingleAxis(Axis),
+	/// Multi-axis linear move, reported with LinearMoveCompleteMsg
+	Linear,
+	/// Single-axis move that carries on until the jog is stopped, reported with MovementCompleteMsg once the axis has slowed to a stop
+	Jog(Axis),
+	/// Short move of the motors only, to take up the backlash of the axes the next block reverses. Not reported, since it's part of that block.
+	/// Its positions are how far the slack has been taken up, from where it started, rather than where the axes are.
+	Backlash,
+}
+
+/// A jog that's still going, and the last time the client showed it wanted it to carry on
+struct Jog {
+	axis: Axis,
+	direction: AxisEnd,
+	last_keepalive: Instant,
+}
+
+/**
+ * A straight-line move of one or more axes, queued in the motion planner.
+ */
+struct Block {
+	kind: BlockKind,
+	/// Whether each motor of a dual-motor axis stops on its own endstop, rather than the whole axis stopping on the first one
+	squaring: bool,
+	start_positions: HashMap<Axis, f64>,
+	end_positions: HashMap<Axis, f64>,
+	/// Requested speed along the path, in nanometres/second
+	speed: f64,
+	length: f64,
+	/// Direction of travel: how much of each nanometre along the path is along each axis
+	unit_vector: HashMap<Axis, f64>,
+	limits: PathLimits,
+	/// Fastest we can be going as we enter this block, to get round the corner from the block before it
+	max_entry_velocity: f64,
+	/// ID of the step segment that finishes this block, once it's been planned
+	last_segment_id: Option<u64>,
+}
+
+impl Block {
+	pub fn new(kind: BlockKind, squaring: bool, start_positions: HashMap<Axis, f64>, end_positions: HashMap<Axis, f64>, speed: f64, motor_configs: &HashMap<Axis, MotorConfig>) -> Self {
+		let length = start_positions.iter()
+			.map(|(axis, start)| (end_positions[axis] - start).powi(2))
+			.sum::<f64>()
+			.sqrt();
+		let mut unit_vector = HashMap::new();
+		if length > 0.0 {
+			for (axis, start) in start_positions.iter() {
+				unit_vector.insert(*axis, (end_positions[axis] - start) / length);
+			}
+		}
+
+		// Limit acceleration (and jerk) along the path so that no individual axis goes over its own limit
+		let mut acceleration = f64::INFINITY;
+		let mut jerk = None;
+		for (axis, fraction) in unit_vector.iter() {
+			let fraction = fraction.abs();
+			if fraction > 0.0 {
+				let config = &motor_configs[axis];
+				acceleration = acceleration.min(config.get_acceleration() / fraction);
+				if let MotionProfileConfig::SCurve{jerk: axis_jerk} = config.motion_profile {
+					jerk = Some(jerk.unwrap_or(f64::INFINITY).min(axis_jerk / fraction));
+				}
+			}
 		}
-		Ok(self.movement_in_progress)
-	}
 
-	/// FIXME: should return Option, so we can distinguish when it's stationary.
-	pub fn get_direction(&self) -> AxisEnd {
-		self.direction
+		Block {
+			kind,
+			squaring,
+			start_positions,
+			end_positions,
+			speed,
+			length,
+			unit_vector,
+			limits: PathLimits{acceleration, jerk},
+			max_entry_velocity: 0.0,
+			last_segment_id: None,
+		}
 	}
 
-	pub fn get_position(&self) -> f64 {
-		// FIXME: handle reversed motor
-		self.config.steps_to_inches(self.driver.current_step())
+	/// Axes that this block actually moves
+	pub fn moving_axes(&self) -> Vec<Axis> {
+		self.unit_vector.iter()
+			.filter(|(_, fraction)| **fraction != 0.0)
+			.map(|(axis, _)| *axis)
+			.collect()
 	}
 
-	pub fn is_movement_in_progress(&self) -> bool {
-		self.movement_in_progress
+	/// Axis to report the block against if it's rejected: the one it moves, or any of them for a linear move
+	pub fn report_axis(&self) -> Option<Axis> {
+		match self.kind {
+			BlockKind::SingleAxis(axis) | BlockKind::Jog(axis) => Some(axis),
+			BlockKind::Linear => self.end_positions.keys().next().copied(),
+			BlockKind::Backlash => None,
+		}
 	}
 
-	/// FIXME: should these be moved to config?
-	fn ips_to_steps_per_millisecond(&self, ips: f64) -> f64 {
-		ips * self.config.revs_per_inch * (self.config.steps_per_rev as f64) / 1000.0
+	/// Whether the block moves the given axis's logical position. Taking up the backlash only moves its motors.
+	pub fn uses_axis(&self, axis: Axis) -> bool {
+		match self.kind {
+			BlockKind::Backlash => false,
+			_ => self.start_positions.contains_key(&axis),
+		}
 	}
-}
 
+	/// Which way the given axis is moving
+	pub fn get_direction(&self, axis: Axis) -> AxisEnd {
+		if self.end_positions[&axis] < self.start_positions[&axis] {
+			AxisEnd::Min
+		} else {
+			AxisEnd::Max
+		}
+	}
 
-type Num = f64;
+	/// Position of the given axis at the given distance along the path
+	pub fn position_at(&self, axis: Axis, distance: f64) -> f64 {
+		if distance >= self.length {
+			return self.end_positions[&axis];
+		}
+		self.start_positions[&axis] + self.unit_vector[&axis] * distance
+	}
 
-// Converter for Stepper to convert from the computed delay value (in milliseconds) to timer ticks.
-pub struct DelayToTicks;
-impl motion_control::DelayToTicks<Num> for DelayToTicks {
-	type Ticks = <SysTimer as embedded_hal::timer::CountDown>::Time;
-	type Error = core::convert::Infallible;
+	/// End the block at the given distance along its path instead, if that's short of its end
+	pub fn cut_short(&mut self, length: f64) {
+		if length >= self.length {
+			return;
+		}
+		for axis in self.moving_axes() {
+			let position = self.position_at(axis, length);
+			self.end_positions.insert(axis, position);
+		}
+		self.length = length;
+	}
 
-	fn delay_to_ticks(&self, delay: Num)
-		-> Result<Self::Ticks, Self::Error>
-	{
-		// Our delay is in fractional milliseconds, and we want as much precision as we can get, so we convert to nanoseconds.
-		let ticks = <SysTimer as embedded_hal::timer::CountDown>::Time::from_nanos((delay * 1_000_000.0).round() as u64);
-		Ok(ticks)
+	/// Fastest we can go round the corner from the previous block into this one.
+	/// Uses the junction deviation approach: the speed at which we could take a curve, at the lower of the two blocks' accelerations, that stays within the given distance of the corner.
+	pub fn junction_velocity(&self, previous: &Block, junction_deviation: f64) -> f64 {
+		if self.length == 0.0 || previous.length == 0.0 {
+			return 0.0;
+		}
+		// Cosine of the angle between where we were coming from and where we're going, so 1 is a complete reversal and -1 is carrying straight on
+		let cos_theta = -self.unit_vector.iter()
+			.map(|(axis, fraction)| fraction * previous.unit_vector.get(axis).unwrap_or(&0.0))
+			.sum::<f64>();
+		if cos_theta > 0.999999 {
+			return 0.0;
+		}
+		if cos_theta < -0.999999 {
+			return f64::INFINITY;
+		}
+		let acceleration = self.limits.acceleration.min(previous.limits.acceleration);
+		let sin_half_theta = (0.5 * (1.0 - cos_theta)).sqrt();
+		(acceleration * junction_deviation * sin_half_theta / (1.0 - sin_half_theta)).sqrt()
 	}
 }
 
@@ -239,70 +337,621 @@ impl motion_control::DelayToTicks<Num> for DelayToTicks {
 /**
  * Object responsible for controlling the motors.
  * This object doesn't understand what we're doing, and just responds to commands to move the motors.
+ *
+ * It's the motion planner: moves are queued as blocks and run one after another, with lookahead over the queue so that we only slow down as much as the corners between them need.
+ * The motion is turned into a schedule of steps, a little ahead of time, which the step generator carries out on its own thread.
  */
 pub struct MotorsControl {
 	receiver: Receiver<Message>,
 	sender: Sender<Message>,
-	x_controller: StepperMotorController,
-	y_controller: StepperMotorController,
-	z_controller: StepperMotorController,
+	/// One for each motor in the config, main motor first
+	controllers: HashMap<Axis, Vec<StepperMotorController>>,
+	motor_configs: HashMap<Axis, MotorConfig>,
+	step_generator: StepGeneratorClient,
+	/// Set once the step generator has died or got stuck, after which nothing can move
+	step_generator_failed: bool,
+	/// Motors held still by the step generator while the rest of their axis squares itself against the endstops
+	held_motors: HashSet<(Axis, AxisMotor)>,
+	/// Offset between the motors of the axis being squared, from before any of them were held
+	squaring_start_offset: Option<i64>,
 	endstop_status_client: EndstopStatusClient,
 	last_position_msg: CurrentPositionMsg,
-	spindle_pin: CdevPin,
+	/// Kept up to date with where the axes are, so they can carry on from there after a restart
+	machine_state: MachineStateClient,
+	spindle: SpindleController,
+	motor_idle_timeout: Duration,
+	junction_deviation: f64,
+	/// Range each homed axis may move within. Empty until the machine has been homed, since until then we don't know where anything is.
+	soft_limits: HashMap<Axis, AxisLimits>,
+	soft_limit_mode: SoftLimitMode,
+	encoder_configs: HashMap<Axis, EncoderConfig>,
+	/// Latest positions from the axes that have encoders
+	measured_positions: HashMap<Axis, f64>,
+	/// Axes whose encoders have shown them too far from where they should be. They aren't moved again until the alarm is reset.
+	following_error_alarms: HashSet<Axis>,
+	/// Axes that have been freed to be moved by hand, and not moved since. Their position follows their encoder, if they have one.
+	freed_axes: HashSet<Axis>,
+	/// Moves that haven't finished yet. The step generator runs a little behind the planner, so it's working on the first one, while the planner may be further along.
+	blocks: VecDeque<Block>,
+	/// Which block the planner is working on, and how far along it
+	planning_index: usize,
+	planning_distance: f64,
+	path_state: PathState,
+	/// Where the step schedule will have put each axis, so far, in logical steps
+	planned_steps: HashMap<Axis, i64>,
+	/// Slack in each axis's leadscrew, in steps
+	backlash_steps: HashMap<Axis, i64>,
+	/// How many steps of each axis's backlash the queued moves will have taken up towards its Max end.
+	/// Missing for an axis until it's first moved, since we can't know which way the slack was taken up before that.
+	slack: HashMap<Axis, i64>,
+	feed_held: bool,
+	/// Factor applied to the speed of every move, set by the operator as a percentage
+	feed_override: f64,
+	jog: Option<Jog>,
+	jog_keepalive_timeout: Duration,
+	last_step_timing_report: Instant,
 }
 
 impl MotorsControl {
-	pub fn new(initial_config: &RustGrindConfig, receiver: Receiver<Message>, sender: Sender<Message>) -> Result<MotorsControl, linux_embedded_hal::sysfs_gpio::Error> {
-		let x_config = initial_config.motor_configs.get(&Axis::X).copied().unwrap();
-		let y_config = initial_config.motor_configs.get(&Axis::Y).copied().unwrap();
-		let z_config = initial_config.motor_configs.get(&Axis::Z).copied().unwrap();
-		let mut chip = Chip::new(initial_config.gpio_chip_name.clone()).unwrap();
-		let spindle_line_handle = chip.get_line(initial_config.spindle_enable_pin).unwrap().request(LineRequestFlags::OUTPUT, 0, "spindle control").unwrap();
-		let spindle_pin = CdevPin::new(spindle_line_handle).unwrap();
-		Ok(MotorsControl {
+	pub fn new(initial_config: &RustGrindConfig, hardware: &dyn HardwareBackend, machine_state: MachineStateClient, receiver: Receiver<Message>, sender: Sender<Message>) -> Result<MotorsControl, HardwareError> {
+		let mut controllers = HashMap::new();
+		let mut steppers = HashMap::new();
+		let mut backlash_steps = HashMap::new();
+		for (axis, config) in initial_config.motor_configs.iter() {
+			let pins = hardware.make_motor_pins(*axis, AxisMotor::Main, config)?;
+			let mut stepper = AxisStepper::new(config.clone(), pins.step, pins.direction);
+			let mut axis_controllers = vec![StepperMotorController::new(config.clone(), pins.enable, pins.microstep)?];
+			if config.slave.is_some() {
+				let pins = hardware.make_motor_pins(*axis, AxisMotor::Slave, config)?;
+				stepper.add_slave(pins.step, pins.direction);
+				axis_controllers.push(StepperMotorController::new(config.clone(), pins.enable, pins.microstep)?);
+			}
+			steppers.insert(*axis, stepper);
+			controllers.insert(*axis, axis_controllers);
+			let axis_backlash_steps = match config.distance_to_steps(config.backlash) {
+				Ok(steps) => steps.max(0),
+				Err(error) => {
+					println!("Ignoring invalid backlash {} of axis {}: {}", config.backlash, axis, error);
+					0
+				},
+			};
+			backlash_steps.insert(*axis, axis_backlash_steps);
+		}
+		let spindle = SpindleController::new(initial_config, hardware, sender.clone())?;
+		let mut step_generator = step_generator::init(initial_config.step_generator, steppers);
+		// Carry on from where the axes were left last time, if that's known
+		for (axis, axis_state) in machine_state.get().axes {
+			step_generator.set_position(axis, axis_state.steps);
+			step_generator.set_slave_offset(axis, axis_state.slave_offset);
+		}
+
+		let mut ret = MotorsControl {
 			receiver,
 			sender,
-			x_controller: StepperMotorController::new(x_config)?,
-			y_controller: StepperMotorController::new(y_config)?,
-			z_controller: StepperMotorController::new(z_config)?,
+			controllers,
+			motor_configs: initial_config.motor_configs.clone(),
+			step_generator,
+			step_generator_failed: false,
+			held_motors: HashSet::new(),
+			squaring_start_offset: None,
 			endstop_status_client: EndstopStatusClient::new(),
 			last_position_msg: CurrentPositionMsg::new(),
-			spindle_pin,
-		})
+			machine_state,
+			spindle,
+			motor_idle_timeout: Duration::from_secs_f64(initial_config.motor_idle_timeout_secs),
+			junction_deviation: initial_config.get_junction_deviation(),
+			soft_limits: HashMap::new(),
+			soft_limit_mode: initial_config.soft_limit_mode,
+			encoder_configs: initial_config.encoder_configs.clone(),
+			measured_positions: HashMap::new(),
+			following_error_alarms: HashSet::new(),
+			freed_axes: HashSet::new(),
+			blocks: VecDeque::new(),
+			planning_index: 0,
+			planning_distance: 0.0,
+			path_state: PathState::default(),
+			planned_steps: HashMap::new(),
+			backlash_steps,
+			slack: HashMap::new(),
+			feed_held: false,
+			feed_override: 1.0,
+			jog: None,
+			jog_keepalive_timeout: Duration::from_millis(initial_config.jog_keepalive_timeout_ms),
+			last_step_timing_report: Instant::now(),
+		};
+		ret.sync_with_step_generator();
+		Ok(ret)
+	}
+
+	/// Controllers of the motors of an axis the machine has
+	fn get_controllers_mut(&mut self, axis : Axis) -> &mut Vec<StepperMotorController> {
+		self.controllers.get_mut(&axis).expect("Axis is in the config")
 	}
 
-	fn get_controller_mut(&mut self, axis : Axis) -> &mut StepperMotorController {
-		match axis {
-			Axis::X => &mut self.x_controller,
-			Axis::Y => &mut self.y_controller,
-			Axis::Z => &mut self.z_controller,
+	/// Whether the machine has the given axis, logging it if it doesn't
+	fn has_axis(&self, axis: Axis) -> bool {
+		let has_axis = self.motor_configs.contains_key(&axis);
+		if !has_axis {
+			println!("There is no axis {}", axis);
+		}
+		has_axis
+	}
+
+	/// Actual position of an axis, as far as the step generator has got
+	fn get_position(&self, axis: Axis) -> f64 {
+		self.motor_configs[&axis].steps_to_position(self.step_generator.get_position(axis))
+	}
+
+	/// Where an axis will be once all the queued moves are done
+	fn get_planned_position(&self, axis: Axis) -> f64 {
+		match self.blocks.iter().rev().find(|block| block.uses_axis(axis)) {
+			Some(block) => block.end_positions[&axis],
+			None => self.get_position(axis),
 		}
 	}
 
 	pub fn go_to_position(&mut self, axis: Axis, position: f64, speed: f64) {
 		println!("Moving {:#?} to position {}", axis, position);
-		// FIXME: should not move if endstop is already hit; seems like we take a step or two to recognize it.
-		self.get_controller_mut(axis).start_move_to(position, speed);
+		if !self.has_axis(axis) {
+			self.send_move_rejected(BlockKind::SingleAxis(axis), axis, MoveRejectionReason::InvalidMove);
+			return;
+		}
+		let mut end_positions = HashMap::new();
+		end_positions.insert(axis, position);
+		self.queue_block(BlockKind::SingleAxis(axis), false, end_positions, speed);
 	}
 
-	pub fn move_relative(&mut self, axis: Axis, distance: f64, speed: f64) {
-		println!("Moving {:#?} by {}", axis, distance);
-		// FIXME: should not move if endstop is already hit; seems like we take a step or two to recognize it.
-		self.get_controller_mut(axis).start_move_rel(distance, speed);
+	pub fn move_relative(&mut self, axis: Axis, distance: f64, speed: f64, square: bool) {
+		println!("Moving {:#?} by {}{}", axis, distance, if square { ", squaring" } else { "" });
+		if !self.has_axis(axis) {
+			self.send_move_rejected(BlockKind::SingleAxis(axis), axis, MoveRejectionReason::InvalidMove);
+			return;
+		}
+		// Relative to wherever the moves before it leave the axis
+		let position = self.get_planned_position(axis) + distance;
+		let mut end_positions = HashMap::new();
+		end_positions.insert(axis, position);
+		self.queue_block(BlockKind::SingleAxis(axis), square, end_positions, speed);
 	}
 
-	pub fn stop_all(&mut self) {
-		self.x_controller.stop_move();
-		self.y_controller.stop_move();
-		self.z_controller.stop_move();
-		self.set_spindle_on(false);
+	/// Start moving an axis towards the end of its travel, for as long as the client keeps the jog alive
+	pub fn start_jog(&mut self, axis: Axis, direction: AxisEnd, speed: f64) {
+		if let Some(jog) = &mut self.jog {
+			if jog.axis == axis && jog.direction == direction {
+				// Already going that way, so just take it as a keepalive
+				jog.last_keepalive = Instant::now();
+				return;
+			}
+		}
+		println!("Jogging {} towards {} at speed {}", axis, direction, speed);
+		if !self.has_axis(axis) {
+			self.send_move_rejected(BlockKind::Jog(axis), axis, MoveRejectionReason::InvalidMove);
+			return;
+		}
+		if !self.blocks.is_empty() {
+			println!("Rejecting jog, other moves are still going");
+			self.send_move_rejected(BlockKind::Jog(axis), axis, MoveRejectionReason::Busy);
+			return;
+		}
+		let position = self.get_position(axis);
+		let sign = if direction == AxisEnd::Max { 1.0 } else { -1.0 };
+		// As far as the soft limits allow, or a long way if there aren't any yet
+		let target = match self.soft_limits.get(&axis) {
+			Some(limits) => if direction == AxisEnd::Max { limits.max } else { limits.min },
+			None => position + sign * JOG_DISTANCE,
+		};
+		if (target - position) * sign <= 0.0 {
+			println!("Rejecting jog, axis {} is already at its soft limit", axis);
+			self.send_move_rejected(BlockKind::Jog(axis), axis, MoveRejectionReason::SoftLimit);
+			return;
+		}
+		let mut end_positions = HashMap::new();
+		end_positions.insert(axis, target);
+		self.queue_block(BlockKind::Jog(axis), false, end_positions, speed);
+		if self.is_jogging() {
+			self.jog = Some(Jog{axis, direction, last_keepalive: Instant::now()});
+		}
+	}
+
+	fn jog_keepalive(&mut self) {
+		if let Some(jog) = &mut self.jog {
+			jog.last_keepalive = Instant::now();
+		}
+	}
+
+	/// Bring the jog to a controlled stop, by cutting its block short where the axis can have slowed down to a stop
+	pub fn stop_jog(&mut self) {
+		let jog = match self.jog.take() {
+			Some(jog) => jog,
+			None => return,
+		};
+		println!("Stopping jog of axis {}", jog.axis);
+		let index = match self.blocks.iter().position(|block| matches!(block.kind, BlockKind::Jog(_))) {
+			Some(index) => index,
+			None => return,
+		};
+		if index > self.planning_index {
+			// Still taking up the backlash before it, so the jog never gets going
+			self.blocks[index].cut_short(0.0);
+		} else if index == self.planning_index {
+			// Only the part the planner hasn't got to yet can be changed, so start slowing down from there
+			let block = &mut self.blocks[index];
+			let length = self.planning_distance + block.limits.distance_to_slow_to(self.path_state, 0.0);
+			block.cut_short(length);
+		}
+		// Otherwise the planner has already got to the end of the block, so it's stopping anyway
+	}
+
+	/// Whether the jog's block is still queued
+	fn is_jogging(&self) -> bool {
+		self.blocks.iter().any(|block| matches!(block.kind, BlockKind::Jog(_)))
 	}
 
-	fn set_spindle_on(&mut self, on: bool) -> Result<(), <CdevPin as embedded_hal::digital::OutputPin>::Error> {
-		if on {
-			self.spindle_pin.try_set_high()
+	/// Forget the jog once it's over, e.g. because it reached an endstop, and stop it if the client has stopped keeping it alive
+	fn update_jog(&mut self) {
+		let timed_out = match &self.jog {
+			Some(jog) => jog.last_keepalive.elapsed() > self.jog_keepalive_timeout,
+			None => return,
+		};
+		if !self.is_jogging() {
+			self.jog = None;
+		} else if timed_out {
+			println!("Jog keepalive timed out");
+			self.stop_jog();
+		}
+	}
+
+	pub fn start_linear_move(&mut self, positions: &HashMap<Axis, f64>, speed: f64) {
+		println!("Moving to {:?}", positions);
+		self.queue_block(BlockKind::Linear, false, positions.clone(), speed);
+	}
+
+	/// Add a move to the end of the queue, starting from wherever the moves before it finish
+	fn queue_block(&mut self, kind: BlockKind, squaring: bool, end_positions: HashMap<Axis, f64>, speed: f64) {
+		if let (Some(jog), false) = (&self.jog, matches!(kind, BlockKind::Jog(_))) {
+			// The jog has no set end, so anything queued behind it would only start whenever the jog happened to stop
+			let axis = jog.axis;
+			println!("Rejecting move, axis {} is jogging", axis);
+			self.send_move_rejected(kind, axis, MoveRejectionReason::Busy);
+			return;
+		}
+		if let Err(axis) = self.check_move(&end_positions, speed) {
+			println!("Rejecting invalid move of axis {} to {:?} at speed {}", axis, end_positions.get(&axis), speed);
+			self.send_move_rejected(kind, axis, MoveRejectionReason::InvalidMove);
+			return;
+		}
+		let mut start_positions = HashMap::new();
+		for axis in end_positions.keys() {
+			start_positions.insert(*axis, self.get_planned_position(*axis));
+		}
+		let end_positions = match self.apply_soft_limits(&start_positions, end_positions) {
+			Ok(end_positions) => end_positions,
+			Err(axis) => {
+				println!("Rejecting move, axis {} would leave its soft limits", axis);
+				self.send_move_rejected(kind, axis, MoveRejectionReason::SoftLimit);
+				return;
+			},
+		};
+		let mut block = Block::new(kind, squaring, start_positions, end_positions, speed, &self.motor_configs);
+		if let Some(endstop) = self.find_hit_endstop(&block) {
+			println!("Rejecting move, endstop {} {} is already hit", endstop.axis, endstop.position);
+			self.send_move_rejected(kind, endstop.axis, MoveRejectionReason::EndstopHit);
+			return;
+		}
+		if let Some(axis) = block.moving_axes().into_iter().find(|axis| self.following_error_alarms.contains(axis)) {
+			println!("Rejecting move, axis {} has a following error alarm", axis);
+			self.send_move_rejected(kind, axis, MoveRejectionReason::FollowingError);
+			return;
+		}
+		if self.step_generator_failed {
+			println!("Rejecting move, the step generator has stopped working");
+			if let Some(axis) = block.report_axis() {
+				self.send_move_rejected(kind, axis, MoveRejectionReason::StepGeneratorFailed);
+			}
+			return;
+		}
+		self.queue_backlash_take_up(&block);
+		if let Some(previous) = self.blocks.back() {
+			block.max_entry_velocity = block.junction_velocity(previous, self.junction_deviation);
+		}
+		for axis in block.moving_axes() {
+			self.freed_axes.remove(&axis);
+			for controller in self.get_controllers_mut(axis).iter_mut() {
+				if let Err(error) = controller.set_active() {
+					println!("Encountered error enabling axis {}, error is {:?}", axis, error);
+				}
+			}
+		}
+		self.blocks.push_back(block);
+	}
+
+	/// Queue a short move of the motors of any axes the block reverses, to take up the slack in their leadscrews before it.
+	/// It's planned like any other block, so the motors speed up no faster than the axes can, and carry on into the block without stopping.
+	fn queue_backlash_take_up(&mut self, block: &Block) {
+		let mut start_positions = HashMap::new();
+		let mut end_positions = HashMap::new();
+		for axis in block.moving_axes() {
+			let backlash_steps = self.backlash_steps[&axis];
+			if backlash_steps == 0 {
+				continue;
+			}
+			let taken_up = if block.get_direction(axis) == AxisEnd::Max { backlash_steps } else { 0 };
+			let slack = match self.slack.insert(axis, taken_up) {
+				Some(slack) => slack,
+				None => {
+					// First move of the axis, so take the slack to have been taken up this way already
+					self.step_generator.set_slack(axis, taken_up);
+					taken_up
+				},
+			};
+			if slack != taken_up {
+				start_positions.insert(axis, 0.0);
+				end_positions.insert(axis, ((taken_up - slack) as f64) / self.motor_configs[&axis].steps_per_unit());
+			}
+		}
+		if start_positions.is_empty() {
+			return;
+		}
+		let mut take_up = Block::new(BlockKind::Backlash, false, start_positions, end_positions, block.speed, &self.motor_configs);
+		if let Some(previous) = self.blocks.back() {
+			take_up.max_entry_velocity = take_up.junction_velocity(previous, self.junction_deviation);
+		}
+		self.blocks.push_back(take_up);
+	}
+
+	/// Make sure a move can actually be made: every position has to be a step we can count to, and the speed has to get us there. Returns the first axis that's wrong if not.
+	fn check_move(&self, end_positions: &HashMap<Axis, f64>, speed: f64) -> Result<(), Axis> {
+		let speed_valid = speed.is_finite() && speed > 0.0;
+		for (axis, position) in end_positions.iter() {
+			if !self.has_axis(*axis) {
+				return Err(*axis);
+			}
+			if let Err(error) = self.motor_configs[axis].position_to_steps(*position) {
+				println!("Can't move axis {} to {}: {}", axis, position, error);
+				return Err(*axis);
+			}
+			if !speed_valid {
+				return Err(*axis);
+			}
+		}
+		Ok(())
+	}
+
+	/// Step count of a position that's already been checked by check_move(), or lies between ones that have
+	fn position_to_step(&self, axis: Axis, position: f64) -> i64 {
+		self.motor_configs[&axis].position_to_steps(position).expect("Position was checked when the move was queued")
+	}
+
+	/// Check a move against the soft limits, returning the end positions to use (cut short where it reaches a limit, if we're clamping), or the axis that would have left its limits if the move is rejected.
+	/// Comparisons are done in steps, so a move to exactly the limit is always allowed. So is a move back towards the limits from outside them.
+	fn apply_soft_limits(&self, start_positions: &HashMap<Axis, f64>, end_positions: HashMap<Axis, f64>) -> Result<HashMap<Axis, f64>, Axis> {
+		// Fraction of the move we can make before the first axis reaches its limit
+		let mut allowed_fraction: f64 = 1.0;
+		let mut limiting_axis = None;
+		for (axis, end) in end_positions.iter() {
+			let limits = match self.soft_limits.get(axis) {
+				Some(limits) => limits,
+				None => continue,
+			};
+			let start = start_positions[axis];
+			let end_step = self.position_to_step(*axis, *end);
+			let limit = if end_step > self.position_to_step(*axis, limits.max) && *end > start {
+				limits.max
+			} else if end_step < self.position_to_step(*axis, limits.min) && *end < start {
+				limits.min
+			} else {
+				continue;
+			};
+			let fraction = ((limit - start) / (end - start)).max(0.0);
+			if fraction < allowed_fraction {
+				allowed_fraction = fraction;
+				limiting_axis = Some(*axis);
+			}
+		}
+
+		match (limiting_axis, self.soft_limit_mode) {
+			(None, _) => Ok(end_positions),
+			(Some(axis), SoftLimitMode::Reject) => Err(axis),
+			(Some(axis), SoftLimitMode::Clamp) => {
+				println!("Clamping move to the soft limits of axis {}", axis);
+				// Cut the move short along its line, so a linear move keeps its direction
+				Ok(end_positions.iter()
+					.map(|(axis, end)| {
+						let start = start_positions[axis];
+						(*axis, start + (end - start) * allowed_fraction)
+					})
+					.collect())
+			},
+		}
+	}
+
+	/// Endstop that a new block would move straight into, because it's already hit. Moving away from a hit endstop is fine.
+	/// A squaring block is only stopped by this if every motor's endstop is hit, since the rest of the motors still have somewhere to go.
+	/// Only axes that no queued block moves are checked, since we can't tell yet whether the endstops will still be hit by the time the block starts. Those are left to check_endstops().
+	fn find_hit_endstop(&self, block: &Block) -> Option<EndstopIdentifier> {
+		block.moving_axes().into_iter()
+			.filter(|axis| !self.blocks.iter().any(|queued| queued.moving_axes().contains(axis)))
+			.filter_map(|axis| {
+				let endstops = self.get_axis_endstops(axis, block.get_direction(axis));
+				let hit_endstop = endstops.iter().find(|(_, hit)| *hit).map(|(endstop, _)| *endstop)?;
+				if block.squaring && !endstops.iter().all(|(_, hit)| *hit) {
+					None
+				} else {
+					Some(hit_endstop)
+				}
+			})
+			.next()
+	}
+
+	/// The endstops at one end of an axis, one for each motor that has one there, and whether they're hit
+	fn get_axis_endstops(&self, axis: Axis, end: AxisEnd) -> Vec<(EndstopIdentifier, bool)> {
+		self.motor_configs[&axis].motors().into_iter()
+			.map(|motor| EndstopIdentifier::for_motor(axis, end, motor))
+			.filter_map(|endstop| self.endstop_status_client.is_endstop_hit(endstop).map(|hit| (endstop, *hit)))
+			.collect()
+	}
+
+	pub fn set_soft_limits(&mut self, mut limits: HashMap<Axis, AxisLimits>) {
+		let motor_configs = &self.motor_configs;
+		limits.retain(|axis, limits| {
+			let valid = match motor_configs.get(axis) {
+				Some(config) => config.position_to_steps(limits.min).is_ok() && config.position_to_steps(limits.max).is_ok(),
+				None => false,
+			};
+			if !valid {
+				println!("Ignoring invalid soft limits {:?} for axis {}", limits, axis);
+			}
+			valid
+		});
+		println!("Setting soft limits to {:?}", limits);
+		self.soft_limits = limits;
+	}
+
+	pub fn set_axis_locked(&mut self, axis: Axis, locked: bool) {
+		println!("{} axis {}", if locked { "Locking" } else { "Freeing" }, axis);
+		if !self.has_axis(axis) {
+			return;
+		}
+		if !locked && self.blocks.iter().any(|block| block.uses_axis(axis)) {
+			// Can't move the axis once it's freed, so cancel any moves that need it
+			println!("Cancelling moves of axis {}", axis);
+			self.flush_step_generator();
+			let (cancelled, blocks): (VecDeque<Block>, VecDeque<Block>) = self.blocks.drain(..).partition(|block| block.uses_axis(axis));
+			self.blocks = blocks;
+			self.replan_blocks();
+			for block in cancelled {
+				self.send_move_rejected(block.kind, axis, MoveRejectionReason::AxisFreed);
+			}
+		}
+		if !locked {
+			// It can be moved by hand now, so nothing we know about where it is can be relied on, or which way its slack was taken up
+			self.machine_state.set_position_trusted(false);
+			self.slack.remove(&axis);
+			self.freed_axes.insert(axis);
 		} else {
-			self.spindle_pin.try_set_low()
+			self.freed_axes.remove(&axis);
+		}
+		for controller in self.get_controllers_mut(axis).iter_mut() {
+			if let Err(error) = controller.set_locked(locked) {
+				println!("Encountered error setting lock on axis {}, error is {:?}", axis, error);
+			}
+		}
+	}
+
+	/// Bring all moves to a controlled stop, to be carried on by resume().
+	/// Moves started while held wait for the resume too. The spindle is left running.
+	pub fn feed_hold(&mut self) {
+		println!("Feed hold");
+		self.feed_held = true;
+	}
+
+	pub fn resume(&mut self) {
+		println!("Resuming from feed hold");
+		self.feed_held = false;
+	}
+
+	pub fn set_feed_override(&mut self, percent: f64) {
+		if !percent.is_finite() {
+			println!("Ignoring invalid feed override {}", percent);
+			return;
+		}
+		let percent = percent.clamp(MIN_FEED_OVERRIDE_PERCENT, MAX_FEED_OVERRIDE_PERCENT);
+		println!("Setting feed override to {}%", percent);
+		self.feed_override = percent / 100.0;
+	}
+
+	/// Stop immediately, without decelerating, and forget all queued moves
+	pub fn stop_all(&mut self) {
+		if !self.step_generator.is_idle() {
+			// Stopping dead from speed can make the motors skip steps, so the axes may not be where we think
+			println!("Stopped while moving, so the positions can't be trusted");
+			self.machine_state.set_position_trusted(false);
+		}
+		if !self.flush_step_generator() {
+			return;
+		}
+		self.blocks.clear();
+		self.jog = None;
+		self.sync_with_step_generator();
+		// Nothing left to resume, so this also clears any feed hold
+		self.feed_held = false;
+		if let Err(error) = self.set_spindle_on(false, None) {
+			println!("Encountered error turning off the spindle, error is {:?}", error);
+		}
+	}
+
+	/// Throw away everything the step generator has buffered. If it's stopped working, everything is stopped for good instead, and false is returned.
+	fn flush_step_generator(&mut self) -> bool {
+		if self.step_generator_failed {
+			// Already stopped everything, and there's no point waiting on it again
+			return false;
+		}
+		match self.step_generator.flush() {
+			Ok(()) => true,
+			Err(error) => {
+				self.handle_step_generator_failure(error);
+				false
+			},
+		}
+	}
+
+	/// The step generator has died or got stuck, so the motors can't be relied on to do anything, and nothing can move again until we're restarted.
+	/// Every queued move is rejected, which stops whatever the operation controller was doing.
+	fn handle_step_generator_failure(&mut self, error: HardwareError) {
+		println!("Step generator has stopped working, so stopping everything. Error is {:?}", error);
+		self.step_generator_failed = true;
+		self.machine_state.set_position_trusted(false);
+		self.jog = None;
+		self.feed_held = false;
+		for block in self.blocks.drain(..).collect::<Vec<Block>>() {
+			if let Some(axis) = block.report_axis() {
+				self.send_move_rejected(block.kind, axis, MoveRejectionReason::StepGeneratorFailed);
+			}
+		}
+		self.sync_with_step_generator();
+		if let Err(error) = self.set_spindle_on(false, None) {
+			println!("Encountered error turning off the spindle, error is {:?}", error);
+		}
+	}
+
+	/// Start planning again from where the step generator actually is, e.g. after a flush. Any held motors move with their axes again.
+	fn sync_with_step_generator(&mut self) {
+		self.planning_index = 0;
+		self.planning_distance = 0.0;
+		self.path_state = PathState::default();
+		for axis in self.motor_configs.keys() {
+			self.planned_steps.insert(*axis, self.step_generator.get_position(*axis));
+		}
+		for (axis, slack) in self.slack.iter_mut() {
+			*slack = self.step_generator.get_slack(*axis);
+		}
+		for (axis, motor) in self.held_motors.drain() {
+			self.step_generator.set_held(axis, motor, false);
+		}
+		self.squaring_start_offset = None;
+	}
+
+	/// Re-queue all the blocks from where the axes actually are, after the moves before them were cut short. Backlash is taken up again as needed.
+	fn replan_blocks(&mut self) {
+		self.sync_with_step_generator();
+		let blocks: Vec<Block> = self.blocks.drain(..).collect();
+		for block in blocks {
+			if let BlockKind::Backlash = block.kind {
+				continue;
+			}
+			self.queue_block(block.kind, block.squaring, block.end_positions, block.speed);
+		}
+	}
+
+	fn set_spindle_on(&mut self, on: bool, rpm: Option<f64>) -> Result<(), HardwareError> {
+		self.spindle.set(on, rpm)
+	}
+
+	/// Switch the spindle as asked. If the driver can't be told, the spindle can't be relied on, so that's a fault.
+	fn handle_spindle_control(&mut self, msg: SpindleControlMsg) {
+		if let Err(error) = self.set_spindle_on(msg.on, msg.rpm) {
+			println!("Encountered error setting spindle, error is {:?}", error);
+			self.send(Message::SpindleFaultMsgType(SpindleFaultMsg{fault: SpindleFault::DriverError}));
 		}
 	}
 
@@ -314,98 +963,846 @@ impl MotorsControl {
 
 	fn handle_message(&mut self, msg : Message) {
 		match msg {
+			Message::AxisLockMsgType(al_msg) => self.set_axis_locked(al_msg.axis, al_msg.locked),
+			Message::DriverFaultMsgType(df_msg) => self.handle_driver_fault(df_msg),
+			Message::EncoderPositionMsgType(ep_msg) => self.handle_encoder_positions(ep_msg.positions),
 			Message::EndstopHitMsgType(eh_msg) => self.endstop_status_client.process_message(eh_msg),
+			Message::FeedHoldMsgType() => self.feed_hold(),
+			Message::FeedOverrideMsgType(fo_msg) => self.set_feed_override(fo_msg.percent),
 			Message::GoToPositionMsgType(gtp_msg) => self.go_to_position(gtp_msg.axis, gtp_msg.position, gtp_msg.speed),
-			Message::MoveAxisRelMsgType(mar_msg) => self.move_relative(mar_msg.axis, mar_msg.distance, mar_msg.speed),
-			Message::SpindleControlMsgType(sc_msg) => self.set_spindle_on(sc_msg.on).unwrap(),
+			Message::JogKeepaliveMsgType() => self.jog_keepalive(),
+			Message::LinearMoveMsgType(lm_msg) => self.start_linear_move(&lm_msg.positions, lm_msg.speed),
+			Message::MoveAxisRelMsgType(mar_msg) => self.move_relative(mar_msg.axis, mar_msg.distance, mar_msg.speed, mar_msg.square),
+			Message::ResetFollowingErrorMsgType(rfe_msg) => self.reset_following_error(rfe_msg.axis),
+			Message::ResumeMsgType() => self.resume(),
+			Message::SoftLimitsMsgType(sl_msg) => self.set_soft_limits(sl_msg.limits),
+			Message::SpindleControlMsgType(sc_msg) => self.handle_spindle_control(sc_msg),
+			Message::SpindleSpeedMsgType(ss_msg) => self.spindle.handle_tachometer_speed(ss_msg.rpm),
+			Message::StartJogMsgType(sj_msg) => self.start_jog(sj_msg.axis, sj_msg.direction, sj_msg.speed),
+			Message::StopJogMsgType() => self.stop_jog(),
 			Message::StopMsgType() => self.stop_all(),
 
 			_ => {},
 		};
 	}
 
+	/// Stop everything if a stepper driver has a fault that means its motor won't follow its steps
+	fn handle_driver_fault(&mut self, msg: DriverFaultMsg) {
+		if msg.faults.iter().any(|fault| fault.stops_motion()) {
+			println!("Stopping for driver faults {:?} on axis {} {} motor", msg.faults, msg.axis, msg.motor);
+			self.stop_all();
+			// Its motor may have lost steps or been turned by hand, even if it wasn't moving
+			self.machine_state.set_position_trusted(false);
+		}
+	}
+
+	/// Check the positions measured by the encoders against the commanded ones, stopping everything if an axis is too far off
+	fn handle_encoder_positions(&mut self, positions: HashMap<Axis, f64>) {
+		for (axis, measured) in positions {
+			let limit = match self.encoder_configs.get(&axis) {
+				Some(encoder_config) => encoder_config.following_error_limit,
+				None => {
+					println!("Ignoring encoder position of axis {}, which has no encoder", axis);
+					continue;
+				},
+			};
+			self.measured_positions.insert(axis, measured);
+			if self.freed_axes.contains(&axis) {
+				// It's been freed to be moved by hand, so take the encoder's word for where it is
+				self.set_commanded_position(axis, measured);
+				continue;
+			}
+			let commanded = self.get_position(axis);
+			if (measured - commanded).abs() > limit && !self.following_error_alarms.contains(&axis) {
+				println!("Following error on axis {}: commanded {}, measured {}", axis, commanded, measured);
+				self.stop_all();
+				self.machine_state.set_position_trusted(false);
+				self.following_error_alarms.insert(axis);
+				self.send(Message::FollowingErrorMsgType(FollowingErrorMsg{axis, commanded, measured}));
+			}
+		}
+	}
+
+	/// Clear an axis's following error alarm, carrying on from where its encoder says it is
+	fn reset_following_error(&mut self, axis: Axis) {
+		let measured = match self.measured_positions.get(&axis) {
+			Some(measured) => *measured,
+			None => {
+				println!("Axis {} has no encoder, so no following error to reset", axis);
+				return;
+			},
+		};
+		println!("Resetting following error on axis {}", axis);
+		self.set_commanded_position(axis, measured);
+		self.following_error_alarms.remove(&axis);
+	}
+
+	/// Make the nearest step to the given position the current one, without moving. Only for axes that no queued move uses.
+	fn set_commanded_position(&mut self, axis: Axis, position: f64) {
+		if self.blocks.iter().any(|block| block.uses_axis(axis)) {
+			println!("Can't set the position of axis {} while it's moving", axis);
+			return;
+		}
+		match self.motor_configs[&axis].position_to_steps(position) {
+			Ok(steps) => {
+				self.step_generator.set_position(axis, steps);
+				self.planned_steps.insert(axis, steps);
+			},
+			Err(error) => println!("Can't set axis {} to {}: {}", axis, position, error),
+		}
+	}
+
+	/// Cut the running move short if it's run into an endstop, carrying on with the rest of the queue from there.
+	/// In a squaring move, each motor is held as it reaches its own endstop, and the move only stops once they all have.
 	fn check_endstops(&mut self) {
-		self.check_endstops_for_axis(Axis::X);
-		self.check_endstops_for_axis(Axis::Y);
-		self.check_endstops_for_axis(Axis::Z);
+		let (squaring, directions): (bool, Vec<(Axis, AxisEnd)>) = match self.blocks.front() {
+			Some(block) => (block.squaring, block.moving_axes().into_iter().map(|axis| (axis, block.get_direction(axis))).collect()),
+			None => return,
+		};
+		for (axis, direction) in directions {
+			let endstops = self.get_axis_endstops(axis, direction);
+			let all_hit = !endstops.is_empty() && endstops.iter().all(|(_, hit)| *hit);
+			if squaring && !all_hit {
+				for (endstop, hit) in endstops {
+					if hit && self.held_motors.insert((axis, endstop.motor)) {
+						println!("Hit endstop {} {} with the {} motor, holding it while the axis squares itself", axis, direction, endstop.motor);
+						self.squaring_start_offset.get_or_insert(self.step_generator.get_slave_offset(axis));
+						self.step_generator.set_held(axis, endstop.motor, true);
+					}
+				}
+				if self.check_squaring_distance(axis) {
+					return;
+				}
+			} else if endstops.iter().any(|(_, hit)| *hit) {
+				println!("Hit endstop {} {}", axis, direction);
+				if squaring {
+					self.log_squaring_correction(axis);
+				}
+				if !self.flush_step_generator() {
+					return;
+				}
+				let block = self.blocks.pop_front().unwrap();
+				self.replan_blocks();
+				self.send_move_complete(block.kind, true);
+				return;
+			}
+		}
+	}
+
+	/// Stop everything if one motor of an axis has gone further on its own than it should have while squaring. Returns whether it has.
+	fn check_squaring_distance(&mut self, axis: Axis) -> bool {
+		let (start_offset, slave) = match (self.squaring_start_offset, &self.motor_configs[&axis].slave) {
+			(Some(start_offset), Some(slave)) => (start_offset, slave),
+			_ => return false,
+		};
+		let distance = ((self.step_generator.get_slave_offset(axis) - start_offset) as f64) / self.motor_configs[&axis].steps_per_unit();
+		if distance.abs() <= slave.max_squaring_distance {
+			return false;
+		}
+		println!("Squaring axis {} went {} out of line, stopping", axis, distance);
+		let kind = self.blocks.front().unwrap().kind;
+		self.stop_all();
+		self.send_move_rejected(kind, axis, MoveRejectionReason::SquaringLimit);
+		true
+	}
+
+	/// Log how far squaring an axis moved its motors relative to each other
+	fn log_squaring_correction(&self, axis: Axis) {
+		if let Some(start_offset) = self.squaring_start_offset {
+			let distance = ((self.step_generator.get_slave_offset(axis) - start_offset) as f64) / self.motor_configs[&axis].steps_per_unit();
+			println!("Squared axis {}, moving the slave motor {} relative to the main one", axis, distance);
+		}
+	}
+
+	fn send(&self, msg: Message) {
+		if self.sender.send(msg).is_err() {
+			println!("Nothing is listening to motor control");
+		}
+	}
+
+	fn send_move_complete(&mut self, kind: BlockKind, endstop_hit: bool) {
+		match kind {
+			BlockKind::SingleAxis(axis) | BlockKind::Jog(axis) => {
+				println!("Sending MovementComplete for {}", axis);
+				let msg = MovementCompleteMsg{axis, endstop_hit};
+				self.send(Message::MovementCompleteMsgType(msg));
+			},
+			BlockKind::Linear => {
+				println!("Sending LinearMoveComplete");
+				let msg = LinearMoveCompleteMsg{endstop_hit};
+				self.send(Message::LinearMoveCompleteMsgType(msg));
+			},
+			// Reported along with the block it's part of
+			BlockKind::Backlash => {},
+		}
+	}
+
+	fn send_move_rejected(&mut self, kind: BlockKind, axis: Axis, reason: MoveRejectionReason) {
+		let move_axis = match kind {
+			BlockKind::SingleAxis(move_axis) | BlockKind::Jog(move_axis) => Some(move_axis),
+			BlockKind::Linear | BlockKind::Backlash => None,
+		};
+		let msg = MoveRejectedMsg{move_axis, axis, reason};
+		self.send(Message::MoveRejectedMsgType(msg));
+	}
+
+	/// Report the moves the step generator has finished
+	fn update_completed_blocks(&mut self) {
+		while let Some(block) = self.blocks.front() {
+			match block.last_segment_id {
+				Some(segment_id) if self.step_generator.is_executed(segment_id) && !self.held_motors.is_empty() => {
+					// A squaring move ran out before all the motors got to their endstops. The held ones didn't follow the plan, so start again from where they are.
+					if !self.flush_step_generator() {
+						return;
+					}
+					let block = self.blocks.pop_front().unwrap();
+					self.replan_blocks();
+					self.send_move_complete(block.kind, false);
+				},
+				Some(segment_id) if self.step_generator.is_executed(segment_id) => {
+					let block = self.blocks.pop_front().unwrap();
+					self.planning_index -= 1;
+					self.send_move_complete(block.kind, false);
+				},
+				_ => break,
+			}
+		}
+	}
+
+	/// Fastest we can be going at the end of the given block, and still be able to slow down for all the corners after it, and stop at the end of the queue
+	fn get_exit_velocity(&self, index: usize) -> f64 {
+		let mut velocity = 0.0;
+		for block in self.blocks.iter().skip(index + 1).rev() {
+			let max_velocity = if self.feed_held { 0.0 } else { block.speed * self.feed_override };
+			velocity = block.limits.max_velocity_before(velocity, block.length)
+				.min(max_velocity)
+				.min(block.max_entry_velocity);
+		}
+		velocity
 	}
 
-	fn check_endstops_for_axis(&mut self, axis: Axis) {
-		let current_direction = self.get_controller_mut(axis).get_direction();
-		let endstop_hit = self.endstop_status_client.is_endstop_hit(EndstopIdentifier::new(axis, current_direction)).copied();
-		if endstop_hit.contains(&true) {
-			if self.get_controller_mut(axis).is_movement_in_progress() {
-				println!("Hit endstop {} {}", axis, current_direction);
+	/// Keep the step generator's buffer full
+	fn update_step_schedule(&mut self) {
+		loop {
+			match self.step_generator.is_ready() {
+				Ok(true) => {},
+				Ok(false) => return,
+				Err(error) => return self.handle_step_generator_failure(error),
+			}
+			if !self.plan_segment() {
+				return;
+			}
+		}
+	}
+
+	/// Work out the next segment of the step schedule and send it to the step generator. Returns false if there's nothing to do.
+	fn plan_segment(&mut self) -> bool {
+		if self.planning_index >= self.blocks.len() {
+			return false;
+		}
+		let target_velocity = if self.feed_held { 0.0 } else { self.blocks[self.planning_index].speed * self.feed_override };
+		if target_velocity == 0.0 && self.path_state.velocity == 0.0 {
+			// Stopped by a feed hold
+			return false;
+		}
+		let exit_velocity = self.get_exit_velocity(self.planning_index);
+		let block = &self.blocks[self.planning_index];
+
+		let mut steps = Vec::new();
+		let mut time = 0.0;
+		let mut finished = false;
+		while time < SEGMENT_DURATION {
+			let remaining = block.length - self.planning_distance;
+			if remaining <= 0.0 {
+				finished = true;
+				break;
+			}
+			let mut time_step = PLANNER_TIME_STEP;
+			let mut distance = block.limits.advance(&mut self.path_state, target_velocity, exit_velocity, remaining, time_step);
+			if distance >= remaining {
+				// Only part of the time step to get to the end
+				time_step *= remaining / distance;
+				distance = remaining;
+			}
+			let previous_distance = self.planning_distance;
+			self.planning_distance += distance;
 
-				self.get_controller_mut(axis).stop_move();
+			let takes_up_backlash = matches!(block.kind, BlockKind::Backlash);
+			for axis in block.moving_axes() {
+				let (step, previous_step) = if takes_up_backlash {
+					// Counted from the start of the block, since the axis's logical position doesn't change
+					let config = &self.motor_configs[&axis];
+					let slack_step = |distance| config.distance_to_steps(block.position_at(axis, distance)).expect("Take-up was worked out in whole steps");
+					(slack_step(self.planning_distance), slack_step(previous_distance))
+				} else {
+					let step = self.position_to_step(axis, block.position_at(axis, self.planning_distance));
+					(step, self.planned_steps.insert(axis, step).unwrap())
+				};
+				let count = (step - previous_step).abs();
+				let direction = if step < previous_step { AxisEnd::Min } else { AxisEnd::Max };
+				// Spread the steps evenly over the time step
+				for i in 0..count {
+					let offset = time + time_step * (i as f64 + 0.5) / (count as f64);
+					steps.push(StepEvent{offset: Duration::from_secs_f64(offset), axis, direction, takes_up_backlash});
+				}
+			}
+			time += time_step;
+			if self.path_state.velocity == 0.0 {
+				// Came to a stop for a feed hold
+				break;
+			}
+		}
+		steps.sort_by_key(|step| step.offset);
+
+		let segment_id = match self.step_generator.send(Duration::from_secs_f64(time), steps) {
+			Ok(segment_id) => segment_id,
+			Err(error) => {
+				self.handle_step_generator_failure(error);
+				return false;
+			},
+		};
+		if finished || self.planning_distance >= block.length {
+			self.blocks[self.planning_index].last_segment_id = Some(segment_id);
+			self.planning_index += 1;
+			self.planning_distance = 0.0;
+			if self.planning_index >= self.blocks.len() {
+				// End of the queue, so we've stopped
+				self.path_state = PathState::default();
+			}
+		}
+		true
+	}
 
-				println!("Endstop hit; sending MovementComplete for {}", axis);
-				let msg = MovementCompleteMsg{ axis, endstop_hit: true};
-				self.sender.send(Message::MovementCompleteMsgType(msg));
+	/// Keep motors that are in use energized, and de-energize the ones that have been idle for a while
+	fn update_idle_motors(&mut self) {
+		for (axis, controllers) in self.controllers.iter_mut() {
+			let in_use = self.blocks.iter().any(|block| block.moving_axes().contains(axis));
+			let idle_timeout = self.motor_idle_timeout;
+			for controller in controllers.iter_mut() {
+				let result = if in_use {
+					controller.set_active()
+				} else {
+					controller.update_idle(idle_timeout)
+				};
+				if let Err(error) = result {
+					println!("Encountered error updating enable pin for axis {}, error is {:?}", axis, error);
+				}
 			}
 		}
 	}
 
 	fn send_position_update(&mut self) {
 		let msg = CurrentPositionMsg{
-			x: self.x_controller.get_position(),
-			y: self.y_controller.get_position(),
-			z: self.z_controller.get_position(),
+			positions: self.motor_configs.keys().map(|axis| (*axis, self.get_position(*axis))).collect(),
+			measured_positions: self.measured_positions.clone(),
 		};
 		if msg != self.last_position_msg {
-			self.sender.send(Message::CurrentPositionMsgType(msg));
+			self.send(Message::CurrentPositionMsgType(msg.clone()));
 			self.last_position_msg = msg;
+			self.machine_state.set_axes(self.motor_configs.iter().map(|(axis, motor_config)| (*axis, AxisState {
+				steps: self.step_generator.get_position(*axis),
+				slave_offset: self.step_generator.get_slave_offset(*axis),
+				steps_per_unit: motor_config.steps_per_unit(),
+				encoder_position: self.measured_positions.get(axis).copied(),
+			})).collect());
 		}
 	}
 
-	fn update_motor(&mut self, axis: Axis) {
-		let prev_movement_in_progress = self.get_controller_mut(axis).is_movement_in_progress();
-		match self.get_controller_mut(axis).update() {
-			Ok(ongoing) => {
-				if !ongoing && prev_movement_in_progress {
-					println!("Sending MovementComplete for {}", axis);
-					let msg = MovementCompleteMsg{ axis, endstop_hit: false};
-					self.sender.send(Message::MovementCompleteMsgType(msg));
-				}
-			},
-			Err(error) => println!("Encountered error updating axis {}, error is {:?}", axis, error),
+	/// Let the operator see how well the step generator is keeping to the schedule
+	fn report_step_timing(&mut self) {
+		if self.last_step_timing_report.elapsed() < STEP_TIMING_REPORT_INTERVAL {
+			return;
 		}
-	}
-
-	fn update_controllers(&mut self) {
-		self.update_motor(Axis::X);
-		self.update_motor(Axis::Y);
-		self.update_motor(Axis::Z);
+		self.last_step_timing_report = Instant::now();
+		let timing = self.step_generator.take_step_timing();
+		if timing.steps == 0 {
+			return;
+		}
+		let msg = StepTimingMsg {
+			steps: timing.steps,
+			mean_lateness_us: timing.mean_lateness.as_secs_f64() * 1e6,
+			max_lateness_us: timing.max_lateness.as_secs_f64() * 1e6,
+		};
+		println!("Step timing: {} steps, {:.1}us late on average, {:.1}us at worst", msg.steps, msg.mean_lateness_us, msg.max_lateness_us);
+		self.send(Message::StepTimingMsgType(msg));
 	}
 
 	pub fn run(&mut self) -> ! {
 		loop {
-			// Check for messages
-			loop {
-				match self.receiver.try_recv() {
-					Ok(msg) => self.handle_message(msg),
-					Err(TryRecvError::Empty) => break,
-					// If nothing is connected to send us messages, then nothing is in control of the motors, so shut down immediately.
-					// There is no way for anything to get connected again, either, so this whole thread is trash.
-					Err(TryRecvError::Disconnected) => self.shutdown(),
-				}
+			// Wait for messages, but not for so long that the step generator runs out of steps.
+			// No need to busy-wait, since the step generator takes care of the timing.
+			let wait_time = if self.blocks.is_empty() { IDLE_INTERVAL } else { PLANNER_INTERVAL };
+			match self.receiver.recv_timeout(wait_time) {
+				Ok(msg) => self.handle_message(msg),
+				Err(RecvTimeoutError::Timeout) => {},
+				// If nothing is connected to send us messages, then nothing is in control of the motors, so shut down immediately.
+				// There is no way for anything to get connected again, either, so this whole thread is trash.
+				Err(RecvTimeoutError::Disconnected) => self.shutdown(),
 			}
-			
+			// Handle everything else that's waiting, too
+			while let Ok(msg) = self.receiver.try_recv() {
+				self.handle_message(msg);
+			}
+
+			self.update_completed_blocks();
 			self.check_endstops();
-			self.update_controllers();
+			self.update_jog();
+			self.update_step_schedule();
+			self.update_idle_motors();
+			self.spindle.update();
 			self.send_position_update();
-
-			// TODO: should probably sleep for like a nanosecond or something so we're not always busy-waiting
-			// Or perhaps use yield_now() instead? I don't know...
+			self.report_step_timing();
 		}
 	}
 }
 
 
-pub fn init(initial_config : RustGrindConfig, receiver : Receiver<Message>, sender: Sender<Message>) {
+pub fn init(initial_config : RustGrindConfig, hardware: Arc<dyn HardwareBackend>, machine_state: MachineStateClient, receiver : Receiver<Message>, sender: Sender<Message>) {
 	let builder = thread::Builder::new().name("MotorControl".to_string());
 	builder.spawn(move || {
-		let mut main_motor_controller = MotorsControl::new(&initial_config, receiver, sender).unwrap();
+		let mut main_motor_controller = MotorsControl::new(&initial_config, hardware.as_ref(), machine_state, receiver, sender).unwrap();
 		main_motor_controller.run();
 	}).unwrap();
 }
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::ConfigManager;
+	use crate::config::SimulationConfig;
+	use crate::config::SlaveMotorConfig;
+	use crate::hardware::SimulatedBackend;
+	use crate::messages::EndstopHitMsg;
+
+	use std::sync::mpsc;
+
+	/// Linear move in the XY plane, with the axes set up as they are by default
+	fn xy_block(from: (f64, f64), to: (f64, f64)) -> Block {
+		let motor_configs = ConfigManager::new().get_config().motor_configs.clone();
+		let positions = |(x, y): (f64, f64)| vec![(Axis::X, x), (Axis::Y, y)].into_iter().collect();
+		Block::new(BlockKind::Linear, false, positions(from), positions(to), NANOMETRES_PER_INCH, &motor_configs)
+	}
+
+	/// Config for the default axes, with a step generator that can run without root
+	fn test_config() -> RustGrindConfig {
+		let mut config = ConfigManager::new().get_config().clone();
+		config.step_generator.realtime_priority = 0;
+		config.step_generator.lock_memory = false;
+		config
+	}
+
+	/// Motor control on simulated hardware, and the receiving end of everything it sends out.
+	/// Nothing runs it, so tests drive the planner themselves.
+	fn motors_control(config: &RustGrindConfig) -> (MotorsControl, Receiver<Message>) {
+		let hardware = SimulatedBackend::new(config, &SimulationConfig::new());
+		let (_, receiver) = mpsc::channel();
+		let (sender, sent) = mpsc::channel();
+		let motors_control = MotorsControl::new(config, &hardware, MachineStateClient::unsaved(), receiver, sender).unwrap();
+		(motors_control, sent)
+	}
+
+	/// Axes and reasons of the moves rejected since the last call
+	fn rejections(sent: &Receiver<Message>) -> Vec<(Axis, MoveRejectionReason)> {
+		sent.try_iter()
+			.filter_map(|msg| match msg {
+				Message::MoveRejectedMsgType(msg) => Some((msg.axis, msg.reason)),
+				_ => None,
+			})
+			.collect()
+	}
+
+	/// Positions of the given axes, in inches
+	fn inches(positions: &[(Axis, f64)]) -> HashMap<Axis, f64> {
+		positions.iter().map(|(axis, position)| (*axis, position * NANOMETRES_PER_INCH)).collect()
+	}
+
+	/// Motor control with X limited to an inch either side of zero
+	fn limited_motors_control(mode: SoftLimitMode) -> (MotorsControl, Receiver<Message>) {
+		let mut config = test_config();
+		config.soft_limit_mode = mode;
+		let (mut motors_control, sent) = motors_control(&config);
+		motors_control.set_soft_limits(vec![(Axis::X, AxisLimits{min: -NANOMETRES_PER_INCH, max: NANOMETRES_PER_INCH})].into_iter().collect());
+		(motors_control, sent)
+	}
+
+	fn set_endstop(motors_control: &mut MotorsControl, endstop: EndstopIdentifier, value: bool) {
+		motors_control.endstop_status_client.process_message(EndstopHitMsg{endstop, value});
+	}
+
+	/// Plan ahead until the planner stops, e.g. for a feed hold, without waiting for the step generator to keep up
+	fn plan_until_stopped(motors_control: &mut MotorsControl) {
+		for _ in 0..100_000 {
+			if !motors_control.plan_segment() {
+				return;
+			}
+		}
+		panic!("Planner never stopped");
+	}
+
+	#[test]
+	fn junction_velocity_carrying_straight_on_is_unlimited() {
+		let previous = xy_block((0.0, 0.0), (1.0, 1.0));
+		let block = xy_block((1.0, 1.0), (3.0, 3.0));
+		assert_eq!(block.junction_velocity(&previous, 0.001), f64::INFINITY);
+	}
+
+	#[test]
+	fn junction_velocity_round_a_right_angle() {
+		let previous = xy_block((0.0, 0.0), (1.0, 0.0));
+		let block = xy_block((1.0, 0.0), (1.0, 1.0));
+		let junction_deviation = 0.001;
+		let acceleration = block.limits.acceleration.min(previous.limits.acceleration);
+		// Going round a 90 degree corner, sin(theta/2) is sqrt(1/2)
+		let sin_half_theta = 0.5f64.sqrt();
+		let expected = (acceleration * junction_deviation * sin_half_theta / (1.0 - sin_half_theta)).sqrt();
+		let velocity = block.junction_velocity(&previous, junction_deviation);
+		assert!((velocity - expected).abs() <= expected * 1e-9, "got {} rather than {}", velocity, expected);
+		// Cutting the corner less tightly allows a faster speed
+		assert!(block.junction_velocity(&previous, 2.0 * junction_deviation) > velocity);
+	}
+
+	#[test]
+	fn junction_velocity_reversing_has_to_stop() {
+		let previous = xy_block((0.0, 0.0), (1.0, 1.0));
+		let block = xy_block((1.0, 1.0), (0.0, 0.0));
+		assert_eq!(block.junction_velocity(&previous, 0.001), 0.0);
+	}
+
+	#[test]
+	fn junction_velocity_after_an_empty_move_has_to_stop() {
+		let previous = xy_block((1.0, 1.0), (1.0, 1.0));
+		let block = xy_block((1.0, 1.0), (2.0, 1.0));
+		assert_eq!(block.junction_velocity(&previous, 0.001), 0.0);
+	}
+
+	#[test]
+	fn feed_hold_slows_to_a_stop_part_way_and_resume_carries_on() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.go_to_position(Axis::X, 10.0 * NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		for _ in 0..100 {
+			assert!(motors_control.plan_segment());
+		}
+		assert!(motors_control.path_state.velocity > 0.0);
+
+		motors_control.feed_hold();
+		plan_until_stopped(&mut motors_control);
+		assert_eq!(motors_control.path_state.velocity, 0.0);
+		// Still part way along the move, which carries on from there
+		assert_eq!(motors_control.planning_index, 0);
+		assert!(motors_control.planning_distance > 0.0 && motors_control.planning_distance < motors_control.blocks[0].length);
+		assert!(!motors_control.plan_segment());
+
+		motors_control.resume();
+		assert!(motors_control.plan_segment());
+		assert!(motors_control.path_state.velocity > 0.0);
+	}
+
+	#[test]
+	fn move_queued_during_a_feed_hold_waits_for_the_resume() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.feed_hold();
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 1);
+		assert!(!motors_control.plan_segment());
+		assert_eq!(motors_control.planning_distance, 0.0);
+		motors_control.resume();
+		assert!(motors_control.plan_segment());
+	}
+
+	#[test]
+	fn stopping_clears_the_feed_hold() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		motors_control.feed_hold();
+		motors_control.stop_all();
+		assert!(!motors_control.feed_held);
+		assert!(motors_control.blocks.is_empty());
+	}
+
+	#[test]
+	fn soft_limits_reject_moves_that_would_leave_them() {
+		let (motors_control, _sent) = limited_motors_control(SoftLimitMode::Reject);
+		let start = inches(&[(Axis::X, 0.0)]);
+		assert!(motors_control.apply_soft_limits(&start, inches(&[(Axis::X, 1.0)])).is_ok());
+		assert!(motors_control.apply_soft_limits(&start, inches(&[(Axis::X, -1.0)])).is_ok());
+		assert_eq!(motors_control.apply_soft_limits(&start, inches(&[(Axis::X, 1.5)])), Err(Axis::X));
+		assert_eq!(motors_control.apply_soft_limits(&start, inches(&[(Axis::X, -1.5)])), Err(Axis::X));
+		// Y has no limits, and only the axis that leaves its limits is reported
+		assert!(motors_control.apply_soft_limits(&inches(&[(Axis::Y, 0.0)]), inches(&[(Axis::Y, 100.0)])).is_ok());
+		assert_eq!(motors_control.apply_soft_limits(&inches(&[(Axis::X, 0.0), (Axis::Y, 0.0)]), inches(&[(Axis::X, 2.0), (Axis::Y, 100.0)])), Err(Axis::X));
+	}
+
+	#[test]
+	fn soft_limits_allow_moving_back_towards_them() {
+		let (motors_control, _sent) = limited_motors_control(SoftLimitMode::Reject);
+		let outside = inches(&[(Axis::X, 3.0)]);
+		assert!(motors_control.apply_soft_limits(&outside, inches(&[(Axis::X, 2.0)])).is_ok());
+		assert_eq!(motors_control.apply_soft_limits(&outside, inches(&[(Axis::X, 4.0)])), Err(Axis::X));
+	}
+
+	#[test]
+	fn soft_limits_clamp_a_move_along_its_line() {
+		let (motors_control, _sent) = limited_motors_control(SoftLimitMode::Clamp);
+		let end_positions = motors_control.apply_soft_limits(&inches(&[(Axis::X, 0.0), (Axis::Y, 0.0)]), inches(&[(Axis::X, 2.0), (Axis::Y, 1.0)])).unwrap();
+		assert!((end_positions[&Axis::X] - NANOMETRES_PER_INCH).abs() < 1e-6);
+		assert!((end_positions[&Axis::Y] - 0.5 * NANOMETRES_PER_INCH).abs() < 1e-6);
+	}
+
+	#[test]
+	fn invalid_soft_limits_are_ignored() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.set_soft_limits(vec![
+			(Axis::X, AxisLimits{min: f64::NAN, max: NANOMETRES_PER_INCH}),
+			(Axis::A, AxisLimits{min: 0.0, max: 1.0}),
+		].into_iter().collect());
+		assert!(motors_control.soft_limits.is_empty());
+	}
+
+	#[test]
+	fn move_outside_the_soft_limits_is_rejected_without_being_queued() {
+		let (mut motors_control, sent) = limited_motors_control(SoftLimitMode::Reject);
+		motors_control.go_to_position(Axis::X, 2.0 * NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert!(motors_control.blocks.is_empty());
+		assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::SoftLimit)]);
+	}
+
+	#[test]
+	fn move_towards_a_hit_endstop_is_rejected() {
+		let (mut motors_control, sent) = motors_control(&test_config());
+		set_endstop(&mut motors_control, EndstopIdentifier::new(Axis::X, AxisEnd::Max), true);
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert!(motors_control.blocks.is_empty());
+		assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::EndstopHit)]);
+		// Backing off it is fine
+		motors_control.go_to_position(Axis::X, -NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 1);
+		assert!(rejections(&sent).is_empty());
+	}
+
+	#[test]
+	fn endstops_of_axes_with_moves_queued_are_checked_once_they_run() {
+		let (mut motors_control, sent) = motors_control(&test_config());
+		motors_control.go_to_position(Axis::X, -NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		// By the time the next move starts, the first will have backed off the endstop
+		set_endstop(&mut motors_control, EndstopIdentifier::new(Axis::X, AxisEnd::Max), true);
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 2);
+		assert!(rejections(&sent).is_empty());
+	}
+
+	#[test]
+	fn moves_that_cannot_be_counted_in_steps_are_rejected() {
+		let (mut motors_control, sent) = motors_control(&test_config());
+		for &position in [f64::NAN, f64::INFINITY, f64::NEG_INFINITY, 1e30].iter() {
+			motors_control.go_to_position(Axis::X, position, NANOMETRES_PER_INCH);
+			assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::InvalidMove)], "Moved to {}", position);
+		}
+		for &speed in [0.0, -NANOMETRES_PER_INCH, f64::NAN, f64::INFINITY].iter() {
+			motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, speed);
+			assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::InvalidMove)], "Moved at speed {}", speed);
+		}
+		// A linear move is rejected as a whole, reporting the axis that's wrong
+		motors_control.start_linear_move(&inches(&[(Axis::X, 1.0), (Axis::A, 1.0)]), NANOMETRES_PER_INCH);
+		assert_eq!(rejections(&sent), vec![(Axis::A, MoveRejectionReason::InvalidMove)]);
+		assert!(motors_control.blocks.is_empty());
+	}
+
+	/// Motor control whose X axis has 0.01 inches of backlash to take up
+	fn backlash_motors_control() -> MotorsControl {
+		let mut config = test_config();
+		config.motor_configs.get_mut(&Axis::X).unwrap().backlash = 0.01 * NANOMETRES_PER_INCH;
+		motors_control(&config).0
+	}
+
+	fn is_take_up(block: &Block) -> bool {
+		matches!(block.kind, BlockKind::Backlash)
+	}
+
+	#[test]
+	fn reversing_an_axis_takes_up_its_backlash_first() {
+		let mut motors_control = backlash_motors_control();
+		let backlash_steps = motors_control.backlash_steps[&Axis::X];
+		assert!(backlash_steps > 0);
+		// The slack is taken to be taken up already for the first move, and stays taken up carrying on the same way
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		motors_control.go_to_position(Axis::X, 2.0 * NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 2);
+
+		motors_control.go_to_position(Axis::X, 0.0, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 4);
+		let take_up = &motors_control.blocks[2];
+		assert!(is_take_up(take_up));
+		let steps_per_unit = motors_control.motor_configs[&Axis::X].steps_per_unit();
+		assert!((take_up.end_positions[&Axis::X] + (backlash_steps as f64) / steps_per_unit).abs() < 1e-6);
+		assert!(!is_take_up(&motors_control.blocks[3]));
+		// The take-up doesn't count towards where the axis is
+		assert_eq!(motors_control.get_planned_position(Axis::X), 0.0);
+	}
+
+	#[test]
+	fn freeing_an_axis_forgets_which_way_its_slack_was_taken_up() {
+		let mut motors_control = backlash_motors_control();
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		motors_control.set_axis_locked(Axis::X, false);
+		assert!(motors_control.blocks.is_empty());
+		// Whichever way it goes next is taken as its first move again
+		motors_control.go_to_position(Axis::X, -NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 1);
+		assert!(!is_take_up(&motors_control.blocks[0]));
+	}
+
+	/// Motor control whose X axis has a slave motor, with its own endstops to square the axis against
+	fn squaring_motors_control() -> (MotorsControl, Receiver<Message>) {
+		let mut config = test_config();
+		config.motor_configs.get_mut(&Axis::X).unwrap().slave = Some(SlaveMotorConfig{
+			reversed: false,
+			max_squaring_distance: 0.1 * NANOMETRES_PER_INCH,
+			enable_pin_number: 100,
+			step_pin_number: 101,
+			direction_pin_number: 102,
+			microstep_pin_numbers: Vec::new(),
+			uart_address: None,
+		});
+		motors_control(&config)
+	}
+
+	#[test]
+	fn squaring_move_carries_on_until_every_motor_has_hit_its_endstop() {
+		let (mut motors_control, sent) = squaring_motors_control();
+		set_endstop(&mut motors_control, EndstopIdentifier::for_motor(Axis::X, AxisEnd::Max, AxisMotor::Main), true);
+		set_endstop(&mut motors_control, EndstopIdentifier::for_motor(Axis::X, AxisEnd::Max, AxisMotor::Slave), false);
+		motors_control.move_relative(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH, true);
+		assert_eq!(motors_control.blocks.len(), 1);
+		assert!(rejections(&sent).is_empty());
+
+		motors_control.stop_all();
+		set_endstop(&mut motors_control, EndstopIdentifier::for_motor(Axis::X, AxisEnd::Max, AxisMotor::Slave), true);
+		motors_control.move_relative(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH, true);
+		assert!(motors_control.blocks.is_empty());
+		assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::EndstopHit)]);
+	}
+
+	#[test]
+	fn move_that_isnt_squaring_stops_for_either_motors_endstop() {
+		let (mut motors_control, sent) = squaring_motors_control();
+		set_endstop(&mut motors_control, EndstopIdentifier::for_motor(Axis::X, AxisEnd::Max, AxisMotor::Main), false);
+		set_endstop(&mut motors_control, EndstopIdentifier::for_motor(Axis::X, AxisEnd::Max, AxisMotor::Slave), true);
+		motors_control.move_relative(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH, false);
+		assert!(motors_control.blocks.is_empty());
+		assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::EndstopHit)]);
+	}
+
+	/// Where the queued jog is headed
+	fn jog_target(motors_control: &MotorsControl, axis: Axis) -> f64 {
+		let block = motors_control.blocks.iter().find(|block| matches!(block.kind, BlockKind::Jog(_))).unwrap();
+		block.end_positions[&axis]
+	}
+
+	#[test]
+	fn jog_heads_for_the_soft_limit_or_a_long_way_off() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.start_jog(Axis::X, AxisEnd::Max, NANOMETRES_PER_INCH);
+		assert_eq!(jog_target(&motors_control, Axis::X), JOG_DISTANCE);
+
+		let (mut motors_control, _sent) = limited_motors_control(SoftLimitMode::Reject);
+		motors_control.start_jog(Axis::X, AxisEnd::Min, NANOMETRES_PER_INCH);
+		assert_eq!(jog_target(&motors_control, Axis::X), -NANOMETRES_PER_INCH);
+	}
+
+	#[test]
+	fn jog_already_at_its_soft_limit_is_rejected() {
+		let (mut motors_control, sent) = motors_control(&test_config());
+		motors_control.set_soft_limits(vec![(Axis::X, AxisLimits{min: -NANOMETRES_PER_INCH, max: 0.0})].into_iter().collect());
+		motors_control.start_jog(Axis::X, AxisEnd::Max, NANOMETRES_PER_INCH);
+		assert!(motors_control.blocks.is_empty());
+		assert!(motors_control.jog.is_none());
+		assert_eq!(rejections(&sent), vec![(Axis::X, MoveRejectionReason::SoftLimit)]);
+	}
+
+	#[test]
+	fn jog_and_other_moves_have_to_wait_for_each_other() {
+		let (mut motors_control, sent) = motors_control(&test_config());
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		motors_control.start_jog(Axis::Y, AxisEnd::Max, NANOMETRES_PER_INCH);
+		assert_eq!(rejections(&sent), vec![(Axis::Y, MoveRejectionReason::Busy)]);
+
+		motors_control.stop_all();
+		motors_control.start_jog(Axis::Y, AxisEnd::Max, NANOMETRES_PER_INCH);
+		motors_control.go_to_position(Axis::X, NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		// Reported against the axis that's jogging
+		assert_eq!(rejections(&sent), vec![(Axis::Y, MoveRejectionReason::Busy)]);
+		assert_eq!(motors_control.blocks.len(), 1);
+	}
+
+	#[test]
+	fn jogging_the_same_way_again_keeps_the_jog_alive() {
+		let (mut motors_control, sent) = motors_control(&test_config());
+		motors_control.start_jog(Axis::X, AxisEnd::Max, NANOMETRES_PER_INCH);
+		let an_hour_ago = Instant::now() - Duration::from_secs(3600);
+		motors_control.jog.as_mut().unwrap().last_keepalive = an_hour_ago;
+		motors_control.start_jog(Axis::X, AxisEnd::Max, NANOMETRES_PER_INCH);
+		assert!(motors_control.jog.as_ref().unwrap().last_keepalive > an_hour_ago);
+		assert_eq!(motors_control.blocks.len(), 1);
+		assert!(rejections(&sent).is_empty());
+	}
+
+	#[test]
+	fn jog_slows_to_a_stop_once_its_keepalive_times_out() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.start_jog(Axis::X, AxisEnd::Max, NANOMETRES_PER_INCH);
+		for _ in 0..100 {
+			assert!(motors_control.plan_segment());
+		}
+		motors_control.update_jog();
+		assert!(motors_control.jog.is_some());
+
+		let timeout = motors_control.jog_keepalive_timeout;
+		motors_control.jog.as_mut().unwrap().last_keepalive = Instant::now() - 2 * timeout;
+		motors_control.update_jog();
+		assert!(motors_control.jog.is_none());
+		// Cut short just far enough on to slow down from where the planner had got to
+		let block = &motors_control.blocks[0];
+		assert!(block.length > motors_control.planning_distance && block.length < JOG_DISTANCE);
+		plan_until_stopped(&mut motors_control);
+		assert_eq!(motors_control.path_state.velocity, 0.0);
+	}
+
+	#[test]
+	fn stopping_a_jog_that_has_not_got_going_cuts_it_to_nothing() {
+		let (mut motors_control, _sent) = motors_control(&test_config());
+		motors_control.start_jog(Axis::X, AxisEnd::Max, NANOMETRES_PER_INCH);
+		motors_control.stop_jog();
+		assert!(motors_control.jog.is_none());
+		assert_eq!(motors_control.blocks[0].length, 0.0);
+	}
+
+	#[test]
+	fn blocks_are_reported_and_dropped_once_the_step_generator_has_run_them() {
+		let mut config = test_config();
+		config.motor_configs.get_mut(&Axis::X).unwrap().backlash = 0.01 * NANOMETRES_PER_INCH;
+		let (mut motors_control, sent) = motors_control(&config);
+		motors_control.go_to_position(Axis::X, 0.05 * NANOMETRES_PER_INCH, NANOMETRES_PER_INCH);
+		motors_control.go_to_position(Axis::X, 0.0, NANOMETRES_PER_INCH);
+		assert_eq!(motors_control.blocks.len(), 3);
+
+		let deadline = Instant::now() + Duration::from_secs(10);
+		while !motors_control.blocks.is_empty() {
+			assert!(Instant::now() < deadline, "Moves never finished");
+			motors_control.update_completed_blocks();
+			motors_control.update_step_schedule();
+			thread::sleep(Duration::from_millis(1));
+		}
+		assert_eq!(motors_control.planning_index, 0);
+		// Taking up the backlash is part of the move after it, so it isn't reported on its own
+		let completed: Vec<Axis> = sent.try_iter()
+			.filter_map(|msg| match msg {
+				Message::MovementCompleteMsgType(msg) => Some(msg.axis),
+				_ => None,
+			})
+			.collect();
+		assert_eq!(completed, vec![Axis::X, Axis::X]);
+		assert_eq!(motors_control.get_position(Axis::X), 0.0);
+	}
+}
@@ -0,0 +1,390 @@
+use super::manual_control_controller::NoOpOperationParams;
+use super::OperationController;
+use super::OperationControllerData;
+use super::OperationParameters;
+
+use crate::common::Axis;
+use crate::common::AxisEnd;
+use crate::common::EndstopIdentifier;
+use crate::common::NANOMETRES_PER_INCH;
+use crate::messages::EndstopHitMsg;
+use crate::messages::Message;
+use crate::messages::MoveAxisRelMsg;
+use crate::messages::MoveRejectedMsg;
+use crate::messages::MoveRejectionReason;
+use crate::messages::MovementCompleteMsg;
+use crate::messages::SoftLimitsMsg;
+
+use std::collections::HashMap;
+
+use strum_macros::Display;
+
+
+
+/// How far to go looking for the endstop (256 inches, in internal units)
+const SEARCH_DISTANCE: f64 = 256.0 * NANOMETRES_PER_INCH;
+/// How far to back away from the endstop between measurements (0.05 inches, in internal units). Needs to be well over the backlash plus the endstop's own hysteresis.
+const CLEARANCE_DISTANCE: f64 = 0.05 * NANOMETRES_PER_INCH;
+/// Speed of the moves that are measured (0.02 inches/second, in internal units). Slow, so the axis hardly moves between the endstop changing and us reading the position.
+const MEASUREMENT_SPEED: f64 = 0.02 * NANOMETRES_PER_INCH;
+/// Number of measurements to average
+const SAMPLE_COUNT: usize = 3;
+
+
+
+#[derive(Copy, Clone)]
+#[derive(Debug)]
+#[derive(Serialize, Deserialize)]
+pub struct BacklashMeasurementParams {
+	pub axis: Axis,
+	/// Which endstop to measure against
+	pub end: AxisEnd,
+}
+
+impl OperationParameters for BacklashMeasurementParams {
+	fn make_controller(&self, data: OperationControllerData) -> Box<dyn OperationController> {
+		Box::new(BacklashMeasurementController::new(data, *self))
+	}
+}
+
+#[derive(Copy, Clone)]
+#[derive(Display)]
+#[derive(PartialEq)]
+enum BacklashMeasurementState {
+	/// Find the endstop at normal speed
+	FastApproach,
+	/// Back away from it, to come at it again slowly
+	BackOff,
+	/// Move slowly into the endstop, noting where it trips
+	SlowApproach,
+	/// Reverse slowly out of the endstop, noting where it releases
+	Release,
+}
+
+use BacklashMeasurementState as MeasurementState;
+
+/**
+ * Measures the backlash of an axis against one of its endstops, and proposes a value for the axis's config.
+ * After the endstop trips, reversing out of it takes the backlash before the table actually moves and the endstop releases.
+ * The endstop's own hysteresis is included in that too, so the proposal is an upper bound; it's left to the operator to put it in the config.
+ */
+struct BacklashMeasurementController {
+	common_data: OperationControllerData,
+	params: BacklashMeasurementParams,
+	state: MeasurementState,
+	trip_position: Option<f64>,
+	release_position: Option<f64>,
+	/// Distance from trip to release of each measurement so far
+	samples: Vec<f64>,
+}
+
+impl BacklashMeasurementController {
+	pub fn new(common_data: OperationControllerData, params: BacklashMeasurementParams) -> Self {
+		let mut ret = Self{
+			common_data,
+			params,
+			state: MeasurementState::FastApproach,
+			trip_position: None,
+			release_position: None,
+			samples: Vec::new(),
+		};
+		// The endstop is right on the edge of the work envelope, so we need to be able to go a little past it
+		ret.send_to_motor_control(Message::SoftLimitsMsgType(SoftLimitsMsg{limits: HashMap::new()}));
+		ret.set_state(MeasurementState::FastApproach);
+		ret
+	}
+
+	fn endstop(&self) -> EndstopIdentifier {
+		EndstopIdentifier::new(self.params.axis, self.params.end)
+	}
+
+	/// Distance to move towards the endstop; negative to move away from it
+	fn towards_endstop(&self, distance: f64) -> f64 {
+		match self.params.end {
+			AxisEnd::Min => -distance,
+			AxisEnd::Max => distance,
+		}
+	}
+
+	fn set_state(&mut self, state: MeasurementState) {
+		println!("Setting state to {}", state);
+		self.state = state;
+		match self.state {
+			MeasurementState::FastApproach => {
+				let speed = self.config_client().config.motor_configs[&self.params.axis].default_speed;
+				self.move_axis(self.towards_endstop(SEARCH_DISTANCE), speed);
+			},
+			MeasurementState::BackOff => {
+				let speed = self.config_client().config.motor_configs[&self.params.axis].default_speed;
+				self.move_axis(self.towards_endstop(-CLEARANCE_DISTANCE), speed);
+			},
+			MeasurementState::SlowApproach => {
+				self.trip_position = None;
+				self.move_axis(self.towards_endstop(2.0 * CLEARANCE_DISTANCE), MEASUREMENT_SPEED);
+			},
+			MeasurementState::Release => {
+				self.release_position = None;
+				self.move_axis(self.towards_endstop(-CLEARANCE_DISTANCE), MEASUREMENT_SPEED);
+			},
+		}
+	}
+
+	fn move_axis(&mut self, distance: f64, speed: f64) {
+		self.send_to_motor_control(Message::MoveAxisRelMsgType(MoveAxisRelMsg{axis: self.params.axis, distance, speed, square: false}));
+	}
+
+	fn handle_endstop_hit(&mut self, msg: EndstopHitMsg) {
+		let (endstop, value) = (msg.endstop, msg.value);
+		self.endstop_status_client_mut().process_message(msg);
+		if endstop != self.endstop() {
+			return;
+		}
+		let position = self.position_client().get_axis_position(self.params.axis);
+		match (self.state, value) {
+			(MeasurementState::SlowApproach, true) => self.trip_position = Some(position),
+			(MeasurementState::Release, false) => self.release_position = Some(position),
+			_ => {},
+		}
+	}
+
+	fn handle_movement_complete(&mut self, msg: MovementCompleteMsg) {
+		match self.state {
+			MeasurementState::FastApproach => {
+				if msg.endstop_hit {
+					self.set_state(MeasurementState::BackOff);
+				} else {
+					// Didn't reach the endstop. Keep going.
+					self.set_state(MeasurementState::FastApproach);
+				}
+			},
+			MeasurementState::BackOff => self.set_state(MeasurementState::SlowApproach),
+			MeasurementState::SlowApproach => {
+				if self.trip_position.is_some() {
+					self.set_state(MeasurementState::Release);
+				} else {
+					println!("Endstop {:?} didn't trip where it did before, giving up", self.endstop());
+					self.stop();
+				}
+			},
+			MeasurementState::Release => self.handle_release_complete(),
+		}
+	}
+
+	fn handle_release_complete(&mut self) {
+		let (trip_position, release_position) = match (self.trip_position, self.release_position) {
+			(Some(trip_position), Some(release_position)) => (trip_position, release_position),
+			_ => {
+				let units = self.config_client().config.unit_system().for_axis(self.params.axis);
+				println!("Endstop {:?} didn't release within {} {}, giving up", self.endstop(), units.internal_to_units(CLEARANCE_DISTANCE), units);
+				self.stop();
+				return;
+			},
+		};
+		let sample = self.towards_endstop(trip_position - release_position);
+		println!("Backlash measurement {}: {} nm", self.samples.len() + 1, sample);
+		self.samples.push(sample);
+		if self.samples.len() < SAMPLE_COUNT {
+			self.set_state(MeasurementState::SlowApproach);
+			return;
+		}
+
+		let configured = self.config_client().config.motor_configs[&self.params.axis].backlash;
+		let backlash = proposed_backlash(&self.samples, configured);
+		let units = self.config_client().config.unit_system().for_axis(self.params.axis);
+		println!(
+			"Measured backlash of axis {} from {:?} nm, with {} {} already configured. Proposed backlash: {} {}",
+			self.params.axis,
+			self.samples,
+			units.internal_to_units(configured),
+			units,
+			units.internal_to_units(backlash),
+			units,
+		);
+		self.stop();
+	}
+
+	fn handle_move_rejected(&mut self, msg: MoveRejectedMsg) {
+		if self.state == MeasurementState::FastApproach && msg.reason == MoveRejectionReason::EndstopHit {
+			// Already sitting on the endstop
+			self.set_state(MeasurementState::BackOff);
+			return;
+		}
+		println!("Backlash measurement move of axis {} was rejected: {:?}", msg.axis, msg.reason);
+		self.stop();
+	}
+}
+
+/// Backlash to propose from the distances between trip and release, given the backlash already configured.
+/// The samples are in logical steps, but the motor takes up the configured backlash on reversing before the axis's logical position moves at all.
+/// So by the time the endstop released, the motor had turned the measured distance plus the configured backlash.
+/// If that was already too much, the endstop released during the take-up, and the configured backlash is the best we know.
+fn proposed_backlash(samples: &[f64], configured: f64) -> f64 {
+	let measured = samples.iter().sum::<f64>() / (samples.len() as f64);
+	measured.max(0.0) + configured
+}
+
+impl OperationController for BacklashMeasurementController {
+	fn operation_controller_data(&self) -> &OperationControllerData {
+		&self.common_data
+	}
+
+	fn operation_controller_data_mut(&mut self) -> &mut OperationControllerData {
+		&mut self.common_data
+	}
+
+	fn stop(&mut self) {
+		// Put back the soft limits we lifted, as long as we know where they should be
+		self.send_soft_limits();
+		println!("Stopping all movement");
+		self.operation_controller_data_mut().feed_held = false;
+		self.send_to_motor_control(Message::StopMsgType());
+		self.change_controller(Box::new(NoOpOperationParams{}));
+	}
+
+	fn handle_message(&mut self, msg : Message) {
+		match msg {
+			Message::CurrentPositionMsgType(cp_msg) => self.position_client_mut().handle_message(cp_msg),
+			Message::EndstopHitMsgType(eh_msg) => self.handle_endstop_hit(eh_msg),
+
+			Message::DriverFaultMsgType(df_msg) => self.handle_driver_fault(df_msg),
+			Message::FeedHoldMsgType() => self.feed_hold(),
+			Message::FeedOverrideMsgType(_) => self.send_to_motor_control(msg),
+			Message::FollowingErrorMsgType(fe_msg) => self.handle_following_error(fe_msg),
+			Message::MoveRejectedMsgType(mr_msg) => self.handle_move_rejected(mr_msg),
+			Message::MovementCompleteMsgType(mc_msg) => self.handle_movement_complete(mc_msg),
+			Message::ResumeMsgType() => self.resume(),
+			Message::StopMsgType() => self.stop(),
+
+			_ => {}
+		};
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::config::ConfigClient;
+	use crate::config::ConfigManager;
+	use crate::endstop_checker::EndstopStatusClient;
+	use crate::machine_state::MachineState;
+	use crate::machine_state::MachineStateClient;
+	use crate::messages::CurrentPositionMsg;
+	use crate::motor_control::CurrentPositionClient;
+	use crate::operation_controllers::WorkEnvelope;
+
+	use std::sync::mpsc;
+	use std::sync::mpsc::Receiver;
+
+	/// Controller measuring against the Max endstop of X, and the receiving end of everything it sends to motor control
+	fn measurement_controller() -> (BacklashMeasurementController, Receiver<Message>) {
+		let (motor_control_sender, receiver) = mpsc::channel();
+		let data = OperationControllerData{
+			config_client: ConfigClient::new(ConfigManager::new().get_config().clone()),
+			endstop_status_client: EndstopStatusClient::new(),
+			position_client: CurrentPositionClient::new(),
+			motor_control_sender,
+			work_envelope: WorkEnvelope::from_state(&MachineState::default()),
+			machine_state: MachineStateClient::unsaved(),
+			feed_held: false,
+			pending_operation_params: None,
+		};
+		let controller = BacklashMeasurementController::new(data, BacklashMeasurementParams{axis: Axis::X, end: AxisEnd::Max});
+		(controller, receiver)
+	}
+
+	/// Distance and speed of the single move sent since last checked
+	fn sent_move(receiver: &Receiver<Message>) -> (f64, f64) {
+		let moves: Vec<(f64, f64)> = receiver.try_iter().filter_map(|msg| match msg {
+			Message::MoveAxisRelMsgType(msg) => Some((msg.distance, msg.speed)),
+			_ => None,
+		}).collect();
+		assert_eq!(moves.len(), 1);
+		moves[0]
+	}
+
+	fn movement_complete(controller: &mut BacklashMeasurementController, endstop_hit: bool) {
+		controller.handle_message(Message::MovementCompleteMsgType(MovementCompleteMsg{axis: Axis::X, endstop_hit}));
+	}
+
+	/// Move the axis to the position, and have the endstop change there
+	fn endstop_changes_at(controller: &mut BacklashMeasurementController, position: f64, value: bool) {
+		let mut msg = CurrentPositionMsg::new();
+		msg.positions.insert(Axis::X, position);
+		controller.handle_message(Message::CurrentPositionMsgType(msg));
+		controller.handle_message(Message::EndstopHitMsgType(EndstopHitMsg{endstop: EndstopIdentifier::new(Axis::X, AxisEnd::Max), value}));
+	}
+
+	fn has_stopped(controller: &BacklashMeasurementController, receiver: &Receiver<Message>) -> bool {
+		let stop_sent = receiver.try_iter().any(|msg| matches!(msg, Message::StopMsgType()));
+		stop_sent && controller.common_data.pending_operation_params.is_some()
+	}
+
+	#[test]
+	fn soft_limits_are_lifted_and_the_endstop_found_first() {
+		let (mut controller, receiver) = measurement_controller();
+		assert!(matches!(receiver.try_iter().next(), Some(Message::SoftLimitsMsgType(msg)) if msg.limits.is_empty()));
+		assert_eq!(receiver.try_iter().count(), 1);
+
+		movement_complete(&mut controller, false);
+		assert_eq!(sent_move(&receiver).0, SEARCH_DISTANCE);
+		movement_complete(&mut controller, true);
+		assert!(controller.state == MeasurementState::BackOff);
+		assert_eq!(sent_move(&receiver).0, -CLEARANCE_DISTANCE);
+	}
+
+	#[test]
+	fn backlash_is_measured_between_trip_and_release() {
+		let (mut controller, receiver) = measurement_controller();
+		movement_complete(&mut controller, true);
+		movement_complete(&mut controller, false);
+		receiver.try_iter().for_each(drop);
+
+		for (sample, backlash) in [1000.0, 1100.0, 1200.0].iter().enumerate() {
+			assert!(controller.state == MeasurementState::SlowApproach);
+			endstop_changes_at(&mut controller, 5000.0, true);
+			movement_complete(&mut controller, false);
+			assert!(controller.state == MeasurementState::Release);
+			assert_eq!(sent_move(&receiver), (-CLEARANCE_DISTANCE, MEASUREMENT_SPEED));
+			endstop_changes_at(&mut controller, 5000.0 - backlash, false);
+			movement_complete(&mut controller, false);
+			assert_eq!(controller.samples.len(), sample + 1);
+			if sample + 1 < SAMPLE_COUNT {
+				assert_eq!(sent_move(&receiver), (2.0 * CLEARANCE_DISTANCE, MEASUREMENT_SPEED));
+			}
+		}
+		assert_eq!(controller.samples, vec![1000.0, 1100.0, 1200.0]);
+		assert!(has_stopped(&controller, &receiver));
+	}
+
+	#[test]
+	fn endstop_that_doesnt_trip_again_gives_up() {
+		let (mut controller, receiver) = measurement_controller();
+		movement_complete(&mut controller, true);
+		movement_complete(&mut controller, false);
+		movement_complete(&mut controller, false);
+		assert!(has_stopped(&controller, &receiver));
+	}
+
+	#[test]
+	fn starting_on_the_endstop_backs_off_it() {
+		let (mut controller, receiver) = measurement_controller();
+		receiver.try_iter().for_each(drop);
+		controller.handle_message(Message::MoveRejectedMsgType(MoveRejectedMsg{move_axis: Some(Axis::X), axis: Axis::X, reason: MoveRejectionReason::EndstopHit}));
+		assert!(controller.state == MeasurementState::BackOff);
+		assert_eq!(sent_move(&receiver).0, -CLEARANCE_DISTANCE);
+
+		controller.handle_message(Message::MoveRejectedMsgType(MoveRejectedMsg{move_axis: Some(Axis::X), axis: Axis::X, reason: MoveRejectionReason::EndstopHit}));
+		assert!(has_stopped(&controller, &receiver));
+	}
+
+	#[test]
+	fn proposal_adds_the_backlash_already_taken_up() {
+		assert_eq!(proposed_backlash(&[1000.0, 1100.0, 1200.0], 500.0), 1600.0);
+		assert_eq!(proposed_backlash(&[1000.0, 1100.0, 1200.0], 0.0), 1100.0);
+		// Released during the take-up, so all we know is that the configured backlash is enough
+		assert_eq!(proposed_backlash(&[0.0, -100.0, 100.0], 500.0), 500.0);
+		assert_eq!(proposed_backlash(&[-300.0, -100.0, -200.0], 500.0), 500.0);
+	}
+}
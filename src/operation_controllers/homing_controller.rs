@@ -4,13 +4,20 @@ use super::OperationParameters;
 
 use crate::common::Axis;
 use crate::common::AxisEnd;
+use crate::common::NANOMETRES_PER_INCH;
+use crate::config::RustGrindConfig;
 use crate::messages::Message;
 use crate::messages::MoveAxisRelMsg;
+use crate::messages::MoveRejectedMsg;
+use crate::messages::MoveRejectionReason;
 use crate::messages::MovementCompleteMsg;
 
-use strum_macros::Display;
 
 
+/// How far to go looking for an endstop (256 inches, in internal units); far enough that the endstop stops the move long before it finishes
+const HOMING_DISTANCE: f64 = 256.0 * NANOMETRES_PER_INCH;
+/// How far over StallGuard's min speed to home against a stall, so the motor's well up to speed before it gets there
+const STALL_HOMING_SPEED_FACTOR: f64 = 1.5;
 
 pub struct HomingParams {}
 impl OperationParameters for HomingParams {
@@ -19,76 +26,123 @@ impl OperationParameters for HomingParams {
 	}
 }
 
+/// One move of the homing sequence: running an axis into the endstop at one of its ends
 #[derive(Copy, Clone)]
-#[derive(Display)]
-#[derive(PartialEq)]
-enum HomingState {
-	XMinus,
-	XPlus,
-	YMinus,
-	YPlus,
+struct HomingStep {
+	axis: Axis,
+	end: AxisEnd,
+	speed: f64,
+}
+
+/**
+ * Work out the homing sequence from the endstops in the config: each end of each axis that has one, real or StallGuard, min end first.
+ * Z goes first, to lift the wheel clear of the work before the table moves.
+ */
+fn make_homing_steps(config: &RustGrindConfig) -> Vec<HomingStep> {
+	let mut axes: Vec<Axis> = config.motor_configs.keys().copied().collect();
+	axes.sort_by_key(|axis| (*axis != Axis::Z, *axis));
+	let mut steps = Vec::new();
+	for axis in axes {
+		let motor_config = &config.motor_configs[&axis];
+		let stall_ends: Vec<AxisEnd> = motor_config.stall_endstops(axis).into_iter().map(|endstop| endstop.position).collect();
+		for end in [AxisEnd::Min, AxisEnd::Max].iter().copied() {
+			let stall_guard = motor_config.driver.uart.as_ref()
+				.and_then(|uart| uart.stall_guard)
+				.filter(|stall_guard| stall_guard.end == end && stall_ends.contains(&end));
+			if let Some(stall_guard) = stall_guard {
+				// A stall isn't noticed below the min speed, so go well over it, even if the axis normally goes slower
+				let speed = motor_config.default_speed.max(STALL_HOMING_SPEED_FACTOR * stall_guard.min_speed);
+				steps.push(HomingStep{axis, end, speed});
+			} else if config.endstop_config.keys().any(|endstop| endstop.axis == axis && endstop.position == end) {
+				steps.push(HomingStep{axis, end, speed: motor_config.default_speed});
+			}
+		}
+	}
+	steps
 }
 
 struct HomingController {
 	common_data: OperationControllerData,
-	state: HomingState,
+	steps: Vec<HomingStep>,
+	/// Index of the step in progress
+	step: usize,
 }
 impl HomingController {
 	pub fn new(common_data: OperationControllerData) -> Self {
+		let steps = make_homing_steps(&common_data.config_client.config);
 		let mut ret = Self{
 			common_data,
-			state: HomingState::XMinus,
+			steps,
+			step: 0,
 		};
-		ret.set_state(HomingState::XMinus);
+		// Homing deliberately runs into the endstops, so the old limits (if any) are in the way, and may be wrong anyway
+		ret.work_envelope_mut().homed = false;
+		ret.send_soft_limits();
+		if ret.steps.is_empty() {
+			println!("No endstops to home against");
+			ret.stop();
+		} else {
+			ret.start_step(0);
+		}
 		ret
 	}
 
 	fn handle_movement_complete(&mut self, msg: MovementCompleteMsg) {
 		if !msg.endstop_hit {
 			// Didn't reach the endstop. Keep going.
-			self.set_state(self.state);
+			self.start_step(self.step);
 			return;
 		}
-		match self.state {
-			HomingState::XMinus => {
-				self.work_envelope_mut().min_x = self.position_client().get_axis_position(Axis::X);
-				self.set_state(HomingState::XPlus);
-			},
-			HomingState::XPlus => {
-				self.work_envelope_mut().max_x = self.position_client().get_axis_position(Axis::X);
-				self.set_state(HomingState::YMinus);
-			},
-			HomingState::YMinus => {
-				self.work_envelope_mut().min_y = self.position_client().get_axis_position(Axis::Y);
-				self.set_state(HomingState::YPlus);
-			},
-			HomingState::YPlus => {
-				self.work_envelope_mut().max_y = self.position_client().get_axis_position(Axis::Y);
-				self.stop();
-			},
+		let step = self.steps[self.step];
+		let position = self.position_client().get_axis_position(step.axis);
+		self.work_envelope_mut().set_extent(step.axis, step.end, position);
+		if self.step + 1 < self.steps.len() {
+			self.start_step(self.step + 1);
+		} else {
+			self.finish();
 		}
 	}
 
-	fn set_state(&mut self, state: HomingState) {
-		println!("Setting state to {}", state);
-		self.state = state;
-		match self.state {
-			HomingState::XMinus => self.move_towards_extent(Axis::X, AxisEnd::Min),
-			HomingState::XPlus => self.move_towards_extent(Axis::X, AxisEnd::Max),
-			HomingState::YMinus => self.move_towards_extent(Axis::Y, AxisEnd::Min),
-			HomingState::YPlus => self.move_towards_extent(Axis::Y, AxisEnd::Max),
+	/// Every end with an endstop has been found, so start keeping moves within them
+	fn finish(&mut self) {
+		self.work_envelope_mut().homed = true;
+		self.send_soft_limits();
+		let unhomed: Vec<Axis> = self.config_client().config.motor_configs.keys()
+			.filter(|axis| !self.steps.iter().any(|step| step.axis == **axis))
+			.copied()
+			.collect();
+		if unhomed.is_empty() {
+			self.operation_controller_data().machine_state.set_position_trusted(true);
+		} else {
+			println!("Axes {:?} have no endstops to home against, so their positions still can't be trusted", unhomed);
 		}
+		self.stop();
 	}
 
-	fn move_towards_extent(&mut self, axis: Axis, end: AxisEnd) {
-		match end {
-			AxisEnd::Max => self.send_to_motor_control(Message::MoveAxisRelMsgType(MoveAxisRelMsg{axis, distance: 256.0, speed: self.get_homing_speed(axis)})),
-			AxisEnd::Min => self.send_to_motor_control(Message::MoveAxisRelMsgType(MoveAxisRelMsg{axis, distance: -256.0, speed: self.get_homing_speed(axis)})),
+	fn handle_move_rejected(&mut self, msg: MoveRejectedMsg) {
+		if msg.reason == MoveRejectionReason::EndstopHit {
+			// Already sitting on the endstop we were heading for
+			self.handle_movement_complete(MovementCompleteMsg{axis: msg.axis, endstop_hit: true});
+			return;
 		}
+		println!("Homing move of axis {} was rejected: {:?}", msg.axis, msg.reason);
+		self.stop();
+	}
+
+	fn start_step(&mut self, index: usize) {
+		self.step = index;
+		let step = self.steps[index];
+		println!("Homing axis {} towards {}", step.axis, step.end);
+		self.move_towards_extent(step);
 	}
 
-	fn get_homing_speed(&self, axis: Axis) -> f64 {
-		self.config_client().config.motor_configs.get(&axis).unwrap().default_speed_ips
+	/// Dual-motor axes are squared against their min endstops. After that, the motors stay in step, so the max endstops only tell us where the end is.
+	fn move_towards_extent(&mut self, step: HomingStep) {
+		let (distance, square) = match step.end {
+			AxisEnd::Max => (HOMING_DISTANCE, false),
+			AxisEnd::Min => (-HOMING_DISTANCE, true),
+		};
+		self.send_to_motor_control(Message::MoveAxisRelMsgType(MoveAxisRelMsg{axis: step.axis, distance, speed: step.speed, square}));
 	}
 }
 impl OperationController for HomingController {
@@ -105,7 +159,13 @@ impl OperationController for HomingController {
 			Message::CurrentPositionMsgType(cp_msg) => self.position_client_mut().handle_message(cp_msg),
 			Message::EndstopHitMsgType(eh_msg) => self.endstop_status_client_mut().process_message(eh_msg),
 
+			Message::DriverFaultMsgType(df_msg) => self.handle_driver_fault(df_msg),
+			Message::FeedHoldMsgType() => self.feed_hold(),
+			Message::FeedOverrideMsgType(_) => self.send_to_motor_control(msg),
+			Message::FollowingErrorMsgType(fe_msg) => self.handle_following_error(fe_msg),
+			Message::MoveRejectedMsgType(mr_msg) => self.handle_move_rejected(mr_msg),
 			Message::MovementCompleteMsgType(mc_msg) => self.handle_movement_complete(mc_msg),
+			Message::ResumeMsgType() => self.resume(),
 			Message::StopMsgType() => self.stop(),
 
 			_ => {}
@@ -36,6 +36,7 @@ impl OperationController for ManualControlController {
 	fn stop(&mut self) {
 		// Same as default implementation, except no need to replace controller because we're already the idle controller
 		println!("Stopping all movement");
+		self.operation_controller_data_mut().feed_held = false;
 		self.send_to_motor_control(Message::StopMsgType());
 	}
 
@@ -44,11 +45,25 @@ impl OperationController for ManualControlController {
 			Message::CurrentPositionMsgType(cp_msg) => self.position_client_mut().handle_message(cp_msg),
 			Message::EndstopHitMsgType(eh_msg) => self.endstop_status_client_mut().process_message(eh_msg),
 
+			Message::AxisLockMsgType(_) => self.send_to_motor_control(msg),
+			Message::DriverFaultMsgType(df_msg) => self.handle_driver_fault(df_msg),
+			Message::FeedHoldMsgType() => self.feed_hold(),
+			Message::FeedOverrideMsgType(_) => self.send_to_motor_control(msg),
+			Message::FollowingErrorMsgType(fe_msg) => self.handle_following_error(fe_msg),
 			Message::GoToPositionMsgType(_) => self.send_to_motor_control(msg),
+			Message::JogKeepaliveMsgType() => self.send_to_motor_control(msg),
+			Message::LinearMoveMsgType(_) => self.send_to_motor_control(msg),
 			Message::MoveAxisRelMsgType(_) => self.send_to_motor_control(msg),
+			Message::MoveRejectedMsgType(mr_msg) => println!("Move of axis {} was rejected: {:?}", mr_msg.axis, mr_msg.reason),
+			Message::ResetFollowingErrorMsgType(_) => self.send_to_motor_control(msg),
+			Message::ResumeMsgType() => self.resume(),
 			Message::SpindleControlMsgType(_) => self.send_to_motor_control(msg),
+			Message::SpindleFaultMsgType(sf_msg) => self.handle_spindle_fault(sf_msg),
+			Message::StartJogMsgType(_) => self.send_to_motor_control(msg),
+			Message::StopJogMsgType() => self.send_to_motor_control(msg),
 			Message::StopMsgType() => self.stop(),
 
+			Message::StartBacklashMeasurementMsgType(measurement_params) => self.change_controller(Box::new(measurement_params)),
 			Message::StartHomingMsgType() => self.change_controller(Box::new(HomingParams{})),
 			Message::StartSurfaceGrinderCutMsgType(cut_params) => self.change_controller(Box::new(cut_params)),
 
@@ -1,3 +1,4 @@
+mod backlash_measurement_controller;
 mod homing_controller;
 mod manual_control_controller;
 mod operation_controller_data;
@@ -6,6 +7,7 @@ mod operation_controller;
 mod surface_grinder_cut_controller;
 mod work_envelope;
 
+pub use self::backlash_measurement_controller::BacklashMeasurementParams;
 pub use self::surface_grinder_cut_controller::SurfaceGrinderCutParams;
 
 use self::operation_controller_data::OperationControllerData;
@@ -15,6 +17,7 @@ use self::operation_controller::OperationParameters;
 use self::work_envelope::WorkEnvelope;
 
 use crate::config::RustGrindConfig;
+use crate::machine_state::MachineStateClient;
 use crate::messages::Message;
 
 use std::sync::mpsc::Receiver;
@@ -23,10 +26,10 @@ use std::thread;
 
 
 
-pub fn init(initial_config : RustGrindConfig, receiver : Receiver<Message>, motor_control_sender: Sender<Message>) {
+pub fn init(initial_config : RustGrindConfig, machine_state: MachineStateClient, receiver : Receiver<Message>, motor_control_sender: Sender<Message>) {
 	let builder = thread::Builder::new().name("MainController".to_string());
 	builder.spawn(move || {
-		let mut main_controller = OperationControllerManager::new(initial_config, receiver, motor_control_sender);
+		let mut main_controller = OperationControllerManager::new(initial_config, machine_state, receiver, motor_control_sender);
 		main_controller.run();
 	}).unwrap();
 }
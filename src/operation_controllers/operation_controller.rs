@@ -1,6 +1,10 @@
 use crate::config::ConfigClient;
 use crate::endstop_checker::EndstopStatusClient;
+use crate::messages::DriverFaultMsg;
+use crate::messages::FollowingErrorMsg;
 use crate::messages::Message;
+use crate::messages::SoftLimitsMsg;
+use crate::messages::SpindleFaultMsg;
 use crate::motor_control::CurrentPositionClient;
 
 use super::manual_control_controller::NoOpOperationParams;
@@ -39,18 +43,67 @@ pub trait OperationController {
 	}
 
 	fn send_to_motor_control(&self, msg: Message) {
-		self.operation_controller_data().motor_control_sender.send(msg);
+		if self.operation_controller_data().motor_control_sender.send(msg).is_err() {
+			println!("Motor control isn't listening");
+		}
+	}
+
+	/// Have motor control keep all moves within the work envelope, or stop limiting them if we haven't homed
+	fn send_soft_limits(&self) {
+		let limits = self.work_envelope().get_soft_limits();
+		self.operation_controller_data().machine_state.set_envelope(self.work_envelope().extents.clone(), self.work_envelope().homed);
+		self.send_to_motor_control(Message::SoftLimitsMsgType(SoftLimitsMsg{limits}));
+	}
+
+	fn is_feed_held(&self) -> bool {
+		self.operation_controller_data().feed_held
 	}
 
 	fn update(&mut self) {}
 
+	/// Decelerate all axes to a stop, keeping this controller and its state so the operation can carry on after resume()
+	fn feed_hold(&mut self) {
+		println!("Feed hold");
+		self.operation_controller_data_mut().feed_held = true;
+		self.send_to_motor_control(Message::FeedHoldMsgType());
+	}
+
+	/// Continue the moves interrupted by feed_hold()
+	fn resume(&mut self) {
+		println!("Resuming from feed hold");
+		self.operation_controller_data_mut().feed_held = false;
+		self.send_to_motor_control(Message::ResumeMsgType());
+	}
+
 	fn stop(&mut self) {
 		println!("Stopping all movement");
+		// Stopping also cancels any feed hold, since there's nothing left to resume
+		self.operation_controller_data_mut().feed_held = false;
 		self.send_to_motor_control(Message::StopMsgType());
 		// Change to idle/manual control controller
 		self.change_controller(Box::new(NoOpOperationParams{}));
 	}
 
+	/// Motor control has already stopped everything because an axis lost its position, so whatever we were doing can't carry on
+	fn handle_following_error(&mut self, msg: FollowingErrorMsg) {
+		println!("Following error alarm on axis {}: commanded position {}, measured {}", msg.axis, msg.commanded, msg.measured);
+		self.stop();
+	}
+
+	/// The spindle can't be relied on to be turning any more, so stop anything that might be grinding with it
+	fn handle_spindle_fault(&mut self, msg: SpindleFaultMsg) {
+		println!("Spindle fault: {:?}", msg.fault);
+		self.stop();
+	}
+
+	/// Motor control has already stopped everything if a driver's fault means its motor can't be relied on, so whatever we were doing can't carry on
+	fn handle_driver_fault(&mut self, msg: DriverFaultMsg) {
+		println!("Driver faults on axis {} {} motor: {:?}", msg.axis, msg.motor, msg.faults);
+		if msg.faults.iter().any(|fault| fault.stops_motion()) {
+			self.stop();
+		}
+	}
+
 	fn handle_message(&mut self, msg: Message);
 
 	fn change_controller(&mut self, params: Box<dyn OperationParameters>) {
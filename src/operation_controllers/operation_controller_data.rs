@@ -1,5 +1,6 @@
 use crate::config::ConfigClient;
 use crate::endstop_checker::EndstopStatusClient;
+use crate::machine_state::MachineStateClient;
 use crate::messages::Message;
 use crate::motor_control::CurrentPositionClient;
 
@@ -16,6 +17,10 @@ pub struct OperationControllerData {
 	pub position_client: CurrentPositionClient,
 	pub motor_control_sender: Sender<Message>,
 	pub work_envelope: WorkEnvelope,
+	/// Where the work envelope is kept across restarts
+	pub machine_state: MachineStateClient,
+	/// Whether motion is paused by a feed hold, waiting for a resume
+	pub feed_held: bool,
 
 	/// Flag to tell the manager to replace this controller with one created from these parameters
 	pub pending_operation_params: Option<Box<dyn OperationParameters>>,
@@ -29,6 +34,8 @@ impl OperationControllerData {
 			position_client: self.position_client.clone(),
 			motor_control_sender: self.motor_control_sender.clone(),
 			work_envelope: self.work_envelope.clone(),
+			machine_state: self.machine_state.clone(),
+			feed_held: self.feed_held,
 			// Not cloning operation parameters because we don't need/want them for the new controller
 			pending_operation_params: None,
 		}
@@ -1,6 +1,7 @@
 use crate::config::ConfigClient;
 use crate::config::RustGrindConfig;
 use crate::endstop_checker::EndstopStatusClient;
+use crate::machine_state::MachineStateClient;
 use crate::messages::Message;
 use crate::motor_control::CurrentPositionClient;
 
@@ -11,32 +12,41 @@ use super::OperationParameters;
 use super::WorkEnvelope;
 
 use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
 use std::sync::mpsc::Sender;
 use std::sync::mpsc::TryRecvError;
 use std::time::Duration;
 
 
 
+/// Longest we wait for messages before letting the controller check on its timeouts, e.g. for the spindle to get up to speed
+const UPDATE_INTERVAL: Duration = Duration::from_millis(10);
+
 pub struct OperationControllerManager {
 	controller: Box<dyn OperationController>,
 	receiver: Receiver<Message>,
 }
 
 impl OperationControllerManager {
-	pub fn new(config: RustGrindConfig, receiver: Receiver<Message>, motor_control_sender: Sender<Message>) -> Self {
-		OperationControllerManager {
+	pub fn new(config: RustGrindConfig, machine_state: MachineStateClient, receiver: Receiver<Message>, motor_control_sender: Sender<Message>) -> Self {
+		let ret = OperationControllerManager {
 			controller: NoOpOperationParams{}.make_controller(
 				OperationControllerData{
 					config_client: ConfigClient::new(config),
 					endstop_status_client: EndstopStatusClient::new(),
 					position_client: CurrentPositionClient::new(),
 					motor_control_sender,
-					work_envelope: WorkEnvelope::new(),
+					work_envelope: WorkEnvelope::from_state(&machine_state.get()),
+					machine_state,
+					feed_held: false,
 					pending_operation_params: None,
 				},
 			),
 			receiver,
-		}
+		};
+		// Carry on keeping moves within the envelope found by homing before the restart, if there was one
+		ret.controller.send_soft_limits();
+		ret
 	}
 
 	fn handle_message(&mut self, msg: Message) {
@@ -47,11 +57,8 @@ impl OperationControllerManager {
 	fn check_replace_controller(&mut self) {
 		let param_option_clone = &self.controller.operation_controller_data_mut().pending_operation_params.take();
 
-		match param_option_clone {
-			Some(params) => {
-				self.controller = params.make_controller(self.controller.operation_controller_data().clone());
-			},
-			None => {}
+		if let Some(params) = param_option_clone {
+			self.controller = params.make_controller(self.controller.operation_controller_data().clone());
 		}
 	}
 
@@ -62,21 +69,25 @@ impl OperationControllerManager {
 
 	pub fn run(&mut self) -> ! {
 		loop {
-			// Check for messages
+			// Wait for messages
+			match self.receiver.recv_timeout(UPDATE_INTERVAL) {
+				Ok(msg) => self.handle_message(msg),
+				Err(RecvTimeoutError::Timeout) => {},
+				// If nothing is connected to send us messages, then something has gone very wrong.
+				// There is no way for anything to get connected again, either.
+				Err(RecvTimeoutError::Disconnected) => self.shutdown(),
+			}
+			// Handle everything else that's waiting, too
 			loop {
 				match self.receiver.try_recv() {
 					Ok(msg) => self.handle_message(msg),
 					Err(TryRecvError::Empty) => break,
-					// If nothing is connected to send us messages, then something has gone very wrong.
-					// There is no way for anything to get connected again, either.
 					Err(TryRecvError::Disconnected) => self.shutdown(),
 				}
 			}
 
 			self.controller.update();
 			self.check_replace_controller();
-
-			std::thread::sleep(Duration::from_nanos(5));
 		}
 	}
 }
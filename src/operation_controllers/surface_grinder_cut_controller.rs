@@ -1,18 +1,21 @@
 use crate::common::Axis;
 use crate::common::AxisEnd;
 use crate::common::EndstopIdentifier;
+use crate::common::UnitSystem;
+use crate::messages::ConvertUnits;
 use crate::messages::GoToPositionMsg;
 use crate::messages::Message;
 use crate::messages::MoveAxisRelMsg;
+use crate::messages::MoveRejectedMsg;
 use crate::messages::MovementCompleteMsg;
 use crate::messages::SpindleControlMsg;
+use crate::messages::SpindleStatusMsg;
 
 use super::OperationController;
 use super::OperationControllerData;
 use super::OperationParameters;
 
-use std::time::Duration;
-use std::time::Instant;
+use std::collections::HashMap;
 
 use strum_macros::Display;
 
@@ -24,21 +27,37 @@ use strum_macros::Display;
 pub struct SurfaceGrinderCutParams {
 	pub depth_of_cut: f64,	// Z depth of each pass
 	pub feed_per_pass: f64,	// Y feed per pass
-	pub stroke_speed: f64,	// IPS
+	pub stroke_speed: f64,	// Per second
 	pub total_depth: f64,
+	/// Speed to run the spindle at. If it isn't given, it runs at the last speed asked for (or full speed).
+	#[serde(default)]
+	pub spindle_rpm: Option<f64>,
 }
 
 impl SurfaceGrinderCutParams {
+	#[allow(dead_code)]
 	pub fn new() -> Self {
 		SurfaceGrinderCutParams {
 			depth_of_cut: 0.0,
 			feed_per_pass: 0.0,
 			stroke_speed: 0.0,
 			total_depth: 0.0,
+			spindle_rpm: None,
 		}
 	}
 }
 
+impl ConvertUnits for SurfaceGrinderCutParams {
+	fn to_internal_units(mut self, units: &UnitSystem) -> Self {
+		let units = units.length;
+		self.depth_of_cut = units.to_internal(self.depth_of_cut);
+		self.feed_per_pass = units.to_internal(self.feed_per_pass);
+		self.stroke_speed = units.to_internal(self.stroke_speed);
+		self.total_depth = units.to_internal(self.total_depth);
+		self
+	}
+}
+
 impl OperationParameters for SurfaceGrinderCutParams {
 	fn make_controller(&self, data: OperationControllerData) -> Box<dyn OperationController> {
 		Box::new(SurfaceGrinderCutController::new(data, *self))
@@ -63,6 +82,18 @@ enum SurfaceGrinderCutState {
 
 use SurfaceGrinderCutState as CutState;
 
+impl SurfaceGrinderCutState {
+	/// Whether the state is just a move of an axis, which can be queued up behind the previous one
+	fn is_move(&self) -> bool {
+		!matches!(self, CutState::Idle | CutState::SpindleSpinUp)
+	}
+
+	/// Whether the wheel is meant to be grinding, so the spindle has to be at speed
+	fn needs_spindle(&self) -> bool {
+		!matches!(self, CutState::Idle | CutState::ToStartingPositionX | CutState::ToStartingPositionY | CutState::SpindleSpinUp)
+	}
+}
+
 
 /**
  * Cut controller for a surface grinder.
@@ -71,8 +102,14 @@ struct SurfaceGrinderCutController {
 	common_data: OperationControllerData,
 	cut_params: SurfaceGrinderCutParams,
 	state: CutState,
-	spindle_started_time: Instant,
+	/// Whether motor control last said the spindle was at speed
+	spindle_at_speed: bool,
 	starting_height: f64,
+	/// Moves sent to motor control that haven't completed yet.
+	/// We keep the next move queued up behind the current one, so the planner can look ahead to it and keep up speed between them.
+	moves_in_progress: u32,
+	/// Where each axis will be once the moves we've sent are done
+	planned_positions: HashMap<Axis, f64>,
 }
 
 impl OperationController for SurfaceGrinderCutController {
@@ -89,7 +126,15 @@ impl OperationController for SurfaceGrinderCutController {
 			Message::CurrentPositionMsgType(cp_msg) => self.position_client_mut().handle_message(cp_msg),
 			Message::EndstopHitMsgType(eh_msg) => self.endstop_status_client_mut().process_message(eh_msg),
 
+			Message::DriverFaultMsgType(df_msg) => self.handle_driver_fault(df_msg),
+			Message::FeedHoldMsgType() => self.feed_hold(),
+			Message::FeedOverrideMsgType(_) => self.send_to_motor_control(msg),
+			Message::FollowingErrorMsgType(fe_msg) => self.handle_following_error(fe_msg),
+			Message::MoveRejectedMsgType(mr_msg) => self.handle_move_rejected(mr_msg),
 			Message::MovementCompleteMsgType(mc_msg) => self.handle_movement_complete(mc_msg),
+			Message::ResumeMsgType() => self.resume(),
+			Message::SpindleFaultMsgType(sf_msg) => self.handle_spindle_fault(sf_msg),
+			Message::SpindleStatusMsgType(ss_msg) => self.handle_spindle_status(ss_msg),
 			Message::StopMsgType() => self.stop(),
 
 			_ => {},
@@ -97,10 +142,12 @@ impl OperationController for SurfaceGrinderCutController {
 	}
 
 	fn update(&mut self) {
-		if
-			self.state == CutState::SpindleSpinUp
-			&& self.spindle_started_time.elapsed() >= Duration::from_secs(3)
-		{
+		if self.state != CutState::SpindleSpinUp {
+			return;
+		}
+		// Don't start the next pass while feed is held; the spindle keeps running, so it'll be up to speed on resume anyway
+		// If it never gets up to speed, motor control reports it as a spindle fault, which abandons the cut
+		if !self.is_feed_held() && self.spindle_at_speed {
 			self.advance_state();
 		}
 	}
@@ -112,8 +159,10 @@ impl SurfaceGrinderCutController {
 			common_data,
 			cut_params,
 			state: CutState::Idle,
-			spindle_started_time: Instant::now(),
+			spindle_at_speed: false,
 			starting_height: 0.0,
+			moves_in_progress: 0,
+			planned_positions: HashMap::new(),
 		};
 		// Begin the cutting process
 		ret.start_cut(cut_params);
@@ -123,7 +172,12 @@ impl SurfaceGrinderCutController {
 	pub fn start_cut(&mut self, params: SurfaceGrinderCutParams) {
 		self.cut_params = params;
 		self.starting_height = self.position_client().get_axis_position(Axis::Z);
+		for axis in [Axis::X, Axis::Y, Axis::Z].iter() {
+			let position = self.position_client().get_axis_position(*axis);
+			self.planned_positions.insert(*axis, position);
+		}
 		self.set_state(CutState::ToStartingPositionX);
+		self.queue_next_move();
 	}
 
 	// FIXME: rename to procedure_in_progress or something, to cover homing?
@@ -132,8 +186,18 @@ impl SurfaceGrinderCutController {
 	}
 
 	fn set_spindle_on(&mut self, on: bool) {
-		self.send_to_motor_control(Message::SpindleControlMsgType(SpindleControlMsg{on}));
-		self.spindle_started_time = Instant::now();
+		// Motor control answers with the spindle's status, so wait for that rather than going by what it said before
+		self.spindle_at_speed = false;
+		self.send_to_motor_control(Message::SpindleControlMsgType(SpindleControlMsg{on, rpm: self.cut_params.spindle_rpm}));
+	}
+
+	/// Grinding with a wheel that's slowed down or stopped could ruin the work, or the wheel, so stop if the spindle drops out of speed mid-pass
+	fn handle_spindle_status(&mut self, msg: SpindleStatusMsg) {
+		self.spindle_at_speed = msg.at_speed;
+		if !msg.at_speed && self.state.needs_spindle() {
+			println!("Spindle dropped out of speed during the cut (measured {:?} rpm), abandoning cut", msg.rpm);
+			self.set_state(CutState::Idle);
+		}
 	}
 
 	fn move_axis_to_extent(&mut self, axis: Axis, end: AxisEnd) {
@@ -142,21 +206,50 @@ impl SurfaceGrinderCutController {
 	}
 
 	fn move_to_position(&mut self, axis: Axis, position: f64) {
+		self.planned_positions.insert(axis, position);
+		self.moves_in_progress += 1;
 		self.send_to_motor_control(Message::GoToPositionMsgType(GoToPositionMsg{axis, position, speed: self.cut_params.stroke_speed}));
 	}
 
 	fn move_relative(&mut self, axis: Axis, distance: f64) {
-		self.send_to_motor_control(Message::MoveAxisRelMsgType(MoveAxisRelMsg{axis, distance, speed: self.cut_params.stroke_speed}));
+		*self.planned_positions.get_mut(&axis).unwrap() += distance;
+		self.moves_in_progress += 1;
+		self.send_to_motor_control(Message::MoveAxisRelMsgType(MoveAxisRelMsg{axis, distance, speed: self.cut_params.stroke_speed, square: false}));
 	}
 
-	fn handle_movement_complete(&mut self, msg: MovementCompleteMsg) {
-		if self.cutting_in_progress() {
+	fn handle_movement_complete(&mut self, _msg: MovementCompleteMsg) {
+		if !self.cutting_in_progress() {
+			return;
+		}
+		self.moves_in_progress = self.moves_in_progress.saturating_sub(1);
+		if self.moves_in_progress == 0 {
+			// Nothing was queued up behind the move that just finished, so it's up to us to carry on
 			self.advance_state();
+		} else {
+			self.queue_next_move();
 		}
 	}
 
+	/// The cut can't be finished without the move, so abandon it
+	fn handle_move_rejected(&mut self, msg: MoveRejectedMsg) {
+		if !self.cutting_in_progress() {
+			return;
+		}
+		println!("Move of axis {} was rejected ({:?}), abandoning cut", msg.axis, msg.reason);
+		self.set_state(CutState::Idle);
+	}
+
 	fn advance_state(&mut self) {
 		self.set_state(self.get_next_state());
+		self.queue_next_move();
+	}
+
+	/// If only the current move is in progress, queue up the one after it (as long as it follows straight on, without waiting for anything else)
+	fn queue_next_move(&mut self) {
+		let next_state = self.get_next_state();
+		if self.moves_in_progress == 1 && self.state.is_move() && next_state.is_move() {
+			self.set_state(next_state);
+		}
 	}
 
 	fn get_next_state(&self) -> CutState {
@@ -204,7 +297,7 @@ impl SurfaceGrinderCutController {
 	}
 
 	fn depth_remaining(&self) -> f64 {
-		((self.starting_height - self.cut_params.total_depth) - self.position_client().get_axis_position(Axis::Z)).abs()
+		((self.starting_height - self.cut_params.total_depth) - self.planned_positions[&Axis::Z]).abs()
 	}
 
 	fn reached_extent(&self, axis: Axis, end: AxisEnd) -> bool {
@@ -214,20 +307,23 @@ impl SurfaceGrinderCutController {
 
 		// FIXME: might be better to work in steps? Floating point is annoying...
 		if end == AxisEnd::Min {
-			self.position_client().get_axis_position(axis) <= self.work_envelope().get_extent(axis, end)
+			self.planned_positions[&axis] <= self.work_envelope().get_extent(axis, end)
 		} else {
-			self.position_client().get_axis_position(axis) >= self.work_envelope().get_extent(axis, end)
+			self.planned_positions[&axis] >= self.work_envelope().get_extent(axis, end)
 		}
 	}
 
 	fn close_enough(&self, axis: Axis, position: f64) -> bool {
 		let axis_config = self.config_client().config.motor_configs.get(&axis).unwrap();
-		let current_step = axis_config.inches_to_steps(self.position_client().get_axis_position(axis));
-		let target_step = axis_config.inches_to_steps(position);
-		target_step == current_step
+		let current_step = axis_config.position_to_steps(self.planned_positions[&axis]);
+		let target_step = axis_config.position_to_steps(position);
+		match (current_step, target_step) {
+			(Ok(current_step), Ok(target_step)) => target_step == current_step,
+			_ => false,
+		}
 	}
 
 	fn distance_to_extent(&self, axis: Axis, extent: AxisEnd) -> f64 {
-		(self.work_envelope().get_extent(axis, extent) - self.position_client().get_axis_position(axis)).abs()
+		(self.work_envelope().get_extent(axis, extent) - self.planned_positions[&axis]).abs()
 	}
 }
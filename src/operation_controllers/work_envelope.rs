@@ -1,38 +1,55 @@
 use crate::common::Axis;
 use crate::common::AxisEnd;
+use crate::common::NANOMETRES_PER_INCH;
+use crate::machine_state::MachineState;
+use crate::messages::AxisLimits;
 
+use std::collections::HashMap;
 
 
+
+/// Extent of the placeholder envelope before homing: 256 inches each way, in internal units
+const UNHOMED_EXTENT: f64 = 256.0 * NANOMETRES_PER_INCH;
+
 #[derive(Clone)]
 pub struct WorkEnvelope {
-	pub min_x: f64,
-	pub min_y: f64,
-	pub min_z: f64,
-	pub max_x: f64,
-	pub max_y: f64,
-	pub max_z: f64,
+	/// Extents of the axes that homing finds them for. Any other axis has the placeholder extents.
+	pub extents: HashMap<Axis, AxisLimits>,
+	/// Whether homing has found the extents. Until then they're just placeholders.
+	pub homed: bool,
 }
 
 impl WorkEnvelope {
-	pub fn new() -> Self {
+	/// The envelope as it was when the controller last stopped
+	pub fn from_state(state: &MachineState) -> Self {
 		WorkEnvelope{
-			min_x: -256.0,
-			min_y: -256.0,
-			min_z: 0.0,
-			max_x: 256.0,
-			max_y: 256.0,
-			max_z: 0.0,
+			extents: state.envelope.clone(),
+			homed: state.homed,
 		}
 	}
 
 	pub fn get_extent(&self, axis: Axis, end: AxisEnd) -> f64 {
-		match (axis, end) {
-			(Axis::X, AxisEnd::Min) => self.min_x,
-			(Axis::Y, AxisEnd::Min) => self.min_y,
-			(Axis::Z, AxisEnd::Min) => self.min_z,
-			(Axis::X, AxisEnd::Max) => self.max_x,
-			(Axis::Y, AxisEnd::Max) => self.max_y,
-			(Axis::Z, AxisEnd::Max) => self.max_z,
+		let limits = self.extents.get(&axis).copied().unwrap_or(AxisLimits{min: -UNHOMED_EXTENT, max: UNHOMED_EXTENT});
+		match end {
+			AxisEnd::Min => limits.min,
+			AxisEnd::Max => limits.max,
+		}
+	}
+
+	pub fn set_extent(&mut self, axis: Axis, end: AxisEnd, position: f64) {
+		let limits = self.extents.entry(axis).or_insert(AxisLimits{min: -UNHOMED_EXTENT, max: UNHOMED_EXTENT});
+		match end {
+			AxisEnd::Min => limits.min = position,
+			AxisEnd::Max => limits.max = position,
+		}
+	}
+
+	/// Limits for motor control to keep moves within, for the axes that homing finds the extents of. Empty if we haven't homed.
+	pub fn get_soft_limits(&self) -> HashMap<Axis, AxisLimits> {
+		if self.homed {
+			self.extents.clone()
+		} else {
+			HashMap::new()
 		}
 	}
 }
@@ -0,0 +1,197 @@
+mod pin_driver;
+mod vfd_driver;
+
+use self::pin_driver::PinSpindleDriver;
+use self::vfd_driver::VfdSpindleDriver;
+
+use crate::config::RustGrindConfig;
+use crate::config::SpindleAtSpeedConfig;
+use crate::config::SpindleDriverConfig;
+use crate::hardware::HardwareBackend;
+use crate::hardware::HardwareError;
+use crate::messages::Message;
+use crate::messages::SpindleFault;
+use crate::messages::SpindleFaultMsg;
+use crate::messages::SpindleStatusMsg;
+
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc;
+use std::time::Duration;
+use std::time::Instant;
+
+
+
+/// Something that runs the spindle, as selected by `RustGrindConfig::spindle_driver`
+pub trait SpindleDriver: Send {
+	/// Switch the spindle on or off. If it's given, the speed is set too; otherwise it runs at the last speed asked for (or full speed).
+	fn set(&mut self, on: bool, rpm: Option<f64>) -> Result<(), HardwareError>;
+
+	/// Carry on with anything that takes a while, e.g. ramping the speed. Called regularly by motor control.
+	fn update(&mut self) -> Result<(), HardwareError>;
+
+	/// Speed the spindle is being driven at, once any ramp is done. None while it's off, or if the driver has no control of its speed.
+	fn target_rpm(&self) -> Option<f64>;
+}
+
+
+/// Keep a requested speed within what the spindle can do, or None if it isn't a speed at all
+fn limit_speed(rpm: f64, min_rpm: f64, max_rpm: f64) -> Option<f64> {
+	if !rpm.is_finite() {
+		println!("Ignoring invalid spindle speed {}", rpm);
+		return None;
+	}
+	let limited = rpm.max(min_rpm).min(max_rpm);
+	if limited != rpm {
+		println!("Spindle speed {} rpm is out of range, using {} rpm", rpm, limited);
+	}
+	Some(limited)
+}
+
+
+/// Make the configured spindle driver. Any measurements (SpindleSpeedMsg) and faults (SpindleFaultMsg) it has to report are sent with the given sender.
+fn make_driver(config: &RustGrindConfig, hardware: &dyn HardwareBackend, sender: Sender<Message>) -> Result<Box<dyn SpindleDriver>, HardwareError> {
+	match &config.spindle_driver {
+		SpindleDriverConfig::Pins => Ok(Box::new(PinSpindleDriver::new(config, hardware)?)),
+		SpindleDriverConfig::ModbusVfd(vfd_config) => Ok(Box::new(VfdSpindleDriver::new(vfd_config, hardware, sender)?)),
+	}
+}
+
+
+
+/**
+ * Runs the spindle through the configured driver, and works out from its speed feedback (if there is any) whether it's up to speed.
+ * The operation controllers are told whenever that changes.
+ */
+pub struct SpindleController {
+	driver: Box<dyn SpindleDriver>,
+	/// Measurements and faults reported by the driver itself
+	driver_feedback: Receiver<Message>,
+	/// Where to send status updates and faults on to
+	sender: Sender<Message>,
+	at_speed_config: SpindleAtSpeedConfig,
+	/// Whether the speed is measured by a tachometer, in which case the driver's own measurements are ignored
+	has_tachometer: bool,
+	/// Whether the speed is measured at all. If it isn't, the spindle is taken to be up to speed once it's had time to spin up.
+	has_speed_feedback: bool,
+	measured_rpm: Option<f64>,
+	/// When the spindle was switched on, or last asked for a new speed. None while it's off.
+	started_time: Option<Instant>,
+	/// Whether the spindle is still coming up to speed since then, so it's a fault if it takes too long
+	spinning_up: bool,
+	last_status: Option<SpindleStatusMsg>,
+}
+
+impl SpindleController {
+	pub fn new(config: &RustGrindConfig, hardware: &dyn HardwareBackend, sender: Sender<Message>) -> Result<Self, HardwareError> {
+		let (feedback_sender, driver_feedback) = mpsc::channel();
+		let has_tachometer = config.spindle_tachometer.is_some();
+		let driver_has_feedback = match config.spindle_driver {
+			SpindleDriverConfig::Pins => false,
+			SpindleDriverConfig::ModbusVfd(_) => true,
+		};
+		Ok(SpindleController {
+			driver: make_driver(config, hardware, feedback_sender)?,
+			driver_feedback,
+			sender,
+			at_speed_config: config.spindle_at_speed,
+			has_tachometer,
+			has_speed_feedback: has_tachometer || driver_has_feedback,
+			measured_rpm: None,
+			started_time: None,
+			spinning_up: false,
+			last_status: None,
+		})
+	}
+
+	/// Switch the spindle on or off, and set its speed if one's given. Whoever asked always gets a status update back, to tell them whether it's at speed yet.
+	pub fn set(&mut self, on: bool, rpm: Option<f64>) -> Result<(), HardwareError> {
+		let old_target = self.driver.target_rpm();
+		let result = self.driver.set(on, rpm);
+		if !on {
+			self.started_time = None;
+			self.spinning_up = false;
+		} else if self.started_time.is_none() || self.driver.target_rpm() != old_target {
+			self.started_time = Some(Instant::now());
+			self.spinning_up = true;
+		}
+		self.send_status(true);
+		result
+	}
+
+	/// Speed measured by the tachometer
+	pub fn handle_tachometer_speed(&mut self, rpm: f64) {
+		self.measured_rpm = Some(rpm);
+	}
+
+	/// Keep the driver going, take in its feedback, and send on any change of status
+	pub fn update(&mut self) {
+		if let Err(error) = self.driver.update() {
+			println!("Encountered error updating spindle, error is {:?}", error);
+		}
+		while let Ok(msg) = self.driver_feedback.try_recv() {
+			match msg {
+				Message::SpindleSpeedMsgType(ss_msg) if !self.has_tachometer => self.measured_rpm = Some(ss_msg.rpm),
+				Message::SpindleFaultMsgType(_) => self.send(msg),
+				_ => {},
+			}
+		}
+		self.check_spin_up();
+		self.send_status(false);
+	}
+
+	/// Report a fault if the spindle's taken too long to come up to speed
+	fn check_spin_up(&mut self) {
+		let started_time = match self.started_time {
+			Some(started_time) if self.spinning_up => started_time,
+			_ => return,
+		};
+		if self.is_at_speed() {
+			self.spinning_up = false;
+		} else if started_time.elapsed() >= Duration::from_secs_f64(self.at_speed_config.timeout_secs) {
+			println!("Spindle not up to speed after {} seconds", self.at_speed_config.timeout_secs);
+			self.spinning_up = false;
+			self.send(Message::SpindleFaultMsgType(SpindleFaultMsg{fault: SpindleFault::NotUpToSpeed}));
+		}
+	}
+
+	fn is_at_speed(&self) -> bool {
+		let started_time = match self.started_time {
+			Some(started_time) => started_time,
+			None => return false,
+		};
+		let spun_up = started_time.elapsed() >= Duration::from_secs_f64(self.at_speed_config.spin_up_secs);
+		if !self.has_speed_feedback {
+			return spun_up;
+		}
+		let measured_rpm = self.measured_rpm.unwrap_or(0.0);
+		match self.driver.target_rpm() {
+			Some(target_rpm) => (measured_rpm - target_rpm).abs() <= target_rpm * self.at_speed_config.tolerance_percent / 100.0,
+			// No telling what speed it should be at, so the best we can do is give it time to spin up, and make sure it's turning
+			None => spun_up && measured_rpm > 0.0,
+		}
+	}
+
+	/// Send the status if it's changed (the measured speed on its own doesn't count), or whether or not it has
+	fn send_status(&mut self, always: bool) {
+		let status = SpindleStatusMsg {
+			on: self.started_time.is_some(),
+			at_speed: self.is_at_speed(),
+			rpm: self.measured_rpm,
+		};
+		let changed = match self.last_status {
+			Some(last_status) => (last_status.on, last_status.at_speed) != (status.on, status.at_speed),
+			None => true,
+		};
+		if always || changed {
+			self.last_status = Some(status);
+			self.send(Message::SpindleStatusMsgType(status));
+		}
+	}
+
+	fn send(&self, msg: Message) {
+		if self.sender.send(msg).is_err() {
+			println!("Nothing is listening for spindle status");
+		}
+	}
+}
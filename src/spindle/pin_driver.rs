@@ -0,0 +1,114 @@
+use super::SpindleDriver;
+use super::limit_speed;
+
+use crate::config::RustGrindConfig;
+use crate::config::SpindlePwmConfig;
+use crate::hardware::DigitalOutput;
+use crate::hardware::HardwareBackend;
+use crate::hardware::HardwareError;
+use crate::hardware::PwmOutput;
+
+use std::time::Instant;
+
+
+
+/// The speed output, if the spindle has one
+struct SpindleSpeedOutput {
+	pwm: Box<dyn PwmOutput>,
+	config: SpindlePwmConfig,
+}
+
+/**
+ * Drives the spindle with output pins: the enable pin switches it on and off, and the PWM output (if any) sets its speed.
+ * Speed changes are ramped, so update() needs calling regularly to carry them out.
+ */
+pub struct PinSpindleDriver {
+	enable_pin: Box<dyn DigitalOutput>,
+	speed_output: Option<SpindleSpeedOutput>,
+	on: bool,
+	/// Speed to run at while on, once the ramp is done
+	target_rpm: f64,
+	/// Speed being output at the moment, partway along the ramp. 0 while stopped.
+	current_rpm: f64,
+	last_update: Instant,
+}
+
+impl PinSpindleDriver {
+	pub fn new(config: &RustGrindConfig, hardware: &dyn HardwareBackend) -> Result<Self, HardwareError> {
+		let enable_pin = hardware.make_spindle_pin(config.spindle_enable_pin)?;
+		let speed_output = match &config.spindle_pwm {
+			Some(pwm_config) => {
+				let mut pwm = hardware.make_spindle_pwm(pwm_config)?;
+				pwm.set_duty_cycle(0.0)?;
+				Some(SpindleSpeedOutput{pwm, config: pwm_config.clone()})
+			},
+			None => None,
+		};
+		let target_rpm = speed_output.as_ref().map(|output| output.config.max_rpm).unwrap_or(0.0);
+		Ok(PinSpindleDriver {
+			enable_pin,
+			speed_output,
+			on: false,
+			target_rpm,
+			current_rpm: 0.0,
+			last_update: Instant::now(),
+		})
+	}
+}
+
+impl SpindleDriver for PinSpindleDriver {
+	fn set(&mut self, on: bool, rpm: Option<f64>) -> Result<(), HardwareError> {
+		if let (Some(rpm), Some(output)) = (rpm, &self.speed_output) {
+			if let Some(rpm) = limit_speed(rpm, output.config.min_rpm, output.config.max_rpm) {
+				self.target_rpm = rpm;
+			}
+		}
+		self.on = on;
+		if self.speed_output.is_none() {
+			// Nothing to ramp, just the switch
+			return if on { self.enable_pin.set_high() } else { self.enable_pin.set_low() };
+		}
+		self.update()
+	}
+
+	/// Move the speed along its ramp, towards the target (or towards stopping, if the spindle's been switched off)
+	fn update(&mut self) -> Result<(), HardwareError> {
+		let now = Instant::now();
+		let elapsed = now.duration_since(self.last_update).as_secs_f64();
+		self.last_update = now;
+		let output = match &mut self.speed_output {
+			Some(output) => output,
+			None => return Ok(()),
+		};
+
+		let target = if self.on { self.target_rpm } else { 0.0 };
+		if target == self.current_rpm {
+			return Ok(());
+		}
+		if target > self.current_rpm {
+			// The spindle won't turn below min_rpm, so start from there
+			let start = self.current_rpm.max(output.config.min_rpm.min(target));
+			self.current_rpm = (start + output.config.ramp_up_rpm_per_sec * elapsed).min(target);
+		} else {
+			self.current_rpm = (self.current_rpm - output.config.ramp_down_rpm_per_sec * elapsed).max(target);
+			if self.current_rpm < output.config.min_rpm {
+				// Too slow to keep turning, so that's as far as the ramp goes
+				self.current_rpm = 0.0;
+			}
+		}
+
+		output.pwm.set_duty_cycle(output.config.rpm_to_duty_cycle(self.current_rpm))?;
+		if self.current_rpm > 0.0 {
+			self.enable_pin.set_high()
+		} else {
+			self.enable_pin.set_low()
+		}
+	}
+
+	fn target_rpm(&self) -> Option<f64> {
+		match self.speed_output {
+			Some(_) if self.on => Some(self.target_rpm),
+			_ => None,
+		}
+	}
+}
@@ -0,0 +1,212 @@
+use super::SpindleDriver;
+use super::limit_speed;
+
+use crate::config::ModbusVfdConfig;
+use crate::hardware::HardwareBackend;
+use crate::hardware::HardwareError;
+use crate::messages::Message;
+use crate::messages::SpindleFault;
+use crate::messages::SpindleFaultMsg;
+use crate::messages::SpindleSpeedMsg;
+use crate::modbus::ModbusRtuClient;
+
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::Sender;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+
+
+/// Number of times in a row the VFD can fail to answer before it's reported as not responding
+const NO_RESPONSE_LIMIT: u32 = 3;
+
+
+
+/// What the VFD should be doing
+#[derive(Copy, Clone)]
+struct VfdCommand {
+	on: bool,
+	rpm: f64,
+}
+
+/**
+ * Spindle run by a VFD over Modbus RTU.
+ * A transaction takes several milliseconds at the usual baud rates, so the talking is done by a thread of its own, which motor control passes commands to.
+ */
+pub struct VfdSpindleDriver {
+	config: ModbusVfdConfig,
+	command_sender: Sender<VfdCommand>,
+	on: bool,
+	/// Speed to run at while on
+	target_rpm: f64,
+}
+
+impl VfdSpindleDriver {
+	pub fn new(config: &ModbusVfdConfig, hardware: &dyn HardwareBackend, msg_sender: Sender<Message>) -> Result<Self, HardwareError> {
+		let port = hardware.make_vfd_port(config)?;
+		let client = ModbusRtuClient::new(port, config.address, config.baud_rate)?;
+		let (command_sender, command_receiver) = mpsc::channel();
+		let mut link = VfdLink {
+			client,
+			config: config.clone(),
+			commands: command_receiver,
+			msg_sender,
+			// Make sure the spindle starts out stopped
+			pending: Some(VfdCommand{on: false, rpm: 0.0}),
+			last_rpm: None,
+			fault: None,
+			failed_transactions: 0,
+		};
+		let builder = thread::Builder::new().name("VfdLink".to_string());
+		builder.spawn(move || link.run())?;
+		Ok(VfdSpindleDriver {
+			config: config.clone(),
+			command_sender,
+			on: false,
+			target_rpm: config.max_rpm,
+		})
+	}
+}
+
+impl SpindleDriver for VfdSpindleDriver {
+	fn set(&mut self, on: bool, rpm: Option<f64>) -> Result<(), HardwareError> {
+		if let Some(rpm) = rpm {
+			if let Some(rpm) = limit_speed(rpm, self.config.min_rpm, self.config.max_rpm) {
+				self.target_rpm = rpm;
+			}
+		}
+		self.on = on;
+		match self.command_sender.send(VfdCommand{on, rpm: self.target_rpm}) {
+			Ok(()) => Ok(()),
+			Err(_) => Err("The VFD link thread has stopped".into()),
+		}
+	}
+
+	fn update(&mut self) -> Result<(), HardwareError> {
+		// The VFD does its own ramping
+		Ok(())
+	}
+
+	fn target_rpm(&self) -> Option<f64> {
+		if self.on {
+			Some(self.target_rpm)
+		} else {
+			None
+		}
+	}
+}
+
+
+/// Talks to the VFD: passes on commands, and reads back its speed and faults
+struct VfdLink {
+	client: ModbusRtuClient,
+	config: ModbusVfdConfig,
+	commands: Receiver<VfdCommand>,
+	msg_sender: Sender<Message>,
+	/// Latest command, until it's been written to the VFD
+	pending: Option<VfdCommand>,
+	last_rpm: Option<f64>,
+	fault: Option<SpindleFault>,
+	failed_transactions: u32,
+}
+
+impl VfdLink {
+	fn run(&mut self) {
+		let poll_interval = Duration::from_millis(self.config.poll_interval_ms);
+		loop {
+			match self.commands.recv_timeout(poll_interval) {
+				Ok(command) => self.pending = Some(command),
+				Err(RecvTimeoutError::Timeout) => {},
+				Err(RecvTimeoutError::Disconnected) => {
+					// Nothing's in control of the spindle any more, so stop it
+					if let Err(error) = self.write_command(VfdCommand{on: false, rpm: 0.0}) {
+						println!("Couldn't stop the VFD, error is {:?}", error);
+					}
+					return;
+				},
+			}
+			// Only the latest command matters
+			while let Ok(command) = self.commands.try_recv() {
+				self.pending = Some(command);
+			}
+
+			if let Some(command) = self.pending {
+				match self.write_command(command) {
+					Ok(()) => self.pending = None,
+					Err(error) => self.transaction_failed(error),
+				}
+			}
+			self.poll();
+		}
+	}
+
+	fn write_command(&mut self, command: VfdCommand) -> Result<(), HardwareError> {
+		let registers = self.config.registers;
+		if command.on {
+			let counts = self.config.rpm_to_frequency_counts(command.rpm) as u16;
+			self.client.write_single_register(registers.frequency_setpoint, counts)?;
+			self.client.write_single_register(registers.control, registers.run_forward)
+		} else {
+			self.client.write_single_register(registers.control, registers.stop)
+		}
+	}
+
+	/// Read back the speed and fault status, and report any change
+	fn poll(&mut self) {
+		let registers = self.config.registers;
+		let status = self.client.read_holding_registers(registers.output_frequency, 1)
+			.and_then(|frequency| Ok((frequency[0], self.client.read_holding_registers(registers.fault_code, 1)?[0])));
+		let (frequency_counts, fault_code) = match status {
+			Ok(status) => status,
+			Err(error) => return self.transaction_failed(error),
+		};
+		if self.failed_transactions >= NO_RESPONSE_LIMIT {
+			println!("VFD is answering again");
+		}
+		self.failed_transactions = 0;
+		if self.fault == Some(SpindleFault::NoResponse) {
+			self.fault = None;
+		}
+
+		let rpm = self.config.frequency_counts_to_rpm(frequency_counts);
+		if self.last_rpm != Some(rpm) {
+			self.last_rpm = Some(rpm);
+			self.send(Message::SpindleSpeedMsgType(SpindleSpeedMsg{rpm}));
+		}
+		if fault_code != 0 {
+			self.report_fault(SpindleFault::VfdFault(fault_code));
+		} else if self.fault.is_some() {
+			println!("VFD fault has cleared");
+			self.fault = None;
+		}
+	}
+
+	fn transaction_failed(&mut self, error: HardwareError) {
+		self.failed_transactions += 1;
+		// Only the first failure is logged, so a disconnected VFD doesn't flood the log
+		if self.failed_transactions == 1 {
+			println!("Modbus transaction with the VFD failed, error is {:?}", error);
+		}
+		if self.failed_transactions == NO_RESPONSE_LIMIT {
+			self.report_fault(SpindleFault::NoResponse);
+		}
+	}
+
+	/// Send a fault on, unless it's already been reported
+	fn report_fault(&mut self, fault: SpindleFault) {
+		if self.fault == Some(fault) {
+			return;
+		}
+		println!("Reporting spindle fault {:?}", fault);
+		self.fault = Some(fault);
+		self.send(Message::SpindleFaultMsgType(SpindleFaultMsg{fault}));
+	}
+
+	fn send(&self, msg: Message) {
+		if self.msg_sender.send(msg).is_err() {
+			println!("Nothing is listening for spindle status");
+		}
+	}
+}
@@ -0,0 +1,535 @@
+use crate::common::Axis;
+use crate::common::AxisEnd;
+use crate::common::AxisMotor;
+use crate::config::MotorConfig;
+use crate::config::StepGeneratorConfig;
+use crate::hardware::DigitalOutput;
+use crate::hardware::HardwareError;
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicI64;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::Receiver;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::mpsc::SyncSender;
+use std::sync::mpsc::TryRecvError;
+use std::sync::mpsc::TrySendError;
+use std::sync::mpsc;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
+
+use nix::sched::sched_setaffinity;
+use nix::sched::CpuSet;
+use nix::sys::mman::mlockall;
+use nix::sys::mman::MlockAllFlags;
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::ClockId;
+use nix::sys::timerfd::Expiration;
+use nix::sys::timerfd::TimerFd;
+use nix::sys::timerfd::TimerFlags;
+use nix::sys::timerfd::TimerSetTimeFlags;
+use nix::time::clock_gettime;
+use nix::unistd::Pid;
+
+
+
+/// Number of segments that can be waiting for the step generator. This is how far ahead the planner works, so it's also roughly how long it takes for a feed hold or override to take effect.
+const STEP_BUFFER_SEGMENTS: usize = 16;
+/// How often the step generator checks in when there's nothing to do
+const IDLE_POLL_INTERVAL: Duration = Duration::from_millis(1);
+/// How long a flush can take before the step generator counts as stuck. It normally notices within a step or an idle poll.
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(1);
+
+
+/// Busy-wait for very short delays, such as step pulses, which are far below what any timer can reliably do.
+fn spin_wait(duration: Duration) {
+	let start = Instant::now();
+	while start.elapsed() < duration {}
+}
+
+
+
+/**
+ * Waits for the time of each step on a timerfd, so the step generator thread sleeps in between steps instead of keeping a core busy.
+ * The timer is set to absolute times, so being held up while setting it doesn't make the wait any longer.
+ */
+struct StepTimer {
+	timer: TimerFd,
+	/// The same moment as an Instant and as a CLOCK_MONOTONIC time, to convert between the two (Instant uses CLOCK_MONOTONIC underneath, so they don't drift apart)
+	reference_instant: Instant,
+	reference_time: Duration,
+	/// How long before each deadline to wake up, and busy-wait the rest of the way
+	spin_margin: Duration,
+}
+
+impl StepTimer {
+	fn new(spin_margin: Duration) -> Result<Self, HardwareError> {
+		let timer = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::TFD_CLOEXEC)?;
+		let reference_instant = Instant::now();
+		let reference_time = Duration::from(clock_gettime(nix::time::ClockId::CLOCK_MONOTONIC)?);
+		Ok(StepTimer {
+			timer,
+			reference_instant,
+			reference_time,
+			spin_margin,
+		})
+	}
+
+	fn wait_until(&self, time: Instant) {
+		let wake_time = time.checked_sub(self.spin_margin).unwrap_or(time);
+		if wake_time > Instant::now() {
+			let deadline = self.reference_time + (wake_time - self.reference_instant);
+			let result = self.timer.set(Expiration::OneShot(TimeSpec::from(deadline)), TimerSetTimeFlags::TFD_TIMER_ABSTIME)
+				.and_then(|_| self.timer.wait());
+			if let Err(error) = result {
+				println!("Step timer failed, so busy-waiting instead. Error is {:?}", error);
+			}
+		}
+		while Instant::now() < time {}
+	}
+}
+
+/// How late the steps were, compared with the schedule
+#[derive(Copy, Clone)]
+pub struct StepTiming {
+	pub steps: u64,
+	pub mean_lateness: Duration,
+	pub max_lateness: Duration,
+}
+
+
+
+/// A single step on one axis, at a time relative to the start of its segment
+#[derive(Copy, Clone)]
+pub struct StepEvent {
+	pub offset: Duration,
+	pub axis: Axis,
+	/// Which way to step, in logical terms (i.e. before applying the motor's reversal)
+	pub direction: AxisEnd,
+	/// Only moves the motors, to take up the slack in the leadscrew, so it doesn't count towards the axis's logical position
+	pub takes_up_backlash: bool,
+}
+
+/**
+ * A short stretch of the step schedule, produced by the motion planner.
+ * Segments are executed back to back, so the step generator's timing carries over from one to the next.
+ */
+pub struct StepSegment {
+	pub id: u64,
+	/// Segments from before the last flush are thrown away
+	generation: u64,
+	pub duration: Duration,
+	/// Steps in order of their offset
+	pub steps: Vec<StepEvent>,
+}
+
+/// State shared between the step generator thread and its client
+struct StepGeneratorShared {
+	/// Bumped to throw away everything that's been buffered
+	generation: AtomicU64,
+	/// Last generation the step generator has seen, so the client knows when a flush has taken effect
+	acked_generation: AtomicU64,
+	/// ID of the last segment that was executed all the way through
+	executed_segment_id: AtomicU64,
+	/// Logical step count of each axis, as actually stepped by its main motor
+	positions: HashMap<Axis, AtomicI64>,
+	/// Logical step count of the slave motor of each axis that has one
+	slave_positions: HashMap<Axis, AtomicI64>,
+	/// Steps of each axis's backlash that have been taken up towards its Max end
+	slack: HashMap<Axis, AtomicI64>,
+	/// Motors that are being kept still while the rest of their axis moves on, e.g. to square a gantry against its endstops
+	held: HashMap<(Axis, AxisMotor), AtomicBool>,
+	/// Steps taken, and how late they were in total and at worst, since the client last took the figures
+	timed_steps: AtomicU64,
+	total_lateness_nanos: AtomicU64,
+	max_lateness_nanos: AtomicU64,
+}
+
+impl StepGeneratorShared {
+	fn is_held(&self, axis: Axis, motor: AxisMotor) -> bool {
+		self.held[&(axis, motor)].load(Ordering::SeqCst)
+	}
+
+	fn record_lateness(&self, lateness: Duration) {
+		let nanos = lateness.as_nanos() as u64;
+		self.timed_steps.fetch_add(1, Ordering::SeqCst);
+		self.total_lateness_nanos.fetch_add(nanos, Ordering::SeqCst);
+		self.max_lateness_nanos.fetch_max(nanos, Ordering::SeqCst);
+	}
+}
+
+
+/// Step and direction pins of one of an axis's motors
+struct MotorStepPins {
+	motor: AxisMotor,
+	step_pin: Box<dyn DigitalOutput>,
+	direction_pin: Box<dyn DigitalOutput>,
+	/// Direction currently set on the direction pin, as the motor sees it. None if it hasn't been set yet.
+	motor_forward: Option<bool>,
+}
+
+impl MotorStepPins {
+	/// Set the direction pin, returning whether it changed
+	fn set_direction(&mut self, motor_forward: bool) -> Result<bool, HardwareError> {
+		if self.motor_forward == Some(motor_forward) {
+			return Ok(false);
+		}
+		if motor_forward {
+			self.direction_pin.set_high()?;
+		} else {
+			self.direction_pin.set_low()?;
+		}
+		self.motor_forward = Some(motor_forward);
+		Ok(true)
+	}
+
+	fn pulse(&mut self, pulse_length: Duration) -> Result<(), HardwareError> {
+		self.step_pin.set_high()?;
+		spin_wait(pulse_length);
+		self.step_pin.set_low()
+	}
+}
+
+/**
+ * Step and direction pins of an axis's motors, driven by the step generator. An axis with two motors (e.g. one on each side of a gantry) steps them in lockstep, except while one is held.
+ */
+pub struct AxisStepper {
+	/// Main motor first
+	motors: Vec<MotorStepPins>,
+	config: MotorConfig,
+	/// Timing the driver needs, from its config
+	step_pulse_length: Duration,
+	direction_setup_time: Duration,
+}
+
+impl AxisStepper {
+	pub fn new(config: MotorConfig, step_pin: Box<dyn DigitalOutput>, direction_pin: Box<dyn DigitalOutput>) -> Self {
+		AxisStepper {
+			motors: vec![MotorStepPins{motor: AxisMotor::Main, step_pin, direction_pin, motor_forward: None}],
+			step_pulse_length: config.driver.step_pulse_length(),
+			direction_setup_time: config.driver.direction_setup_time(),
+			config,
+		}
+	}
+
+	/// Add the axis's slave motor, which steps along with the main one
+	pub fn add_slave(&mut self, step_pin: Box<dyn DigitalOutput>, direction_pin: Box<dyn DigitalOutput>) {
+		self.motors.push(MotorStepPins{motor: AxisMotor::Slave, step_pin, direction_pin, motor_forward: None});
+	}
+
+	/// Motors this axis drives
+	fn motor_ids(&self) -> Vec<AxisMotor> {
+		self.motors.iter().map(|motor| motor.motor).collect()
+	}
+
+	/// Step the axis's motors once, skipping the held ones. Returns the logical step taken.
+	fn step(&mut self, direction: AxisEnd, is_held: impl Fn(AxisMotor) -> bool) -> Result<i64, HardwareError> {
+		let logical_step = if direction == AxisEnd::Max { 1 } else { -1 };
+		let config = &self.config;
+		let mut stepping: Vec<&mut MotorStepPins> = self.motors.iter_mut().filter(|motor| !is_held(motor.motor)).collect();
+		let mut direction_changed = false;
+		for motor in stepping.iter_mut() {
+			let motor_forward = config.apply_reversal(motor.motor, logical_step) > 0;
+			direction_changed |= motor.set_direction(motor_forward)?;
+		}
+		if direction_changed {
+			spin_wait(self.direction_setup_time);
+		}
+		for motor in stepping.iter_mut() {
+			motor.pulse(self.step_pulse_length)?;
+		}
+		Ok(logical_step)
+	}
+}
+
+
+
+/**
+ * Executes the step schedule built by the motion planner, on its own high-priority thread.
+ * It does nothing except wait for the time of each step and pulse the pins, so nothing else the controller is doing can throw off the step timing.
+ *
+ * Steps are timed here rather than by the stepper crate's SoftwareMotionControl, which the motors used to be driven with.
+ * That runs each motor through its own moves on its own ramp, and keeps the pins to itself, so it can't keep several axes on one line, or carry the speed from one move into the next.
+ */
+struct StepGenerator {
+	receiver: Receiver<StepSegment>,
+	steppers: HashMap<Axis, AxisStepper>,
+	shared: Arc<StepGeneratorShared>,
+	timer: StepTimer,
+	/// When the next segment should start, if we're running continuously.
+	/// If we fall behind (e.g. the thread was held up), the following segments are run late to catch up, so the moves still take as long as planned.
+	next_segment_start: Option<Instant>,
+}
+
+impl StepGenerator {
+	fn run(&mut self) {
+		loop {
+			let generation = self.shared.generation.load(Ordering::SeqCst);
+			self.shared.acked_generation.store(generation, Ordering::SeqCst);
+			let segment = match self.receiver.try_recv() {
+				Ok(segment) => segment,
+				Err(TryRecvError::Empty) => {
+					// Ran out of segments, so there's no schedule to keep to anymore; the next segment starts whenever it arrives
+					self.next_segment_start = None;
+					match self.receiver.recv_timeout(IDLE_POLL_INTERVAL) {
+						Ok(segment) => segment,
+						Err(RecvTimeoutError::Timeout) => continue,
+						// Motor control is gone, so nothing will ever need stepping again
+						Err(RecvTimeoutError::Disconnected) => return,
+					}
+				},
+				Err(TryRecvError::Disconnected) => return,
+			};
+			if segment.generation != self.shared.generation.load(Ordering::SeqCst) {
+				// Flushed
+				self.next_segment_start = None;
+				continue;
+			}
+			self.execute(segment);
+		}
+	}
+
+	fn execute(&mut self, segment: StepSegment) {
+		let start = self.next_segment_start.unwrap_or_else(Instant::now);
+		for event in segment.steps.iter() {
+			let step_time = start + event.offset;
+			self.timer.wait_until(step_time);
+			if segment.generation != self.shared.generation.load(Ordering::SeqCst) {
+				// Flushed partway through, e.g. because an endstop was hit. Stop dead.
+				self.next_segment_start = None;
+				return;
+			}
+			self.shared.record_lateness(Instant::now().saturating_duration_since(step_time));
+			if let Some(stepper) = self.steppers.get_mut(&event.axis) {
+				let shared = &self.shared;
+				match stepper.step(event.direction, |motor| shared.is_held(event.axis, motor)) {
+					Ok(step) if event.takes_up_backlash => {
+						shared.slack[&event.axis].fetch_add(step, Ordering::SeqCst);
+					},
+					Ok(step) => {
+						if !shared.is_held(event.axis, AxisMotor::Main) {
+							shared.positions[&event.axis].fetch_add(step, Ordering::SeqCst);
+						}
+						if let Some(slave_position) = shared.slave_positions.get(&event.axis) {
+							if !shared.is_held(event.axis, AxisMotor::Slave) {
+								slave_position.fetch_add(step, Ordering::SeqCst);
+							}
+						}
+					},
+					Err(error) => println!("Encountered error stepping axis {}, error is {:?}", event.axis, error),
+				}
+			}
+		}
+		// Wait out the rest of the segment, so the next one starts on time
+		let end = start + segment.duration;
+		self.timer.wait_until(end);
+		self.next_segment_start = Some(end);
+		self.shared.executed_segment_id.store(segment.id, Ordering::SeqCst);
+	}
+}
+
+/// Make the current thread as real-time as the system allows. Failures are only logged, since the machine still works without (just with worse step timing).
+fn set_up_realtime_thread(config: &StepGeneratorConfig) {
+	if config.lock_memory {
+		// Page faults would stall the step generator for far longer than a step
+		if let Err(error) = mlockall(MlockAllFlags::MCL_CURRENT | MlockAllFlags::MCL_FUTURE) {
+			println!("Could not lock memory for the step generator: {}", error);
+		}
+	}
+	if let Some(cpu) = config.cpu {
+		let mut cpu_set = CpuSet::new();
+		let result = cpu_set.set(cpu).and_then(|_| sched_setaffinity(Pid::from_raw(0), &cpu_set));
+		if let Err(error) = result {
+			println!("Could not pin the step generator to CPU {}: {}", cpu, error);
+		}
+	}
+	// Threads with normal scheduling have their timers allowed to run late by 50us by default, to batch up wakeups
+	if unsafe { libc::prctl(libc::PR_SET_TIMERSLACK, 1) } != 0 {
+		println!("Could not reduce the step generator's timer slack: {}", io::Error::last_os_error());
+	}
+	if config.realtime_priority > 0 {
+		let param = libc::sched_param{sched_priority: config.realtime_priority};
+		// nix doesn't wrap sched_setscheduler, so go straight to libc
+		if unsafe { libc::sched_setscheduler(0, libc::SCHED_FIFO, &param) } != 0 {
+			println!("Could not give the step generator real-time priority: {}", io::Error::last_os_error());
+		}
+	}
+}
+
+
+
+/// Motor control's handle on the step generator: feeds it segments, and reads back how far it's got.
+pub struct StepGeneratorClient {
+	sender: SyncSender<StepSegment>,
+	shared: Arc<StepGeneratorShared>,
+	/// Segment that didn't fit in the buffer yet
+	pending_segment: Option<StepSegment>,
+	next_segment_id: u64,
+	/// Last segment sent before the last flush, which will never be executed
+	flushed_segment_id: u64,
+}
+
+impl StepGeneratorClient {
+	/// Whether there's room to send another segment
+	pub fn is_ready(&mut self) -> Result<bool, HardwareError> {
+		match self.pending_segment.take() {
+			Some(segment) => self.try_send(segment),
+			None => Ok(true),
+		}
+	}
+
+	/// Queue a segment for execution, returning its ID. Call is_ready() first, to avoid piling up segments here.
+	pub fn send(&mut self, duration: Duration, steps: Vec<StepEvent>) -> Result<u64, HardwareError> {
+		self.next_segment_id += 1;
+		let segment = StepSegment {
+			id: self.next_segment_id,
+			generation: self.shared.generation.load(Ordering::SeqCst),
+			duration,
+			steps,
+		};
+		self.try_send(segment)?;
+		Ok(self.next_segment_id)
+	}
+
+	fn try_send(&mut self, segment: StepSegment) -> Result<bool, HardwareError> {
+		match self.sender.try_send(segment) {
+			Ok(()) => Ok(true),
+			Err(TrySendError::Full(segment)) => {
+				self.pending_segment = Some(segment);
+				Ok(false)
+			},
+			Err(TrySendError::Disconnected(_)) => Err("step generator thread has died".into()),
+		}
+	}
+
+	/// Throw away every buffered segment, stopping dead, and wait until the step generator has stopped.
+	/// Fails if it doesn't stop in time, in which case it's stuck or has died, and can't be relied on to do anything.
+	pub fn flush(&mut self) -> Result<(), HardwareError> {
+		self.pending_segment = None;
+		self.flushed_segment_id = self.next_segment_id;
+		let generation = self.shared.generation.fetch_add(1, Ordering::SeqCst) + 1;
+		let start = Instant::now();
+		while self.shared.acked_generation.load(Ordering::SeqCst) != generation {
+			if start.elapsed() > FLUSH_TIMEOUT {
+				return Err(format!("step generator didn't stop within {:?} of being flushed", FLUSH_TIMEOUT).into());
+			}
+			thread::yield_now();
+		}
+		Ok(())
+	}
+
+	/// Whether everything that's been sent has been executed, so the motors are standing still
+	pub fn is_idle(&self) -> bool {
+		self.pending_segment.is_none() && (self.next_segment_id == self.flushed_segment_id || self.is_executed(self.next_segment_id))
+	}
+
+	/// Whether the segment with the given ID has been executed
+	pub fn is_executed(&self, segment_id: u64) -> bool {
+		self.shared.executed_segment_id.load(Ordering::SeqCst) >= segment_id
+	}
+
+	/// Current position of an axis in logical steps, as actually stepped
+	pub fn get_position(&self, axis: Axis) -> i64 {
+		self.shared.positions[&axis].load(Ordering::SeqCst)
+	}
+
+	/// Change the position of an axis that isn't moving, e.g. to where its encoder says it really is. A slave motor keeps its offset from the main one.
+	pub fn set_position(&mut self, axis: Axis, steps: i64) {
+		let previous = self.shared.positions[&axis].swap(steps, Ordering::SeqCst);
+		if let Some(slave_position) = self.shared.slave_positions.get(&axis) {
+			slave_position.fetch_add(steps - previous, Ordering::SeqCst);
+		}
+	}
+
+	/// Steps of an axis's backlash that have been taken up towards its Max end, by steps that only moved the motors
+	pub fn get_slack(&self, axis: Axis) -> i64 {
+		self.shared.slack[&axis].load(Ordering::SeqCst)
+	}
+
+	/// Say how much of an axis's backlash has been taken up, e.g. when it's first moved and we have to assume it was taken up in that direction. Only for axes that aren't moving.
+	pub fn set_slack(&mut self, axis: Axis, steps: i64) {
+		self.shared.slack[&axis].store(steps, Ordering::SeqCst);
+	}
+
+	/// How many logical steps an axis's slave motor is ahead of its main one, from the times one moved while the other was held. Zero if there's no slave.
+	pub fn get_slave_offset(&self, axis: Axis) -> i64 {
+		match self.shared.slave_positions.get(&axis) {
+			Some(slave_position) => slave_position.load(Ordering::SeqCst) - self.get_position(axis),
+			None => 0,
+		}
+	}
+
+	/// Put an axis's slave motor the given number of logical steps ahead of its main one, e.g. as it was left before a restart
+	pub fn set_slave_offset(&mut self, axis: Axis, offset: i64) {
+		if let Some(slave_position) = self.shared.slave_positions.get(&axis) {
+			slave_position.store(self.get_position(axis) + offset, Ordering::SeqCst);
+		}
+	}
+
+	/// How late the steps have been since the last call
+	pub fn take_step_timing(&self) -> StepTiming {
+		let steps = self.shared.timed_steps.swap(0, Ordering::SeqCst);
+		let total_lateness = self.shared.total_lateness_nanos.swap(0, Ordering::SeqCst);
+		let max_lateness = self.shared.max_lateness_nanos.swap(0, Ordering::SeqCst);
+		StepTiming {
+			steps,
+			mean_lateness: Duration::from_nanos(total_lateness.checked_div(steps).unwrap_or(0)),
+			max_lateness: Duration::from_nanos(max_lateness),
+		}
+	}
+
+	/// Keep one of an axis's motors still while the rest of the axis carries on, or let it move with the axis again
+	pub fn set_held(&mut self, axis: Axis, motor: AxisMotor, held: bool) {
+		match self.shared.held.get(&(axis, motor)) {
+			Some(motor_held) => motor_held.store(held, Ordering::SeqCst),
+			None => println!("Axis {} has no {} motor to hold", axis, motor),
+		}
+	}
+}
+
+
+pub fn init(config: StepGeneratorConfig, steppers: HashMap<Axis, AxisStepper>) -> StepGeneratorClient {
+	let (sender, receiver) = mpsc::sync_channel(STEP_BUFFER_SEGMENTS);
+	let shared = Arc::new(StepGeneratorShared {
+		generation: AtomicU64::new(0),
+		acked_generation: AtomicU64::new(0),
+		executed_segment_id: AtomicU64::new(0),
+		positions: steppers.keys().map(|axis| (*axis, AtomicI64::new(0))).collect(),
+		slave_positions: steppers.iter()
+			.filter(|(_, stepper)| stepper.motor_ids().contains(&AxisMotor::Slave))
+			.map(|(axis, _)| (*axis, AtomicI64::new(0)))
+			.collect(),
+		slack: steppers.keys().map(|axis| (*axis, AtomicI64::new(0))).collect(),
+		held: steppers.iter()
+			.flat_map(|(axis, stepper)| stepper.motor_ids().into_iter().map(move |motor| ((*axis, motor), AtomicBool::new(false))))
+			.collect(),
+		timed_steps: AtomicU64::new(0),
+		total_lateness_nanos: AtomicU64::new(0),
+		max_lateness_nanos: AtomicU64::new(0),
+	});
+	let thread_shared = shared.clone();
+	let builder = thread::Builder::new().name("StepGenerator".to_string());
+	builder.spawn(move || {
+		set_up_realtime_thread(&config);
+		let mut step_generator = StepGenerator {
+			receiver,
+			steppers,
+			shared: thread_shared,
+			timer: StepTimer::new(config.spin_margin()).unwrap(),
+			next_segment_start: None,
+		};
+		step_generator.run();
+	}).unwrap();
+	StepGeneratorClient {
+		sender,
+		shared,
+		pending_segment: None,
+		next_segment_id: 0,
+		flushed_segment_id: 0,
+	}
+}
@@ -0,0 +1,61 @@
+use crate::config::RustGrindConfig;
+use crate::config::SpindleTachometerConfig;
+use crate::hardware::HardwareBackend;
+use crate::hardware::PulseCounter;
+use crate::messages::Message;
+use crate::messages::SpindleSpeedMsg;
+
+use std::sync::mpsc::Sender;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+
+
+/**
+ * Measures the spindle's speed with its tachometer, and keeps motor control up to date with it.
+ */
+struct Tachometer {
+	counter: Box<dyn PulseCounter>,
+	config: SpindleTachometerConfig,
+	msg_sender: Sender<Message>,
+}
+
+impl Tachometer {
+	pub fn new(config: SpindleTachometerConfig, hardware: &dyn HardwareBackend, msg_sender: Sender<Message>) -> Self {
+		Tachometer{
+			counter: hardware.make_spindle_tachometer(&config).unwrap(),
+			config,
+			msg_sender,
+		}
+	}
+
+	pub fn run(&mut self) {
+		let sample_interval = Duration::from_millis(self.config.sample_interval_ms);
+		let mut last_rpm = None;
+		loop {
+			let pulses = self.counter.count_pulses(sample_interval).unwrap();
+			let rpm = self.config.pulses_to_rpm(pulses, sample_interval);
+			if last_rpm != Some(rpm) {
+				if self.msg_sender.send(Message::SpindleSpeedMsgType(SpindleSpeedMsg{rpm})).is_err() {
+					println!("Motor control has gone away, so nothing needs the spindle speed any more");
+					return;
+				}
+				last_rpm = Some(rpm);
+			}
+		}
+	}
+}
+
+
+pub fn init(initial_config: RustGrindConfig, hardware: Arc<dyn HardwareBackend>, msg_sender: Sender<Message>) {
+	let config = match initial_config.spindle_tachometer {
+		Some(config) => config,
+		None => return,
+	};
+	let builder = thread::Builder::new().name("Tachometer".to_string());
+	builder.spawn(move || {
+		let mut tachometer = Tachometer::new(config, hardware.as_ref(), msg_sender);
+		tachometer.run();
+	}).unwrap();
+}
@@ -0,0 +1,260 @@
+use crate::hardware::HardwareError;
+
+use std::f64::consts::SQRT_2;
+use std::io::Read;
+use std::io::Write;
+use std::time::Duration;
+
+use serial_core::SerialPort;
+use serial_unix::TTYPort;
+
+
+
+/// First byte of every datagram: the sync nibble, padded out with reserved bits
+pub const SYNC: u8 = 0x05;
+/// Address the driver sends its replies from
+pub const MASTER_ADDRESS: u8 = 0xFF;
+/// Set in the register address of a write request
+pub const WRITE_FLAG: u8 = 0x80;
+/// Length of a read request: sync, driver address, register and CRC
+pub const READ_REQUEST_LENGTH: usize = 4;
+/// Length of a write request, and of a reply: sync, address, register, four bytes of data and CRC
+pub const DATAGRAM_LENGTH: usize = 8;
+
+pub const GCONF: u8 = 0x00;
+pub const GSTAT: u8 = 0x01;
+/// Count of the write requests the driver has accepted, modulo 256
+pub const IFCNT: u8 = 0x02;
+pub const IOIN: u8 = 0x06;
+pub const IHOLD_IRUN: u8 = 0x10;
+/// Time between 1/256 microsteps, in clock cycles, as the driver measures it from the step input
+pub const TSTEP: u8 = 0x12;
+/// Slowest speed (as a TSTEP) at which StallGuard is trusted
+pub const TCOOLTHRS: u8 = 0x14;
+pub const SGTHRS: u8 = 0x40;
+pub const SG_RESULT: u8 = 0x41;
+pub const CHOPCONF: u8 = 0x6C;
+pub const DRV_STATUS: u8 = 0x6F;
+
+/// GCONF: scale the current by the voltage on the VREF input, as well as IRUN/IHOLD
+pub const GCONF_I_SCALE_ANALOG: u32 = 1 << 0;
+/// GCONF: SpreadCycle rather than StealthChop
+pub const GCONF_EN_SPREADCYCLE: u32 = 1 << 2;
+/// GCONF: the PDN_UART pin is the UART, not power down
+pub const GCONF_PDN_DISABLE: u32 = 1 << 6;
+/// GCONF: microstepping set by CHOPCONF's MRES, not the MS1 and MS2 pins (which are the address in UART mode)
+pub const GCONF_MSTEP_REG_SELECT: u32 = 1 << 7;
+pub const GCONF_MULTISTEP_FILT: u32 = 1 << 8;
+
+/// GSTAT: the driver has been reset since the flags were last cleared
+pub const GSTAT_RESET: u32 = 1 << 0;
+/// GSTAT: the driver has shut down because of overtemperature or a short
+pub const GSTAT_DRV_ERR: u32 = 1 << 1;
+/// GSTAT: the charge pump is undervoltage, so the driver is disabled
+pub const GSTAT_UV_CP: u32 = 1 << 2;
+
+/// CHOPCONF: higher sensitivity current sensing, for lower currents
+pub const CHOPCONF_VSENSE: u32 = 1 << 17;
+/// CHOPCONF: microstep resolution, as a power of two below 256
+pub const CHOPCONF_MRES_SHIFT: u32 = 24;
+pub const CHOPCONF_MRES_MASK: u32 = 0xF << CHOPCONF_MRES_SHIFT;
+/// CHOPCONF: interpolate whatever the microstepping is to 256 microsteps
+pub const CHOPCONF_INTPOL: u32 = 1 << 28;
+
+/// IHOLD_IRUN: how long to wait at standstill before ramping down to the hold current, in units of 2^18 clock cycles
+pub const IHOLD_IRUN_IHOLDDELAY: u32 = 8 << 16;
+
+pub const DRV_STATUS_OTPW: u32 = 1 << 0;
+pub const DRV_STATUS_OT: u32 = 1 << 1;
+pub const DRV_STATUS_S2GA: u32 = 1 << 2;
+pub const DRV_STATUS_S2GB: u32 = 1 << 3;
+pub const DRV_STATUS_S2VSA: u32 = 1 << 4;
+pub const DRV_STATUS_S2VSB: u32 = 1 << 5;
+pub const DRV_STATUS_OLA: u32 = 1 << 6;
+pub const DRV_STATUS_OLB: u32 = 1 << 7;
+/// DRV_STATUS: the driver is in StealthChop mode
+pub const DRV_STATUS_STEALTH: u32 = 1 << 30;
+/// DRV_STATUS: the motor is at standstill, so StallGuard's reading means nothing
+pub const DRV_STATUS_STST: u32 = 1 << 31;
+
+/// Largest value of TSTEP and TCOOLTHRS, which TSTEP sticks at when the motor is slower than it can measure, or stopped
+pub const TSTEP_MAX: u32 = 0xFFFFF;
+/// Frequency of the driver's internal clock
+pub const CLOCK_FREQUENCY: f64 = 12_000_000.0;
+
+/// Voltage across the sense resistors at full scale current, without and with vsense set
+const FULL_SCALE_VOLTAGE: f64 = 0.325;
+const FULL_SCALE_VOLTAGE_VSENSE: f64 = 0.180;
+/// Resistance of the driver's switches, in series with the sense resistors
+const SWITCH_RESISTANCE: f64 = 0.02;
+
+/// How long to wait for the echo of a request, and for the reply after it
+const RESPONSE_TIMEOUT: Duration = Duration::from_millis(50);
+/// How long to wait for stray bytes when clearing out the input after an error
+const DISCARD_TIMEOUT: Duration = Duration::from_millis(10);
+
+
+
+/// CRC of a datagram, as Trinamic defines it: CRC-8 with polynomial 0x07, but taking each byte least significant bit first
+pub fn crc8(data: &[u8]) -> u8 {
+	let mut crc: u8 = 0;
+	for byte in data {
+		let mut byte = *byte;
+		for _ in 0..8 {
+			if ((crc >> 7) ^ (byte & 1)) != 0 {
+				crc = (crc << 1) ^ 0x07;
+			} else {
+				crc <<= 1;
+			}
+			byte >>= 1;
+		}
+	}
+	crc
+}
+
+pub fn append_crc(datagram: &mut Vec<u8>) {
+	datagram.push(crc8(datagram));
+}
+
+/// Whether a whole datagram (CRC included) arrived intact
+pub fn crc_matches(datagram: &[u8]) -> bool {
+	match datagram.split_last() {
+		Some((crc, data)) => crc8(data) == *crc,
+		None => false,
+	}
+}
+
+/// Current scale (CS, from 0 to 31) for the given RMS motor current, before rounding, as the datasheet works it out
+fn current_scale(current: f64, sense_resistor_ohms: f64, vsense: bool) -> f64 {
+	let full_scale_voltage = if vsense { FULL_SCALE_VOLTAGE_VSENSE } else { FULL_SCALE_VOLTAGE };
+	32.0 * current * SQRT_2 * (sense_resistor_ohms + SWITCH_RESISTANCE) / full_scale_voltage - 1.0
+}
+
+/// Largest RMS current the driver can be set to with the given sense resistors
+pub fn max_current(sense_resistor_ohms: f64) -> f64 {
+	FULL_SCALE_VOLTAGE / (SQRT_2 * (sense_resistor_ohms + SWITCH_RESISTANCE))
+}
+
+/// Settings for the given run and hold currents: whether to set vsense, and the value of IHOLD_IRUN.
+/// The more sensitive vsense range is used whenever the run current fits in it, as it sets low currents more finely.
+pub fn current_settings(run_current: f64, hold_current: f64, sense_resistor_ohms: f64) -> (bool, u32) {
+	let vsense = current_scale(run_current, sense_resistor_ohms, true).round() <= 31.0;
+	let scale = |current: f64| current_scale(current, sense_resistor_ohms, vsense).round().clamp(0.0, 31.0) as u32;
+	(vsense, scale(hold_current) | (scale(run_current) << 8) | IHOLD_IRUN_IHOLDDELAY)
+}
+
+/// Value of CHOPCONF's MRES for the given microstepping, if the driver can do it
+pub fn microstep_resolution(microsteps: u32) -> Option<u32> {
+	if microsteps.is_power_of_two() && microsteps <= 256 {
+		Some(8 - microsteps.trailing_zeros())
+	} else {
+		None
+	}
+}
+
+/// TSTEP the driver measures at the given step rate (in steps/second, at the given microstepping)
+pub fn tstep(step_rate: f64, microsteps: u32) -> u32 {
+	if step_rate.is_nan() || step_rate <= 0.0 {
+		return TSTEP_MAX;
+	}
+	(CLOCK_FREQUENCY * (microsteps as f64) / (256.0 * step_rate)).min(TSTEP_MAX as f64) as u32
+}
+
+
+
+/**
+ * Master of the single-wire UART of one or more Trinamic TMC2209 drivers, with up to four drivers sharing it at different addresses.
+ * The wire is shared between sending and receiving, so everything we send comes straight back to us before the driver's reply.
+ */
+pub struct TmcUartClient {
+	port: TTYPort,
+}
+
+impl TmcUartClient {
+	pub fn new(mut port: TTYPort) -> Result<Self, HardwareError> {
+		port.set_timeout(RESPONSE_TIMEOUT)?;
+		Ok(TmcUartClient {
+			port,
+		})
+	}
+
+	pub fn read_register(&mut self, address: u8, register: u8) -> Result<u32, HardwareError> {
+		let mut request = vec![SYNC, address, register];
+		append_crc(&mut request);
+		let result = self.send(&request).and_then(|_| {
+			let mut reply = [0; DATAGRAM_LENGTH];
+			self.port.read_exact(&mut reply)?;
+			if !crc_matches(&reply) {
+				return Err(format!("TMC reply {:?} failed its CRC check", reply).into());
+			}
+			if reply[0] & 0x0F != SYNC || reply[1] != MASTER_ADDRESS || reply[2] != register {
+				return Err(format!("TMC reply {:?} isn't for register {} of driver {}", reply, register, address).into());
+			}
+			Ok(u32::from_be_bytes([reply[3], reply[4], reply[5], reply[6]]))
+		});
+		if result.is_err() {
+			// Don't let the rest of a bad or late reply get mixed up with the next one
+			self.discard_input()?;
+		}
+		result
+	}
+
+	/// Write a register. The driver doesn't reply to writes, so the only way to tell they've worked is to watch its IFCNT register.
+	pub fn write_register(&mut self, address: u8, register: u8, value: u32) -> Result<(), HardwareError> {
+		let mut request = vec![SYNC, address, register | WRITE_FLAG];
+		request.extend_from_slice(&value.to_be_bytes());
+		append_crc(&mut request);
+		let result = self.send(&request);
+		if result.is_err() {
+			self.discard_input()?;
+		}
+		result
+	}
+
+	/// Send a request, and read back its echo
+	fn send(&mut self, request: &[u8]) -> Result<(), HardwareError> {
+		self.port.write_all(request)?;
+		let mut echo = vec![0; request.len()];
+		self.port.read_exact(&mut echo)?;
+		if echo != request {
+			return Err(format!("TMC request {:?} came back as {:?}, so something else is using the UART", request, echo).into());
+		}
+		Ok(())
+	}
+
+	fn discard_input(&mut self) -> Result<(), HardwareError> {
+		self.port.set_timeout(DISCARD_TIMEOUT)?;
+		let mut buffer = [0; 64];
+		while let Ok(count) = self.port.read(&mut buffer) {
+			if count == 0 {
+				break;
+			}
+		}
+		self.port.set_timeout(RESPONSE_TIMEOUT)?;
+		Ok(())
+	}
+}
+
+
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn crc8_of_the_datasheet_read_request() {
+		// Reading GCONF of the driver at address 0, the example datagram in the TMC2209 datasheet
+		assert_eq!(crc8(&[SYNC, 0x00, GCONF]), 0x48);
+	}
+
+	#[test]
+	fn crc_is_appended_and_checked() {
+		let mut datagram = vec![SYNC, 0x01, IHOLD_IRUN | WRITE_FLAG, 0x00, 0x08, 0x10, 0x04];
+		append_crc(&mut datagram);
+		assert_eq!(datagram.len(), DATAGRAM_LENGTH);
+		assert!(crc_matches(&datagram));
+		datagram[5] ^= 1;
+		assert!(!crc_matches(&datagram));
+		assert!(!crc_matches(&[]));
+	}
+}
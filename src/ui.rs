@@ -1,6 +1,15 @@
+use crate::common::LengthUnit;
+use crate::common::UnitSystem;
+use crate::messages::AxisLockMsg;
+use crate::messages::ConvertUnits;
+use crate::messages::FeedOverrideMsg;
+use crate::messages::LinearMoveMsg;
 use crate::messages::Message;
 use crate::messages::MoveAxisRelMsg;
+use crate::messages::ResetFollowingErrorMsg;
 use crate::messages::SpindleControlMsg;
+use crate::messages::StartJogMsg;
+use crate::operation_controllers::BacklashMeasurementParams;
 use crate::operation_controllers::SurfaceGrinderCutParams;
 
 use std::path::Path;
@@ -15,14 +24,77 @@ use rocket::State;
 
 
 
+/**
+ * Body of a request with lengths in it: the request itself, plus the units it's in.
+ * Without units, it's taken to be in the same units as the config. Positions of rotary axes are always in degrees.
+ */
+#[derive(Deserialize)]
+struct WithUnits<T> {
+	#[serde(flatten)]
+	request: T,
+	units: Option<LengthUnit>,
+}
+
+impl<T: ConvertUnits> WithUnits<T> {
+	fn into_internal_units(self, default_units: &UnitSystem) -> T {
+		let units = default_units.with_length(self.units.unwrap_or(default_units.length));
+		self.request.to_internal_units(&units)
+	}
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_axis_lock(message: Json<AxisLockMsg>, sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::AxisLockMsgType(message.into_inner()));
+}
+
+#[post("/", format = "json")]
+fn order_feed_hold(sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::FeedHoldMsgType());
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_feed_override(message: Json<FeedOverrideMsg>, sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::FeedOverrideMsgType(message.into_inner()));
+}
+
+#[post("/", format = "json")]
+fn order_jog_keepalive(sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::JogKeepaliveMsgType());
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_linear_move(message: Json<WithUnits<LinearMoveMsg>>, sender: State<Mutex<Sender<Message>>>, units: State<UnitSystem>) {
+	sender.lock().unwrap().send(Message::LinearMoveMsgType(message.into_inner().into_internal_units(&units)));
+}
+
 #[post("/", format = "json", data = "<message>")]
-fn order_move_axis_rel(message: Json<MoveAxisRelMsg>, sender: State<Mutex<Sender<Message>>>) {
-	sender.lock().unwrap().send(Message::MoveAxisRelMsgType(message.into_inner()));
+fn order_move_axis_rel(message: Json<WithUnits<MoveAxisRelMsg>>, sender: State<Mutex<Sender<Message>>>, units: State<UnitSystem>) {
+	sender.lock().unwrap().send(Message::MoveAxisRelMsgType(message.into_inner().into_internal_units(&units)));
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_reset_following_error(message: Json<ResetFollowingErrorMsg>, sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::ResetFollowingErrorMsgType(message.into_inner()));
+}
+
+#[post("/", format = "json")]
+fn order_resume(sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::ResumeMsgType());
 }
 
 #[post("/", format = "json", data = "<message>")]
 fn order_spindle_power(message: Json<bool>, sender: State<Mutex<Sender<Message>>>) {
-	sender.lock().unwrap().send(Message::SpindleControlMsgType(SpindleControlMsg{on: message.into_inner()}));
+	sender.lock().unwrap().send(Message::SpindleControlMsgType(SpindleControlMsg{on: message.into_inner(), rpm: None}));
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_spindle_control(message: Json<SpindleControlMsg>, sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::SpindleControlMsgType(message.into_inner()));
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_start_backlash_measurement(message: Json<BacklashMeasurementParams>, sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::StartBacklashMeasurementMsgType(message.into_inner()));
 }
 
 #[post("/", format = "json")]
@@ -31,8 +103,13 @@ fn order_start_homing(sender: State<Mutex<Sender<Message>>>) {
 }
 
 #[post("/", format = "json", data = "<message>")]
-fn order_start_surface_grinder_cut(message: Json<SurfaceGrinderCutParams>, sender: State<Mutex<Sender<Message>>>) {
-	sender.lock().unwrap().send(Message::StartSurfaceGrinderCutMsgType(message.into_inner()));
+fn order_start_jog(message: Json<WithUnits<StartJogMsg>>, sender: State<Mutex<Sender<Message>>>, units: State<UnitSystem>) {
+	sender.lock().unwrap().send(Message::StartJogMsgType(message.into_inner().into_internal_units(&units)));
+}
+
+#[post("/", format = "json", data = "<message>")]
+fn order_start_surface_grinder_cut(message: Json<WithUnits<SurfaceGrinderCutParams>>, sender: State<Mutex<Sender<Message>>>, units: State<UnitSystem>) {
+	sender.lock().unwrap().send(Message::StartSurfaceGrinderCutMsgType(message.into_inner().into_internal_units(&units)));
 }
 
 #[post("/", format = "json")]
@@ -40,6 +117,11 @@ fn order_stop(sender: State<Mutex<Sender<Message>>>) {
 	sender.lock().unwrap().send(Message::StopMsgType());
 }
 
+#[post("/", format = "json")]
+fn order_stop_jog(sender: State<Mutex<Sender<Message>>>) {
+	sender.lock().unwrap().send(Message::StopJogMsgType());
+}
+
 
 #[get("/<file..>", rank = 2)]
 pub fn fallback_url(file: PathBuf) -> Option<NamedFile> {
@@ -56,18 +138,31 @@ pub fn index() -> Option<NamedFile> {
 }
 
 
-pub fn init(sender: Sender<Message>) {
+/// Start the web server. Lengths in requests are in the given units, unless a request says otherwise.
+pub fn init(sender: Sender<Message>, default_units: UnitSystem) {
 	let builder = thread::Builder::new().name("Main UI".to_string());
 	builder.spawn(move || {
 		let mutex = Mutex::new(sender);
 		rocket::ignite()
 			.manage(mutex)
+			.manage(default_units)
 			.mount("/", routes![fallback_url, index])
+			.mount("/api/axisLock", routes![order_axis_lock])
+			.mount("/api/feedHold", routes![order_feed_hold])
+			.mount("/api/feedOverride", routes![order_feed_override])
+			.mount("/api/jogKeepalive", routes![order_jog_keepalive])
+			.mount("/api/linearMove", routes![order_linear_move])
 			.mount("/api/moveAxisRel", routes![order_move_axis_rel])
+			.mount("/api/resetFollowingError", routes![order_reset_following_error])
+			.mount("/api/resume", routes![order_resume])
+			.mount("/api/spindleControl", routes![order_spindle_control])
 			.mount("/api/spindlePower", routes![order_spindle_power])
+			.mount("/api/startBacklashMeasurement", routes![order_start_backlash_measurement])
 			.mount("/api/startHoming", routes![order_start_homing])
+			.mount("/api/startJog", routes![order_start_jog])
 			.mount("/api/startSurfaceGrinderCut", routes![order_start_surface_grinder_cut])
 			.mount("/api/stop", routes![order_stop])
+			.mount("/api/stopJog", routes![order_stop_jog])
 			.launch();
 	}).unwrap();
 }